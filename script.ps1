@@ -2,6 +2,11 @@
 Write-Host "Running cargo tests..." -ForegroundColor Green
 cargo test --lib --bins --tests
 
+# Check that the VM core still builds and runs without the standard library
+Write-Host "`nTesting without std..." -ForegroundColor Green
+cargo build --lib --no-default-features
+cargo test --no-default-features --test core_tests
+
 # Run cargo fmt to format the code
 Write-Host "`nFormatting code..." -ForegroundColor Green
 cargo fmt
@@ -0,0 +1,27 @@
+//! Warnings produced by analyzing an assembled program
+//!
+//! Diagnostics point out code that assembles fine but probably isn't what the
+//! author meant. They never stop a program from being assembled.
+
+use std::fmt;
+
+use crate::span::Span;
+
+/// A warning about a source line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic
+{
+    /// The 1-based source line the warning refers to
+    pub line: usize,
+    /// The source text the warning refers to
+    pub span: Span,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "line {}: warning: {}", self.line, self.message)
+    }
+}
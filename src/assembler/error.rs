@@ -5,6 +5,9 @@
 
 use std::error::Error;
 use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::span::Span;
 
 /// Represents all possible errors that can occur during assembly.
 #[derive(Debug)]
@@ -25,6 +28,9 @@ pub enum AssemblerError
     /// A referenced label wasn't defined anywhere in the code
     UndefinedLabel(String),
 
+    /// A constant was referenced before its `EQU` definition
+    UndefinedConstant(String),
+
     /// Wrong number of operands for an instruction
     InvalidNumberOfOperands
     {
@@ -41,6 +47,93 @@ pub enum AssemblerError
 
     /// A syntax error occurred
     SyntaxError(String),
+
+    /// An `%include` directive was malformed, or its file couldn't be read
+    InvalidInclude(String),
+
+    /// The program doesn't fit in the requested image size
+    ProgramTooLarge
+    {
+        /// Size of the assembled program in bytes
+        size: usize,
+        /// The size it had to fit in
+        limit: usize,
+    },
+
+    /// Another error, tagged with the source line it occurred on
+    AtLine
+    {
+        /// The 1-based source line number, counting blank and comment lines
+        line: usize,
+        /// The source text the error refers to, if known
+        span: Option<Span>,
+        /// The file the line is in, for errors from `Assembler::assemble_file`
+        file: Option<PathBuf>,
+        /// The underlying error
+        error: Box<AssemblerError>,
+    },
+}
+
+impl AssemblerError
+{
+    /// Tags this error with the source line it occurred on.
+    ///
+    /// Errors that already carry a line number are returned unchanged.
+    pub fn at_line(self, line: usize) -> Self
+    {
+        match self {
+            AssemblerError::AtLine { .. } => self,
+            error => AssemblerError::AtLine {
+                line,
+                span: None,
+                file: None,
+                error: Box::new(error),
+            },
+        }
+    }
+
+    /// Tags this error with the source text it refers to, and that text's line.
+    ///
+    /// Errors that already carry a line number are returned unchanged.
+    pub fn at_span(self, span: Span) -> Self
+    {
+        match self {
+            AssemblerError::AtLine { .. } => self,
+            error => AssemblerError::AtLine {
+                line: span.line,
+                span: Some(span),
+                file: None,
+                error: Box::new(error),
+            },
+        }
+    }
+
+    /// Returns the source line this error occurred on, if known.
+    pub fn line(&self) -> Option<usize>
+    {
+        match self {
+            AssemblerError::AtLine { line, .. } => Some(*line),
+            _ => None,
+        }
+    }
+
+    /// Returns the file this error occurred in, for errors from `Assembler::assemble_file`.
+    pub fn file(&self) -> Option<&Path>
+    {
+        match self {
+            AssemblerError::AtLine { file, .. } => file.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the source text this error refers to, if known.
+    pub fn span(&self) -> Option<Span>
+    {
+        match self {
+            AssemblerError::AtLine { span, .. } => *span,
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for AssemblerError
@@ -53,6 +146,7 @@ impl fmt::Display for AssemblerError
             AssemblerError::InvalidValue(s) => write!(f, "Invalid value: {}", s),
             AssemblerError::InvalidLabel(s) => write!(f, "Invalid label: {}", s),
             AssemblerError::UndefinedLabel(s) => write!(f, "Undefined label: {}", s),
+            AssemblerError::UndefinedConstant(s) => write!(f, "Constant used before definition: {}", s),
             AssemblerError::InvalidNumberOfOperands {
                 instruction,
                 expected,
@@ -64,6 +158,17 @@ impl fmt::Display for AssemblerError
             ),
             AssemblerError::InvalidAddress(s) => write!(f, "Invalid address: {}", s),
             AssemblerError::SyntaxError(s) => write!(f, "Syntax error: {}", s),
+            AssemblerError::InvalidInclude(s) => write!(f, "Invalid include: {}", s),
+            AssemblerError::ProgramTooLarge { size, limit } => {
+                write!(f, "Program is {} bytes, larger than the {} byte limit", size, limit)
+            }
+            AssemblerError::AtLine {
+                line,
+                file: Some(file),
+                error,
+                ..
+            } => write!(f, "{}:{}: {}", file.display(), line, error),
+            AssemblerError::AtLine { line, error, .. } => write!(f, "line {}: {}", line, error),
         }
     }
 }
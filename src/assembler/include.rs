@@ -0,0 +1,150 @@
+//! `%include` expansion
+//!
+//! Before assembly, every `%include "file.asm"` line is replaced with the
+//! contents of that file, recursively. Paths are resolved relative to the
+//! directory of the file containing the directive. All files share one
+//! label namespace, exactly as if the text had been pasted in.
+//!
+//! Each line of the expanded text remembers the file and line it came from,
+//! so errors can be reported against the original files.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::error::AssemblerError;
+use super::parser::strip_comment;
+use crate::span::Span;
+
+/// Source text with its includes expanded, and where each of its lines came from.
+pub(super) struct Expanded
+{
+    /// The combined source text
+    pub(super) code: String,
+    /// The origin of each line of `code`, in order
+    origins: Vec<Origin>,
+}
+
+/// The file and position a line of expanded source was copied from
+struct Origin
+{
+    /// The file, as it was named by the caller or the `%include` directive
+    file: PathBuf,
+    /// 1-based line number in `file`
+    line: usize,
+    /// Byte offset of the line in `file`
+    file_start: usize,
+    /// Byte offset of the line in the expanded text
+    start: usize,
+}
+
+impl Expanded
+{
+    /// Moves an error's line and span from the expanded text back to the file the line came from.
+    ///
+    /// Errors without a line are returned unchanged.
+    pub(super) fn locate(&self, error: AssemblerError) -> AssemblerError
+    {
+        let AssemblerError::AtLine { line, span, error, .. } = error else {
+            return error;
+        };
+        let Some(origin) = line.checked_sub(1).and_then(|index| self.origins.get(index)) else {
+            return AssemblerError::AtLine {
+                line,
+                span,
+                file: None,
+                error,
+            };
+        };
+
+        // Spans never cross lines, so they move with the start of their line
+        let span = span.map(|span| Span {
+            start: span.start - origin.start + origin.file_start,
+            end: span.end - origin.start + origin.file_start,
+            line: origin.line,
+        });
+        AssemblerError::AtLine {
+            line: origin.line,
+            span,
+            file: Some(origin.file.clone()),
+            error,
+        }
+    }
+}
+
+/// Reads `path` and expands its `%include` directives into a single source text.
+///
+/// # Arguments
+/// * `path` - The top-level source file
+///
+/// # Returns
+/// * `Ok(Expanded)` - The source with every include replaced by the included file
+/// * `Err(AssemblerError::InvalidInclude)` - If a file can't be read, a directive is
+///   malformed, or a file ends up including itself
+pub(super) fn expand_includes(path: &Path) -> Result<Expanded, AssemblerError>
+{
+    let mut output = Expanded {
+        code: String::new(),
+        origins: Vec::new(),
+    };
+    expand_into(path, &mut Vec::new(), &mut output)?;
+    Ok(output)
+}
+
+/// Appends the expanded contents of `path` to `output`.
+///
+/// `stack` holds the canonical paths of the files currently being expanded,
+/// so an include cycle is reported instead of recursing forever.
+fn expand_into(path: &Path, stack: &mut Vec<PathBuf>, output: &mut Expanded) -> Result<(), AssemblerError>
+{
+    let canonical =
+        fs::canonicalize(path).map_err(|e| AssemblerError::InvalidInclude(format!("{}: {}", path.display(), e)))?;
+    if stack.contains(&canonical) {
+        return Err(AssemblerError::InvalidInclude(format!(
+            "{} is included from itself",
+            path.display()
+        )));
+    }
+    let code =
+        fs::read_to_string(&canonical).map_err(|e| AssemblerError::InvalidInclude(format!("{}: {}", path.display(), e)))?;
+    let directory = canonical.parent().map(Path::to_path_buf).unwrap_or_default();
+
+    stack.push(canonical);
+    for (index, line) in code.lines().enumerate() {
+        match included_path(line) {
+            Some(included) => {
+                let included = included
+                    .map_err(|e| AssemblerError::InvalidInclude(format!("{}:{}: {}", path.display(), index + 1, e)))?;
+                expand_into(&directory.join(included), stack, output)?;
+            }
+            None => {
+                output.origins.push(Origin {
+                    file: path.to_path_buf(),
+                    line: index + 1,
+                    file_start: line.as_ptr() as usize - code.as_ptr() as usize,
+                    start: output.code.len(),
+                });
+                output.code.push_str(line);
+                output.code.push('\n');
+            }
+        }
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// Returns the quoted file name if `line` is an `%include` directive.
+fn included_path(line: &str) -> Option<Result<&str, &'static str>>
+{
+    let line = strip_comment(line).trim();
+    let (directive, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    if !directive.eq_ignore_ascii_case("%include") {
+        return None;
+    }
+
+    let name = rest
+        .trim()
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .filter(|name| !name.is_empty());
+    Some(name.ok_or("%include needs a quoted file name"))
+}
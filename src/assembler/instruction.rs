@@ -3,9 +3,11 @@
 //! This module defines how assembly instructions are represented internally
 //! during the assembly process, before they are converted to bytecode.
 
+use std::collections::HashMap;
 use std::str::FromStr;
 
 use super::error::AssemblerError;
+use crate::span::Span;
 
 /// Represents a single assembly instruction.
 ///
@@ -15,10 +17,11 @@ use super::error::AssemblerError;
 /// - "r0" and "5" are the operands
 ///
 /// # Example
-/// ```
+/// ```ignore
 /// let inst = Instruction {
 ///     opcode: "MOV".to_string(),
 ///     operands: vec!["r0".to_string(), "5".to_string()],
+///     span: Span::default(),
 /// };
 /// ```
 #[derive(Debug, PartialEq)]
@@ -26,141 +29,271 @@ pub struct Instruction
 {
     pub opcode: String,
     pub operands: Vec<String>,
+    /// Where the instruction is in the source, set by the parser
+    pub span: Span,
+    /// Whether `LOAD`/`STORE` must use the two-byte address encoding even if the
+    /// address fits in one byte, set by the parser once a layout has widened it
+    pub wide: bool,
 }
 
 impl Instruction
 {
-    /// Creates a new instruction with the given opcode and operands.
+    /// Creates a new instruction with the given opcode and operands and an empty span.
     ///
     /// # Arguments
     /// * `opcode` - The operation code as a string
     /// * `operands` - A vector of operand strings
     pub fn new(opcode: String, operands: Vec<String>) -> Self
     {
-        Self { opcode, operands }
+        Self {
+            opcode,
+            operands,
+            span: Span::default(),
+            wide: false,
+        }
+    }
+
+    /// Whether the instruction jumps to, calls or records a code address written as a
+    /// number or expression rather than a plain label.
+    ///
+    /// Such a target can't follow the code it points at if that code moves.
+    pub fn has_fixed_target(&self) -> bool
+    {
+        let takes_target = self.opcode != "ENTER"
+            && MNEMONICS
+                .iter()
+                .find(|mnemonic| mnemonic.name == self.opcode)
+                .is_some_and(|mnemonic| matches!(mnemonic.shape, Shape::Addr | Shape::Relative | Shape::Addresses));
+        takes_target
+            && self
+                .operands
+                .iter()
+                .any(|operand| !is_identifier(operand) || is_expression(operand))
     }
 
     /// Encode the instruction to a byte array.
-    pub fn encode(&self) -> Result<Vec<u8>, AssemblerError>
+    ///
+    /// The mnemonic is looked up in [`MNEMONICS`], which decides the opcode byte and
+    /// how the operands are encoded.
+    ///
+    /// # Arguments
+    /// * `address` - The address the instruction will be placed at, used for relative jumps
+    /// * `labels` - Label addresses used to resolve symbolic operands. Pass `None` during the
+    ///   first pass, before labels are known, to encode them as placeholder bytes so the
+    ///   instruction size can still be calculated.
+    pub fn encode(&self, address: usize, labels: Option<&HashMap<String, usize>>) -> Result<Vec<u8>, AssemblerError>
     {
-        match self.opcode.as_str() {
-            "MOV" => {
-                check_operand_count(self, 2)?;
-                let dst = parse_register(&self.operands[0])?;
-                let src = if self.operands[1].starts_with('r') {
-                    parse_register(&self.operands[1])?
+        let mnemonic = MNEMONICS
+            .iter()
+            .find(|mnemonic| mnemonic.name == self.opcode)
+            .ok_or_else(|| AssemblerError::InvalidInstruction(self.opcode.clone()))?;
+        let opcode = mnemonic.opcode;
+
+        if !matches!(mnemonic.shape, Shape::Bytes | Shape::Addresses | Shape::NoneOrImm(_)) {
+            check_operand_count(self, mnemonic.shape.operand_count())?;
+        }
+
+        match mnemonic.shape {
+            Shape::None => Ok(vec![opcode]),
+            Shape::NoneOrImm(imm_opcode) => match self.operands.len() {
+                0 => Ok(vec![opcode]),
+                1 => Ok(vec![imm_opcode, parse_value(&self.operands[0], labels)?]),
+                got => Err(AssemblerError::InvalidNumberOfOperands {
+                    instruction: self.opcode.clone(),
+                    expected: 1,
+                    got,
+                }),
+            },
+            Shape::Reg => Ok(vec![opcode, parse_register(&self.operands[0])?]),
+            Shape::RegZero => Ok(vec![opcode, parse_register(&self.operands[0])?, 0]),
+            Shape::RegReg => encode_two_reg_op(self, opcode),
+            Shape::RegImm => encode_reg_imm_op(self, opcode, labels),
+            Shape::RegRegOrImm(imm_opcode) => {
+                if is_register(&self.operands[1]) {
+                    encode_two_reg_op(self, opcode)
                 } else {
-                    parse_value(&self.operands[1])?
-                };
-                Ok(vec![0x04, dst, src])
-            }
-            "ADD" => {
-                check_operand_count(self, 2)?;
-                let dst = parse_register(&self.operands[0])?;
-                let src = parse_register(&self.operands[1])?;
-                Ok(vec![0x30, dst, src])
-            }
-            "SUB" => {
-                check_operand_count(self, 2)?;
-                let dst = parse_register(&self.operands[0])?;
-                let src = parse_register(&self.operands[1])?;
-                Ok(vec![0x31, dst, src])
-            }
-            "MUL" => {
-                check_operand_count(self, 2)?;
-                let dst = parse_register(&self.operands[0])?;
-                let src = parse_register(&self.operands[1])?;
-                Ok(vec![0x32, dst, src])
-            }
-            "DIV" => {
-                check_operand_count(self, 2)?;
-                let dst = parse_register(&self.operands[0])?;
-                let src = parse_register(&self.operands[1])?;
-                Ok(vec![0x33, dst, src])
-            }
-            "STORE" => {
-                check_operand_count(self, 2)?;
-                let reg = parse_register(&self.operands[0])?;
-                let addr = parse_value(&self.operands[1])?;
-                Ok(vec![0x21, reg, addr])
+                    encode_reg_imm_op(self, imm_opcode, labels)
+                }
             }
-            "LOAD" => {
-                check_operand_count(self, 2)?;
-                let reg = parse_register(&self.operands[0])?;
-                let addr = parse_value(&self.operands[1])?;
-                Ok(vec![0x20, reg, addr])
-            }
-            "STIDX" => {
-                check_operand_count(self, 2)?;
-                let reg = parse_register(&self.operands[0])?;
-                let base = parse_register(&self.operands[1])?;
-                Ok(vec![0x23, reg, base])
-            }
-            "LDIDX" => {
-                check_operand_count(self, 2)?;
-                let reg = parse_register(&self.operands[0])?;
-                let base = parse_register(&self.operands[1])?;
-                Ok(vec![0x22, reg, base])
-            }
-            "PUSH" => {
-                check_operand_count(self, 1)?;
-                let reg = parse_register(&self.operands[0])?;
-                Ok(vec![0x10, reg])
+            Shape::Move(reg_opcode) => {
+                if is_register(&self.operands[1]) {
+                    encode_two_reg_op(self, reg_opcode)
+                } else {
+                    encode_reg_imm_op(self, opcode, labels)
+                }
             }
-            "POP" => {
-                check_operand_count(self, 1)?;
+            Shape::RegImmOrWide(wide_opcode) => encode_reg_imm_or_wide_op(self, opcode, wide_opcode, labels),
+            Shape::RegWide => {
                 let reg = parse_register(&self.operands[0])?;
-                Ok(vec![0x11, reg])
-            }
-            "CALL" => {
-                check_operand_count(self, 1)?;
-                let addr = parse_value(&self.operands[0])?;
-                Ok(vec![0x12, addr])
-            }
-            "RET" => {
-                check_operand_count(self, 0)?;
-                Ok(vec![0x13])
-            }
-            "JMP" => {
-                check_operand_count(self, 1)?;
-                let addr = parse_value(&self.operands[0])?;
-                Ok(vec![0x40, addr])
-            }
-            "JEQ" => {
-                check_operand_count(self, 1)?;
-                let addr = parse_value(&self.operands[0])?;
-                Ok(vec![0x41, addr])
-            }
-            "JGT" => {
-                check_operand_count(self, 1)?;
-                let addr = parse_value(&self.operands[0])?;
-                Ok(vec![0x42, addr])
-            }
-            "JNE" => {
-                check_operand_count(self, 1)?;
-                let addr = parse_value(&self.operands[0])?;
-                Ok(vec![0x44, addr])
-            }
-            "CMP" => {
-                check_operand_count(self, 2)?;
-                let reg1 = parse_register(&self.operands[0])?;
-                let reg2 = parse_register(&self.operands[1])?;
-                Ok(vec![0x43, reg1, reg2])
+                // MOVW takes a value, LOADW/STOREW an address
+                let value = if self.opcode == "MOVW" {
+                    parse_wide_value(&self.operands[1], labels)?
+                } else {
+                    parse_wide_address(&self.operands[1], labels)?
+                };
+                let [lo, hi] = value.to_le_bytes();
+                Ok(vec![opcode, reg, lo, hi])
             }
-            "HALT" | "HLT" => {
-                check_operand_count(self, 0)?;
-                Ok(vec![0xFF])
+            Shape::Addr => Ok(vec![opcode, parse_address(&self.operands[0], labels)?]),
+            Shape::Relative => {
+                let offset = parse_relative_offset(&self.operands[0], address + 2, labels)?;
+                Ok(vec![opcode, offset as u8])
             }
-            "OUT" => {
-                check_operand_count(self, 1)?;
-                let reg = parse_register(&self.operands[0])?;
-                Ok(vec![0x03, reg])
+            Shape::Bytes | Shape::Addresses => {
+                if self.operands.is_empty() {
+                    return Err(AssemblerError::InvalidNumberOfOperands {
+                        instruction: self.opcode.clone(),
+                        expected: 1,
+                        got: 0,
+                    });
+                }
+                let parse = if mnemonic.shape == Shape::Bytes {
+                    parse_value
+                } else {
+                    parse_address
+                };
+                self.operands.iter().map(|val| parse(val, labels)).collect()
             }
-            _ => Err(AssemblerError::InvalidInstruction(self.opcode.clone())),
         }
     }
 }
 
+/// How a mnemonic's operands are encoded after its opcode byte
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Shape
+{
+    /// No operands
+    None,
+    /// No operands, or a byte value using the given opcode
+    NoneOrImm(u8),
+    /// A single register
+    Reg,
+    /// A single register, followed by a zero byte
+    RegZero,
+    /// Two registers
+    RegReg,
+    /// A register and a byte value (immediate or address)
+    RegImm,
+    /// Two registers, or a register and an immediate using the given opcode
+    RegRegOrImm(u8),
+    /// A register and an address, switching to the given two-byte opcode for addresses above 255
+    RegImmOrWide(u8),
+    /// A register and a byte value, or two registers using the given opcode
+    Move(u8),
+    /// A register and a two-byte little-endian value
+    RegWide,
+    /// A single address (or other byte value)
+    Addr,
+    /// A signed offset from the next instruction
+    Relative,
+    /// One or more raw data bytes
+    Bytes,
+    /// One or more one-byte addresses, such as the entries of a jump table
+    Addresses,
+}
+
+impl Shape
+{
+    /// Number of operands the shape expects (`Bytes` takes at least one, `NoneOrImm` zero or one)
+    fn operand_count(self) -> usize
+    {
+        match self {
+            Shape::None | Shape::NoneOrImm(_) => 0,
+            Shape::Reg | Shape::RegZero | Shape::Addr | Shape::Relative | Shape::Bytes | Shape::Addresses => 1,
+            Shape::RegReg
+            | Shape::RegImm
+            | Shape::RegRegOrImm(_)
+            | Shape::RegImmOrWide(_)
+            | Shape::Move(_)
+            | Shape::RegWide => 2,
+        }
+    }
+}
+
+/// A mnemonic (or alias) the assembler accepts
+struct Mnemonic
+{
+    name: &'static str,
+    opcode: u8,
+    shape: Shape,
+}
+
+const fn mnemonic(name: &'static str, opcode: u8, shape: Shape) -> Mnemonic
+{
+    Mnemonic { name, opcode, shape }
+}
+
+/// Every mnemonic the assembler accepts, including aliases.
+///
+/// Mnemonics are matched after being uppercased, so source code is case-insensitive.
+static MNEMONICS: &[Mnemonic] = &[
+    mnemonic("NOP", 0x00, Shape::None),
+    mnemonic("INC", 0x01, Shape::Reg),
+    mnemonic("DEC", 0x02, Shape::Reg),
+    mnemonic("OUT", 0x03, Shape::Reg),
+    mnemonic("OUTSTR", 0x0A, Shape::Reg),
+    mnemonic("NOT", 0x05, Shape::Reg),
+    mnemonic("NEG", 0x08, Shape::Reg),
+    mnemonic("MOV", 0x04, Shape::Move(0x09)),
+    mnemonic("CLR", 0x04, Shape::RegZero),
+    mnemonic("MOVW", 0x06, Shape::RegWide),
+    mnemonic("XCHG", 0x07, Shape::RegReg),
+    mnemonic("MOVR", 0x09, Shape::RegReg),
+    mnemonic("PUSH", 0x10, Shape::Reg),
+    mnemonic("POP", 0x11, Shape::Reg),
+    mnemonic("CALL", 0x12, Shape::Addr),
+    mnemonic("RET", 0x13, Shape::None),
+    mnemonic("PUSHA", 0x14, Shape::None),
+    mnemonic("POPA", 0x15, Shape::None),
+    mnemonic("ENTER", 0x16, Shape::Addr),
+    mnemonic("LEAVE", 0x17, Shape::None),
+    mnemonic("LOAD", 0x20, Shape::RegImmOrWide(0x24)),
+    mnemonic("STORE", 0x21, Shape::RegImmOrWide(0x25)),
+    mnemonic("LDIDX", 0x22, Shape::RegImm),
+    mnemonic("STIDX", 0x23, Shape::RegImm),
+    mnemonic("LOADW", 0x24, Shape::RegWide),
+    mnemonic("STOREW", 0x25, Shape::RegWide),
+    mnemonic("LDSP", 0x26, Shape::RegImm),
+    mnemonic("STSP", 0x27, Shape::RegImm),
+    mnemonic("MEMCPY", 0x28, Shape::None),
+    mnemonic("FILL", 0x29, Shape::None),
+    mnemonic("ADD", 0x30, Shape::RegRegOrImm(0x3A)),
+    mnemonic("SUB", 0x31, Shape::RegRegOrImm(0x3B)),
+    mnemonic("MUL", 0x32, Shape::RegReg),
+    mnemonic("DIV", 0x33, Shape::RegReg),
+    mnemonic("AND", 0x34, Shape::RegReg),
+    mnemonic("OR", 0x35, Shape::RegReg),
+    mnemonic("XOR", 0x36, Shape::RegReg),
+    mnemonic("SHL", 0x37, Shape::RegReg),
+    mnemonic("SHR", 0x38, Shape::RegReg),
+    mnemonic("MOD", 0x39, Shape::RegReg),
+    mnemonic("ADDI", 0x3A, Shape::RegImm),
+    mnemonic("SUBI", 0x3B, Shape::RegImm),
+    mnemonic("ADC", 0x3C, Shape::RegReg),
+    mnemonic("CMPI", 0x3D, Shape::RegImm),
+    mnemonic("JMP", 0x40, Shape::Addr),
+    mnemonic("JEQ", 0x41, Shape::Addr),
+    mnemonic("JZ", 0x41, Shape::Addr),
+    mnemonic("JGT", 0x42, Shape::Addr),
+    mnemonic("CMP", 0x43, Shape::RegRegOrImm(0x3D)),
+    mnemonic("JNE", 0x44, Shape::Addr),
+    mnemonic("JNZ", 0x44, Shape::Addr),
+    mnemonic("JLT", 0x45, Shape::Addr),
+    mnemonic("JLE", 0x46, Shape::Addr),
+    mnemonic("JGE", 0x47, Shape::Addr),
+    mnemonic("JC", 0x48, Shape::Addr),
+    mnemonic("JNC", 0x49, Shape::Addr),
+    mnemonic("JR", 0x4A, Shape::Relative),
+    mnemonic("JS", 0x4B, Shape::Addr),
+    mnemonic("JNS", 0x4C, Shape::Addr),
+    mnemonic("JMPR", 0x4D, Shape::Reg),
+    mnemonic("HALT", 0xFF, Shape::NoneOrImm(0xFE)),
+    mnemonic("HLT", 0xFF, Shape::NoneOrImm(0xFE)),
+    mnemonic(".BYTE", 0x00, Shape::Bytes),
+    mnemonic("DB", 0x00, Shape::Bytes),
+    mnemonic(".ADDR", 0x00, Shape::Addresses),
+];
+
 impl FromStr for Instruction
 {
     type Err = AssemblerError;
@@ -174,53 +307,326 @@ impl FromStr for Instruction
             return Err(AssemblerError::SyntaxError("Empty or comment line".to_string()));
         }
 
-        // Split into parts and handle comments
-        let parts: Vec<&str> = s
-            .split(';')  // Split on comments
-            .next()      // Take the part before any comment
-            .unwrap()    // We know there's at least one part
-            .split_whitespace()
-            .flat_map(|part| part.split(','))
-            .map(|part| part.trim())
-            .filter(|part| !part.is_empty())
-            .collect();
-
-        if parts.is_empty() {
+        // Take the part before any comment, and split off the opcode
+        let code = s.split(';').next().unwrap().trim();
+        let (opcode, rest) = code.split_once(char::is_whitespace).unwrap_or((code, ""));
+        let opcode = opcode.trim_end_matches(',');
+        if opcode.is_empty() {
             return Err(AssemblerError::SyntaxError("Empty instruction".to_string()));
         }
 
-        let opcode = parts[0].to_uppercase();
-        let operands = parts[1..].iter().map(|s| s.to_string()).collect();
+        // Operands are separated by commas or whitespace, except that an expression
+        // like `buffer + 1` is a single operand
+        let mut operands = Vec::new();
+        for part in rest.split(',') {
+            if is_expression(part.trim()) {
+                operands.push(part.split_whitespace().collect());
+            } else {
+                operands.extend(part.split_whitespace().map(|s| s.to_string()));
+            }
+        }
 
-        Ok(Instruction::new(opcode, operands))
+        Ok(Instruction::new(opcode.to_uppercase(), operands))
     }
 }
 
 // Helper functions
-fn parse_register(reg: &str) -> Result<u8, AssemblerError>
+/// Parses a register operand like `r3`.
+///
+/// Any register an operand byte can name (r0-r255) is accepted; the parser
+/// checks it against the number of registers being assembled for.
+pub(crate) fn parse_register(reg: &str) -> Result<u8, AssemblerError>
 {
     if !reg.starts_with('r') {
         return Err(AssemblerError::InvalidRegister(reg.to_string()));
     }
-    let num = reg[1..]
+    reg[1..]
         .parse::<u8>()
-        .map_err(|_| AssemblerError::InvalidRegister(reg.to_string()))?;
-    if num >= 8 {
-        return Err(AssemblerError::InvalidRegister(reg.to_string()));
+        .map_err(|_| AssemblerError::InvalidRegister(reg.to_string()))
+}
+
+pub(crate) fn is_register(operand: &str) -> bool
+{
+    operand
+        .strip_prefix('r')
+        .is_some_and(|num| !num.is_empty() && num.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn is_identifier(operand: &str) -> bool
+{
+    operand.starts_with(|c: char| c.is_alphabetic() || c == '_')
+}
+
+/// Whether an operand adds or subtracts terms, e.g. `buffer + 1` (a leading `-` is just a sign)
+fn is_expression(operand: &str) -> bool
+{
+    operand.char_indices().any(|(index, c)| c == '+' || (c == '-' && index > 0))
+}
+
+/// Evaluates an expression made of literals and labels joined by `+` and `-`.
+///
+/// The result isn't range checked, so callers can decide how wide it may be.
+fn evaluate(expr: &str, labels: &HashMap<String, usize>) -> Result<i64, AssemblerError>
+{
+    let (mut sign, mut rest) = match expr.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, expr),
+    };
+
+    let mut total = 0;
+    loop {
+        let end = rest.find(['+', '-']).unwrap_or(rest.len());
+        let term = &rest[..end];
+        let value = if is_identifier(term) {
+            *labels
+                .get(term)
+                .ok_or_else(|| AssemblerError::UndefinedLabel(term.to_string()))? as i64
+        } else {
+            parse_wide_literal(term).map_err(|_| AssemblerError::InvalidValue(expr.to_string()))? as i64
+        };
+        total += sign * value;
+
+        if end == rest.len() {
+            return Ok(total);
+        }
+        sign = if rest[end..].starts_with('+') { 1 } else { -1 };
+        rest = &rest[end + 1..];
     }
-    Ok(num)
 }
 
-fn parse_value(val: &str) -> Result<u8, AssemblerError>
+/// Evaluates an expression operand, checking that the result fits in `min..=max`.
+///
+/// Expressions evaluate to 0 before labels are known.
+fn parse_expression(val: &str, labels: Option<&HashMap<String, usize>>, min: i64, max: i64) -> Result<i64, AssemblerError>
+{
+    // Labels aren't resolved until the second pass
+    let Some(labels) = labels else {
+        return Ok(0);
+    };
+
+    let value = evaluate(val, labels)?;
+    if !(min..=max).contains(&value) {
+        return Err(AssemblerError::InvalidValue(format!("{} is {}, out of range", val, value)));
+    }
+    Ok(value)
+}
+
+fn parse_value(val: &str, labels: Option<&HashMap<String, usize>>) -> Result<u8, AssemblerError>
+{
+    if is_expression(val) {
+        return parse_expression(val, labels, i8::MIN as i64, u8::MAX as i64).map(|value| value as u8);
+    }
+
+    if is_identifier(val) {
+        return match labels {
+            // Labels aren't resolved until the second pass
+            None => Ok(0),
+            Some(labels) => {
+                let addr = *labels
+                    .get(val)
+                    .ok_or_else(|| AssemblerError::UndefinedLabel(val.to_string()))?;
+                u8::try_from(addr).map_err(|_| {
+                    AssemblerError::InvalidAddress(format!(
+                        "{} is at 0x{:X}, out of range for a one-byte operand",
+                        val, addr
+                    ))
+                })
+            }
+        };
+    }
+
+    parse_literal(val)
+}
+
+/// Parses a one-byte jump or call target, resolving labels like [`parse_value`].
+///
+/// Targets below 0 or past 0xFF can't be encoded, so they are reported instead of wrapped.
+fn parse_address(val: &str, labels: Option<&HashMap<String, usize>>) -> Result<u8, AssemblerError>
+{
+    parse_address_up_to(val, labels, u8::MAX as u16).map(|addr| addr as u8)
+}
+
+/// Parses a two-byte data address, reporting targets below 0 or past 0xFFFF.
+fn parse_wide_address(val: &str, labels: Option<&HashMap<String, usize>>) -> Result<u16, AssemblerError>
+{
+    parse_address_up_to(val, labels, u16::MAX)
+}
+
+/// Resolves an address written as a literal, label or expression, which must be in `0..=max`.
+///
+/// Labels aren't resolved until the second pass, so operands that use them give 0 before then.
+fn parse_address_up_to(val: &str, labels: Option<&HashMap<String, usize>>, max: u16) -> Result<u16, AssemblerError>
+{
+    let value = match labels {
+        Some(labels) => evaluate(val, labels)?,
+        None => match evaluate(val, &HashMap::new()) {
+            Err(AssemblerError::UndefinedLabel(_)) => return Ok(0),
+            result => result?,
+        },
+    };
+
+    u16::try_from(value).ok().filter(|&addr| addr <= max).ok_or_else(|| {
+        let width = if max == u8::MAX as u16 { "one-byte" } else { "two-byte" };
+        AssemblerError::InvalidAddress(format!("{} is {}, out of range for a {} address", val, value, width))
+    })
+}
+
+/// Parses a decimal or `0x`-prefixed hex byte value.
+///
+/// Negative decimals (`-128` to `-1`) are accepted and stored as their
+/// two's-complement bit pattern, so `-1` becomes `0xFF`.
+pub(crate) fn parse_literal(val: &str) -> Result<u8, AssemblerError>
 {
-    if val.starts_with("0x") {
-        u8::from_str_radix(&val[2..], 16)
+    if val.starts_with('-') {
+        return val
+            .parse::<i8>()
+            .map(|signed| signed as u8)
+            .map_err(|_| AssemblerError::InvalidValue(val.to_string()));
+    }
+
+    if let Some(hex) = val.strip_prefix("0x") {
+        u8::from_str_radix(hex, 16)
     } else {
         val.parse()
     }
     .map_err(|_| AssemblerError::InvalidValue(val.to_string()))
 }
 
+/// Parses a double-quoted string literal into its ASCII bytes.
+///
+/// Supports the escapes `\n`, `\r`, `\t`, `\0`, `\\` and `\"`.
+///
+/// # Arguments
+/// * `text` - The literal, including its quotes
+pub(crate) fn parse_string_literal(text: &str) -> Result<Vec<u8>, AssemblerError>
+{
+    let invalid = || AssemblerError::InvalidValue(text.to_string());
+    let inner = text
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next().ok_or_else(invalid)? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                '0' => '\0',
+                '\\' => '\\',
+                '"' => '"',
+                _ => return Err(invalid()),
+            },
+            // An unescaped quote would have ended the string
+            '"' => return Err(invalid()),
+            c => c,
+        };
+        if !c.is_ascii() {
+            return Err(invalid());
+        }
+        bytes.push(c as u8);
+    }
+    Ok(bytes)
+}
+
+/// Parses the offset of a relative jump.
+///
+/// Labels are converted to their distance from `next_address`, the address of the
+/// instruction following the jump. Literals are taken as the offset itself.
+fn parse_relative_offset(
+    val: &str,
+    next_address: usize,
+    labels: Option<&HashMap<String, usize>>,
+) -> Result<i8, AssemblerError>
+{
+    if is_identifier(val) {
+        return match labels {
+            None => Ok(0),
+            Some(labels) => {
+                let target = *labels
+                    .get(val)
+                    .ok_or_else(|| AssemblerError::UndefinedLabel(val.to_string()))?;
+                let offset = target as isize - next_address as isize;
+                i8::try_from(offset).map_err(|_| {
+                    AssemblerError::InvalidAddress(format!(
+                        "{} is {} bytes away, out of range for a relative jump",
+                        val, offset
+                    ))
+                })
+            }
+        };
+    }
+
+    val.parse::<i8>().map_err(|_| AssemblerError::InvalidValue(val.to_string()))
+}
+
+/// Parses a value for a two-byte immediate, resolving labels like [`parse_value`].
+fn parse_wide_value(val: &str, labels: Option<&HashMap<String, usize>>) -> Result<u16, AssemblerError>
+{
+    if is_expression(val) {
+        return parse_expression(val, labels, i16::MIN as i64, u16::MAX as i64).map(|value| value as u16);
+    }
+
+    if is_identifier(val) {
+        return match labels {
+            None => Ok(0),
+            Some(labels) => {
+                let &addr = labels
+                    .get(val)
+                    .ok_or_else(|| AssemblerError::UndefinedLabel(val.to_string()))?;
+                u16::try_from(addr).map_err(|_| {
+                    AssemblerError::InvalidAddress(format!(
+                        "{} is at 0x{:X}, out of range for a two-byte operand",
+                        val, addr
+                    ))
+                })
+            }
+        };
+    }
+
+    if val.starts_with('-') {
+        return val
+            .parse::<i16>()
+            .map(|signed| signed as u16)
+            .map_err(|_| AssemblerError::InvalidValue(val.to_string()));
+    }
+
+    parse_wide_literal(val)
+}
+
+/// Parses a decimal or `0x`-prefixed hex value up to 16 bits.
+fn parse_wide_literal(val: &str) -> Result<u16, AssemblerError>
+{
+    if let Some(hex) = val.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16)
+    } else {
+        val.parse()
+    }
+    .map_err(|_| AssemblerError::InvalidValue(val.to_string()))
+}
+
+/// Encodes a register and byte value, switching to `wide_opcode` for values above 255.
+///
+/// Labels count once their addresses are known; without `labels` they're assumed to fit in a byte.
+fn encode_reg_imm_or_wide_op(
+    inst: &Instruction,
+    opcode: u8,
+    wide_opcode: u8,
+    labels: Option<&HashMap<String, usize>>,
+) -> Result<Vec<u8>, AssemblerError>
+{
+    let reg = parse_register(&inst.operands[0])?;
+    let addr = parse_wide_address(&inst.operands[1], labels)?;
+    if inst.wide || addr > 0xFF {
+        let [lo, hi] = addr.to_le_bytes();
+        Ok(vec![wide_opcode, reg, lo, hi])
+    } else {
+        Ok(vec![opcode, reg, addr as u8])
+    }
+}
+
 fn check_operand_count(inst: &Instruction, expected: usize) -> Result<(), AssemblerError>
 {
     if inst.operands.len() != expected {
@@ -242,3 +648,17 @@ fn encode_two_reg_op(inst: &Instruction, opcode: u8) -> Result<Vec<u8>, Assemble
         parse_register(&inst.operands[1])?,
     ])
 }
+
+fn encode_reg_imm_op(
+    inst: &Instruction,
+    opcode: u8,
+    labels: Option<&HashMap<String, usize>>,
+) -> Result<Vec<u8>, AssemblerError>
+{
+    check_operand_count(inst, 2)?;
+    Ok(vec![
+        opcode,
+        parse_register(&inst.operands[0])?,
+        parse_value(&inst.operands[1], labels)?,
+    ])
+}
@@ -21,11 +21,17 @@
 //!     JMP start   ; Jump back to the start label
 //! ```
 
+mod diagnostic;
 mod error;
+mod include;
 mod instruction;
 mod parser;
+mod symbol;
 
+pub use diagnostic::Diagnostic;
+pub use error::AssemblerError;
 pub use parser::*;
+pub use symbol::{Symbol, SymbolKind};
 
 /// The main assembler that converts assembly code into bytecode.
 ///
@@ -50,6 +56,7 @@ impl Assembler
     ///
     /// # Example
     /// ```
+    /// # use vm::Assembler;
     /// let assembler = Assembler::new();
     /// ```
     pub fn new() -> Self
@@ -57,6 +64,27 @@ impl Assembler
         Self { parser: Parser::new() }
     }
 
+    /// Creates an assembler for a machine with `num_registers` registers.
+    ///
+    /// [`Assembler::new`] allows r0-r7, matching the default `VMConfig`. Use this
+    /// with the same count as `VMConfig::num_registers` to use the extra registers.
+    ///
+    /// # Example
+    /// ```
+    /// # use vm::Assembler;
+    /// let mut assembler = Assembler::with_registers(16);
+    /// assert_eq!(
+    ///     assembler.assemble("MOV r10, 1").unwrap(),
+    ///     vec![0x04, 0x0A, 0x01]
+    /// );
+    /// ```
+    pub fn with_registers(num_registers: usize) -> Self
+    {
+        let mut parser = Parser::new();
+        parser.set_num_registers(num_registers);
+        Self { parser }
+    }
+
     /// Converts assembly code into bytecode.
     ///
     /// This is the main entry point for assembly. It takes assembly code as a string
@@ -71,6 +99,7 @@ impl Assembler
     ///
     /// # Example
     /// ```
+    /// # use vm::Assembler;
     /// let mut assembler = Assembler::new();
     /// let code = "
     ///     MOV r0, 5
@@ -82,9 +111,123 @@ impl Assembler
     ///     Err(e) => eprintln!("Assembly failed: {}", e),
     /// }
     /// ```
-    pub fn assemble(&mut self, code: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>>
+    pub fn assemble(&mut self, code: &str) -> Result<Vec<u8>, AssemblerError>
+    {
+        self.parser.assemble(code)
+    }
+
+    /// Converts assembly code into bytecode, reporting every error found.
+    ///
+    /// Unlike [`Assembler::assemble`], this keeps going after an error so a whole
+    /// file can be fixed in one go.
+    ///
+    /// # Arguments
+    /// * `code` - The assembly source code as a string
+    ///
+    /// # Returns
+    /// * `Ok(Vec<u8>)` - The generated bytecode if assembly succeeds
+    /// * `Err(Vec<AssemblerError>)` - Every error found, ordered by source line
+    pub fn assemble_all(&mut self, code: &str) -> Result<Vec<u8>, Vec<AssemblerError>>
+    {
+        self.parser.assemble_all(code)
+    }
+
+    /// Converts assembly code into bytecode and a human-readable listing.
+    ///
+    /// The listing has one line per source line showing its address, the bytes it
+    /// produced and the original text:
+    ///
+    /// ```text
+    /// 0000: 04 00 05    MOV r0, 5
+    /// 0003:             loop:
+    /// ```
+    ///
+    /// # Arguments
+    /// * `code` - The assembly source code as a string
+    ///
+    /// # Returns
+    /// * `Ok((Vec<u8>, String))` - The bytecode and the listing
+    /// * `Err(AssemblerError)` - The first error found
+    pub fn assemble_with_listing(&mut self, code: &str) -> Result<(Vec<u8>, String), AssemblerError>
+    {
+        self.parser.assemble_with_listing(code)
+    }
+
+    /// Converts assembly code into bytecode and returns the program's entry point.
+    ///
+    /// The entry point is the address of the label named by an `.entry` (or `.start`)
+    /// directive, or 0 if the program doesn't have one. Pass it to
+    /// `VMConfig::builder().pc_start(...)` to start execution there.
+    ///
+    /// # Arguments
+    /// * `code` - The assembly source code as a string
+    ///
+    /// # Returns
+    /// * `Ok((Vec<u8>, usize))` - The bytecode and the entry point address
+    /// * `Err(AssemblerError)` - The first error found, e.g. an undefined entry label
+    pub fn assemble_with_entry(&mut self, code: &str) -> Result<(Vec<u8>, usize), AssemblerError>
+    {
+        self.parser.assemble_with_entry(code)
+    }
+
+    /// Reads a source file and converts it into bytecode.
+    ///
+    /// Any `%include "file.asm"` line is replaced with the contents of that file
+    /// before assembly, so every file shares one set of labels. Included paths
+    /// are relative to the directory of the file that includes them, and a file
+    /// that (directly or indirectly) includes itself is an error. Errors name the
+    /// file and line they occurred on, and [`AssemblerError::file`] returns the file.
+    ///
+    /// # Arguments
+    /// * `path` - The source file to assemble
+    ///
+    /// # Returns
+    /// * `Ok(Vec<u8>)` - The generated bytecode if assembly succeeds
+    /// * `Err(AssemblerError::InvalidInclude)` - If a file can't be read or includes itself
+    /// * `Err(AssemblerError)` - The first error found while assembling
+    pub fn assemble_file(&mut self, path: impl AsRef<std::path::Path>) -> Result<Vec<u8>, AssemblerError>
+    {
+        self.parser.assemble_file(path.as_ref())
+    }
+
+    /// Turns the peephole optimizer on or off (it is off by default).
+    ///
+    /// When enabled, later assemblies remove instructions that can't affect the
+    /// program: a `MOV` whose register is overwritten by the next `MOV`, and an
+    /// `ADD reg, 0` whose flags are overwritten by the next `ADD` or `SUB`.
+    /// Instructions at a label are never removed, and labels move along with
+    /// the code so jumps still land in the right place. A program that jumps to,
+    /// calls or lists (`.addr`) a numeric address is left as written, since that
+    /// address can't move with the code.
+    ///
+    /// # Example
+    /// ```
+    /// # use vm::Assembler;
+    /// let mut assembler = Assembler::new();
+    /// assembler.optimize(true);
+    /// let bytecode = assembler.assemble("MOV r0, 1\nMOV r0, 2\nHALT").unwrap();
+    /// assert_eq!(bytecode, vec![0x04, 0x00, 0x02, 0xFF]);
+    /// ```
+    pub fn optimize(&mut self, enabled: bool)
     {
-        self.parser.assemble(code).map_err(|e| e.into())
+        self.parser.set_optimize(enabled);
+    }
+
+    /// Converts assembly code into bytecode zero-padded to exactly `size` bytes.
+    ///
+    /// Useful for fixed-size ROM images.
+    ///
+    /// # Arguments
+    /// * `code` - The assembly source code as a string
+    /// * `size` - Length of the image to produce
+    ///
+    /// # Returns
+    /// * `Ok(Vec<u8>)` - The bytecode followed by zeros, `size` bytes in total
+    /// * `Err(AssemblerError::ProgramTooLarge)` - If the program is longer than `size`
+    /// * `Err(AssemblerError)` - The first error found while assembling
+    pub fn assemble_padded(&mut self, code: &str, size: usize) -> Result<Vec<u8>, AssemblerError>
+    {
+        self.parser.assemble_padded(code, size)
     }
 
     /// Returns a reference to the label-to-address mapping.
@@ -98,4 +241,38 @@ impl Assembler
     {
         &self.parser.labels
     }
+
+    /// Returns every label from the last assembly, sorted by address.
+    ///
+    /// Unlike [`Assembler::labels`], each entry also records whether the label
+    /// marks code or data.
+    ///
+    /// # Returns
+    /// * A vector of symbols, ordered by address and then by name
+    pub fn symbol_table(&self) -> Vec<Symbol>
+    {
+        self.parser.symbol_table()
+    }
+
+    /// Looks for likely mistakes in the last assembled program.
+    ///
+    /// Currently this reports unreachable code: instructions that follow an
+    /// unconditional `HALT`, `JMP`, `JR`, `JMPR` or `RET` without a label in between.
+    /// Only the first instruction of each unreachable block is reported.
+    ///
+    /// # Returns
+    /// * Warnings ordered by line; the bytecode is unaffected
+    pub fn analyze(&self) -> Vec<Diagnostic>
+    {
+        self.parser.analyze()
+    }
+
+    /// Returns a reference to the constants defined with `EQU`.
+    ///
+    /// # Returns
+    /// * A reference to the HashMap containing constant names and their values
+    pub fn constants(&self) -> &std::collections::HashMap<String, u8>
+    {
+        &self.parser.constants
+    }
 }
@@ -5,33 +5,77 @@
 //! 1. First pass collects labels and calculates instruction sizes
 //! 2. Second pass generates the actual bytecode
 //!
+//! When optimization is enabled, a peephole pass runs between the two passes and
+//! removes redundant instructions, moving later instructions and labels down.
+//!
 //! The parser handles:
 //! - Comment removal (lines starting with ';')
-//! - Label definitions (lines ending with ':')
+//! - Label definitions (`name:`, alone or before an instruction on the same line)
+//! - Local labels (`.name:`), scoped to the closest global label before them
+//! - Directives such as `.org`, `.entry`, `.byte`, `.addr` and `.str` (lines starting with '.')
+//! - Constant definitions (`NAME EQU value`)
 //! - Instruction parsing (opcode and operands)
 //! - Register validation
 //! - Memory address resolution
-//! - Label resolution
+//! - Label resolution, including `+`/`-` expressions such as `buffer + 1`
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
+use super::diagnostic::Diagnostic;
 use super::error::AssemblerError;
-use super::instruction::Instruction;
+use super::include::expand_includes;
+use super::instruction::{is_register, parse_literal, parse_register, parse_string_literal, Instruction};
+use super::symbol::{Symbol, SymbolKind};
+use crate::span::Span;
+
+/// Registers available unless [`Parser::set_num_registers`] says otherwise, matching `VMConfig`
+const DEFAULT_NUM_REGISTERS: usize = 8;
 
 /// Parser for assembly code
 ///
 /// Maintains state during the assembly process including:
 /// - Collected instructions
 /// - Label addresses
+/// - Constant values
 /// - Current address counter
 pub struct Parser
 {
     /// Instructions collected during first pass
-    instructions: Vec<Instruction>,
+    instructions: Vec<SourceInstruction>,
     /// Map of label names to their addresses in memory
     pub labels: HashMap<String, usize>,
+    /// Map of constant names (defined with `EQU`) to their values
+    pub constants: HashMap<String, u8>,
     /// Current address during assembly
     current_address: usize,
+    /// Address at the start of each source line, used for listings
+    line_addresses: Vec<usize>,
+    /// Label named by an `.entry` directive, with the line it appeared on
+    entry: Option<(usize, String)>,
+    /// Whether to run the peephole optimizer between the two passes
+    optimize: bool,
+    /// The last global label defined, which `.local` labels are scoped to
+    scope: Option<String>,
+    /// Number of registers the program may use, r0 first
+    num_registers: usize,
+    /// Label addresses from the previous layout of the program, used to size
+    /// instructions whose encoding depends on them
+    layout: Option<HashMap<String, usize>>,
+    /// Size of each instruction in the previous layout, by position
+    previous_sizes: Vec<usize>,
+}
+
+/// An instruction along with where it will be placed
+///
+/// Where it came from is recorded in the instruction's span.
+struct SourceInstruction
+{
+    /// Address of the instruction's first byte
+    address: usize,
+    /// Number of bytes the instruction was laid out with
+    size: usize,
+    inst: Instruction,
 }
 
 impl Default for Parser
@@ -50,21 +94,190 @@ impl Parser
         Self {
             instructions: Vec::new(),
             labels: HashMap::new(),
+            constants: HashMap::new(),
             current_address: 0,
+            line_addresses: Vec::new(),
+            entry: None,
+            optimize: false,
+            scope: None,
+            num_registers: DEFAULT_NUM_REGISTERS,
+            layout: None,
+            previous_sizes: Vec::new(),
         }
     }
 
+    /// Enables or disables the peephole optimizer for later assemblies
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether redundant instructions should be removed
+    pub fn set_optimize(&mut self, enabled: bool)
+    {
+        self.optimize = enabled;
+    }
+
+    /// Sets how many registers later assemblies may use
+    ///
+    /// # Arguments
+    /// * `num_registers` - Register operands must be below `r{num_registers}`
+    pub fn set_num_registers(&mut self, num_registers: usize)
+    {
+        self.num_registers = num_registers;
+    }
+
     /// Main entry point for assembly process
     ///
     /// # Arguments
     /// * `code` - The assembly source code to process
     ///
     /// # Returns
-    /// * `Result<Vec<u8>, AssemblerError>` - The generated bytecode or an error
+    /// * `Result<Vec<u8>, AssemblerError>` - The generated bytecode or the first error in the source
     pub fn assemble(&mut self, code: &str) -> Result<Vec<u8>, AssemblerError>
     {
-        self.first_pass(code)?;
-        self.second_pass()
+        self.assemble_all(code)
+            .map_err(|errors| errors.into_iter().next().expect("assembly failed without an error"))
+    }
+
+    /// Assembles the code, collecting every error instead of stopping at the first
+    ///
+    /// # Arguments
+    /// * `code` - The assembly source code to process
+    ///
+    /// # Returns
+    /// * `Result<Vec<u8>, Vec<AssemblerError>>` - The generated bytecode, or all errors ordered by line
+    pub fn assemble_all(&mut self, code: &str) -> Result<Vec<u8>, Vec<AssemblerError>>
+    {
+        self.layout = None;
+        self.previous_sizes.clear();
+        let mut errors = self.first_pass(code);
+
+        // A `LOAD` or `STORE` of a label above 0xFF takes the wide encoding, which moves
+        // everything after it. Lay the program out again until the labels stop moving.
+        // Instructions never shrink between layouts, so this ends once none grow.
+        while errors.is_empty() && self.layout.as_ref() != Some(&self.labels) {
+            self.layout = Some(self.labels.clone());
+            self.previous_sizes = self.instructions.iter().map(|source| source.size).collect();
+            errors = self.first_pass(code);
+        }
+
+        // A numeric jump target wouldn't move with the code it points at
+        let fixed_targets = self.instructions.iter().any(|source| source.inst.has_fixed_target());
+        if self.optimize && errors.is_empty() && !fixed_targets {
+            self.optimize_instructions();
+        }
+        let bytecode = self.second_pass(&mut errors);
+
+        if let Some((line, label)) = &self.entry {
+            if !self.labels.contains_key(label) {
+                errors.push(AssemblerError::UndefinedLabel(label.clone()).at_line(*line));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(bytecode)
+        } else {
+            errors.sort_by_key(|e| e.line());
+            Err(errors)
+        }
+    }
+
+    /// Assembles the code and produces a listing alongside the bytecode
+    ///
+    /// Each source line is listed with the address it starts at and the bytes
+    /// it produced, e.g. `0008: 30 00 01    ADD r0, r1`. Lines that emit nothing,
+    /// such as labels and comments, are listed with just their address.
+    ///
+    /// # Arguments
+    /// * `code` - The assembly source code to process
+    ///
+    /// # Returns
+    /// * `Result<(Vec<u8>, String), AssemblerError>` - The bytecode and listing, or the first error
+    pub fn assemble_with_listing(&mut self, code: &str) -> Result<(Vec<u8>, String), AssemblerError>
+    {
+        let bytecode = self.assemble(code)?;
+
+        let mut listing = String::new();
+        for (index, line) in code.lines().enumerate() {
+            let line_number = index + 1;
+            let bytes = match self.instructions.iter().find(|source| source.inst.span.line == line_number) {
+                Some(source) => {
+                    let size = self.calculate_instruction_size(&source.inst);
+                    bytecode[source.address..source.address + size]
+                        .iter()
+                        .map(|byte| format!("{:02X}", byte))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                }
+                None => String::new(),
+            };
+
+            let entry = format!("{:04X}: {:<11} {}", self.line_addresses[index], bytes, line.trim());
+            listing.push_str(entry.trim_end());
+            listing.push('\n');
+        }
+
+        Ok((bytecode, listing))
+    }
+
+    /// Assembles the code and returns the program's entry point alongside the bytecode
+    ///
+    /// The entry point is the address of the label named by an `.entry` (or `.start`)
+    /// directive, or 0 if there is none.
+    ///
+    /// # Arguments
+    /// * `code` - The assembly source code to process
+    ///
+    /// # Returns
+    /// * `Result<(Vec<u8>, usize), AssemblerError>` - The bytecode and entry point, or the first error
+    pub fn assemble_with_entry(&mut self, code: &str) -> Result<(Vec<u8>, usize), AssemblerError>
+    {
+        let bytecode = self.assemble(code)?;
+        Ok((bytecode, self.entry_point()))
+    }
+
+    /// Assembles the code and zero-pads the bytecode to exactly `size` bytes
+    ///
+    /// # Arguments
+    /// * `code` - The assembly source code to process
+    /// * `size` - Length of the image to produce
+    ///
+    /// # Returns
+    /// * `Result<Vec<u8>, AssemblerError>` - The padded bytecode, or the first error
+    ///   (`ProgramTooLarge` if the program is already longer than `size`)
+    pub fn assemble_padded(&mut self, code: &str, size: usize) -> Result<Vec<u8>, AssemblerError>
+    {
+        let mut bytecode = self.assemble(code)?;
+        if bytecode.len() > size {
+            return Err(AssemblerError::ProgramTooLarge {
+                size: bytecode.len(),
+                limit: size,
+            });
+        }
+        bytecode.resize(size, 0);
+        Ok(bytecode)
+    }
+
+    /// Reads and assembles a source file, expanding its `%include` directives first
+    ///
+    /// Errors report the file and line the failing code came from.
+    ///
+    /// # Arguments
+    /// * `path` - The source file to assemble
+    ///
+    /// # Returns
+    /// * `Result<Vec<u8>, AssemblerError>` - The generated bytecode, or the first error
+    pub fn assemble_file(&mut self, path: &Path) -> Result<Vec<u8>, AssemblerError>
+    {
+        let expanded = expand_includes(path)?;
+        self.assemble(&expanded.code).map_err(|e| expanded.locate(e))
+    }
+
+    /// Returns the address named by the last assembly's `.entry` directive, or 0 if it had none
+    pub fn entry_point(&self) -> usize
+    {
+        self.entry
+            .as_ref()
+            .and_then(|(_, label)| self.labels.get(label).copied())
+            .unwrap_or(0)
     }
 
     /// First pass of assembly - collect labels and calculate addresses
@@ -74,33 +287,297 @@ impl Parser
     /// 2. Collects label definitions and their addresses
     /// 3. Parses instructions to calculate their sizes
     /// 4. Builds the instruction list for the second pass
-    fn first_pass(&mut self, code: &str) -> Result<(), AssemblerError>
+    ///
+    /// Lines that fail to parse are skipped so the remaining lines can still be checked.
+    ///
+    /// # Returns
+    /// * `Vec<AssemblerError>` - Every error encountered, tagged with its line number
+    fn first_pass(&mut self, code: &str) -> Vec<AssemblerError>
     {
+        let mut errors = Vec::new();
+
         self.current_address = 0;
         self.instructions.clear();
         self.labels.clear();
+        self.constants.clear();
+        self.line_addresses.clear();
+        self.entry = None;
+        self.scope = None;
 
-        for line in code.lines() {
-            let line = line.trim();
+        for (index, line) in code.lines().enumerate() {
+            let span = Span {
+                line: index + 1,
+                ..Span::default()
+            }
+            .subspan(code, line);
+            self.line_addresses.push(self.current_address);
+            if let Err(e) = self.parse_line(line, span) {
+                // Point at the line's code, without indentation or comment
+                errors.push(e.at_span(span.subspan(line, strip_comment(line).trim())));
+            }
+        }
+        errors
+    }
 
-            // Skip empty lines and comments
-            if line.is_empty() || line.starts_with(';') {
-                continue;
+    /// Processes a single source line during the first pass
+    ///
+    /// # Arguments
+    /// * `raw` - The raw source line
+    /// * `span` - Where `raw` is in the source, recorded with any instruction
+    fn parse_line(&mut self, raw: &str, span: Span) -> Result<(), AssemblerError>
+    {
+        // Strip comments
+        let mut line = strip_comment(raw).trim();
+
+        // Handle labels, which may be followed by more labels or an instruction
+        while let Some((label, rest)) = line.split_once(':') {
+            if label.contains('"') {
+                break; // The ':' is inside a string
+            }
+            self.define_label(label.trim())?;
+            line = rest.trim();
+        }
+
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        // Handle strings, which are emitted as `.byte` data
+        let (directive, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let directive = directive.to_uppercase();
+        if directive == ".STR" || directive == ".STRZ" {
+            let mut bytes = parse_string_literal(rest.trim())?;
+            if directive == ".STRZ" {
+                bytes.push(0);
             }
+            if bytes.is_empty() {
+                return Err(AssemblerError::SyntaxError(".str needs at least one character".to_string()));
+            }
+            let mut inst = Instruction::new(".BYTE".to_string(), bytes.iter().map(|byte| byte.to_string()).collect());
+            inst.span = span.subspan(raw, line);
+            self.push_instruction(inst);
+            return Ok(());
+        }
+
+        // Handle constant definitions (NAME EQU value)
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.len() > 1 && tokens[1].eq_ignore_ascii_case("EQU") {
+            self.define_constant(&tokens)?;
+            return Ok(());
+        }
+
+        // Parse instruction, substituting any constants already defined and local label names
+        let mut inst = line.parse::<Instruction>()?;
+        inst.span = span.subspan(raw, line);
+        for operand in &mut inst.operands {
+            *operand = self.substitute_names(operand)?;
+            if is_register(operand) && parse_register(operand)? as usize >= self.num_registers {
+                return Err(AssemblerError::InvalidRegister(operand.clone()));
+            }
+        }
+
+        // Handle the origin directive, which moves the current address forward
+        if inst.opcode == ".ORG" {
+            self.set_origin(&inst)?;
+            return Ok(());
+        }
+
+        // Handle the entry point directive, resolved once all labels are known
+        if inst.opcode == ".ENTRY" || inst.opcode == ".START" {
+            self.set_entry(&inst, span.line)?;
+            return Ok(());
+        }
 
-            // Handle labels (lines ending with ':')
-            if line.ends_with(':') {
-                let label = &line[..line.len() - 1].trim();
+        self.push_instruction(inst);
+        Ok(())
+    }
+
+    /// Adds an instruction at the current address and moves past it
+    ///
+    /// # Arguments
+    /// * `inst` - The instruction to add, with its span set
+    fn push_instruction(&mut self, mut inst: Instruction)
+    {
+        let mut size = self.calculate_instruction_size(&inst);
+
+        // Keep an instruction the previous layout widened wide. Shrinking it back could
+        // move a label back under 0xFF and make the layout flip between the two forever.
+        if self
+            .previous_sizes
+            .get(self.instructions.len())
+            .is_some_and(|&previous| previous > size)
+        {
+            inst.wide = true;
+            size = self.calculate_instruction_size(&inst);
+        }
+
+        self.instructions.push(SourceInstruction {
+            address: self.current_address,
+            size,
+            inst,
+        });
+        self.current_address += size;
+    }
+
+    /// Records a label at the current address
+    ///
+    /// A label starting with `.` is local to the closest global label before it,
+    /// and is stored under the combined name, e.g. `.loop` after `main:` is `main.loop`.
+    ///
+    /// # Arguments
+    /// * `label` - The label name, without the trailing ':'
+    fn define_label(&mut self, label: &str) -> Result<(), AssemblerError>
+    {
+        let label = match label.strip_prefix('.') {
+            Some(local) => {
+                self.validate_label(local)?;
+                self.local_label_name(label)?
+            }
+            None => {
                 self.validate_label(label)?;
-                self.labels.insert(label.to_string(), self.current_address);
-                continue;
+                self.scope = Some(label.to_string());
+                label.to_string()
+            }
+        };
+        if self.constants.contains_key(&label) {
+            return Err(AssemblerError::InvalidLabel(format!(
+                "{} is already defined as a constant",
+                label
+            )));
+        }
+        self.labels.insert(label, self.current_address);
+        Ok(())
+    }
+
+    /// Returns the full name of a `.local` label in the current scope
+    ///
+    /// # Arguments
+    /// * `label` - The local label, including its leading '.'
+    fn local_label_name(&self, label: &str) -> Result<String, AssemblerError>
+    {
+        match &self.scope {
+            Some(scope) => Ok(format!("{}{}", scope, label)),
+            None => Err(AssemblerError::InvalidLabel(format!(
+                "Local label {} has no global label before it",
+                label
+            ))),
+        }
+    }
+
+    /// Records a `NAME EQU value` constant definition
+    ///
+    /// The value may be a number or a previously defined constant.
+    ///
+    /// # Arguments
+    /// * `tokens` - The whitespace-separated tokens of the definition line
+    fn define_constant(&mut self, tokens: &[&str]) -> Result<(), AssemblerError>
+    {
+        if tokens.len() != 3 {
+            return Err(AssemblerError::SyntaxError(format!(
+                "Expected NAME EQU value, got: {}",
+                tokens.join(" ")
+            )));
+        }
+
+        let name = tokens[0];
+        self.validate_label(name)?;
+        if self.labels.contains_key(name) || self.constants.contains_key(name) {
+            return Err(AssemblerError::InvalidLabel(format!("{} is already defined", name)));
+        }
+
+        let value = match self.constants.get(tokens[2]) {
+            Some(&value) => value,
+            None => parse_literal(tokens[2])?,
+        };
+        self.constants.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    /// Replaces constants in an operand with their values, and local labels with their full names
+    ///
+    /// Each term of an expression like `SIZE - 1` is substituted separately.
+    ///
+    /// # Arguments
+    /// * `operand` - The operand as written, with any whitespace removed
+    fn substitute_names(&self, operand: &str) -> Result<String, AssemblerError>
+    {
+        let substitute = |term: &str| match self.constants.get(term) {
+            Some(value) => Ok(value.to_string()),
+            None if term.starts_with('.') => self.local_label_name(term),
+            None => Ok(term.to_string()),
+        };
+
+        let mut result = String::new();
+        let mut start = 0;
+        for (index, c) in operand.char_indices() {
+            // A `-` at the start of a term is a sign, not a subtraction
+            if (c == '+' || c == '-') && index > start {
+                result.push_str(&substitute(&operand[start..index])?);
+                result.push(c);
+                start = index + 1;
             }
+        }
+        result.push_str(&substitute(&operand[start..])?);
+        Ok(result)
+    }
+
+    /// Handles a `.org addr` directive by moving the current address
+    ///
+    /// The origin can only move forward; the gap is filled with zeros
+    /// during the second pass.
+    ///
+    /// # Arguments
+    /// * `inst` - The parsed `.org` directive
+    fn set_origin(&mut self, inst: &Instruction) -> Result<(), AssemblerError>
+    {
+        if inst.operands.len() != 1 {
+            return Err(AssemblerError::InvalidNumberOfOperands {
+                instruction: inst.opcode.clone(),
+                expected: 1,
+                got: inst.operands.len(),
+            });
+        }
+
+        let operand = &inst.operands[0];
+        let origin = if let Some(hex) = operand.strip_prefix("0x") {
+            usize::from_str_radix(hex, 16)
+        } else {
+            operand.parse()
+        }
+        .map_err(|_| AssemblerError::InvalidAddress(operand.clone()))?;
 
-            // Parse instruction
-            let inst = line.parse::<Instruction>()?;
-            self.current_address += self.calculate_instruction_size(&inst);
-            self.instructions.push(inst);
+        if origin < self.current_address {
+            return Err(AssemblerError::InvalidAddress(format!(
+                ".org {} moves backwards from address 0x{:02X}",
+                operand, self.current_address
+            )));
         }
+
+        self.current_address = origin;
+        Ok(())
+    }
+
+    /// Handles an `.entry label` directive by recording the label to start at
+    ///
+    /// # Arguments
+    /// * `inst` - The parsed `.entry` directive
+    /// * `line_number` - The line it appeared on, used to report an undefined label
+    fn set_entry(&mut self, inst: &Instruction, line_number: usize) -> Result<(), AssemblerError>
+    {
+        if inst.operands.len() != 1 {
+            return Err(AssemblerError::InvalidNumberOfOperands {
+                instruction: inst.opcode.clone(),
+                expected: 1,
+                got: inst.operands.len(),
+            });
+        }
+        if self.entry.is_some() {
+            return Err(AssemblerError::SyntaxError("Entry point defined more than once".to_string()));
+        }
+
+        let label = &inst.operands[0];
+        self.validate_label(label)?;
+        self.entry = Some((line_number, label.clone()));
         Ok(())
     }
 
@@ -113,8 +590,14 @@ impl Parser
     /// * `usize` - Number of bytes needed for this instruction
     fn calculate_instruction_size(&self, inst: &Instruction) -> usize
     {
-        // Get the size from the encoded instruction
-        inst.encode().map(|bytes| bytes.len()).unwrap_or(0)
+        // Get the size from the encoded instruction, using the label addresses from the
+        // previous layout if there is one and placeholders otherwise
+        self.layout
+            .as_ref()
+            .and_then(|labels| inst.encode(0, Some(labels)).ok())
+            .or_else(|| inst.encode(0, None).ok())
+            .map(|bytes| bytes.len())
+            .unwrap_or(0)
     }
 
     /// Performs the second pass of assembly, generating the final bytecode
@@ -124,43 +607,180 @@ impl Parser
     /// 2. Converts instructions to their binary representation
     /// 3. Resolves all label references to addresses
     ///
+    /// Instructions that fail to encode are skipped and their errors added to `errors`.
+    ///
     /// # Returns
-    /// * `Result<Vec<u8>, AssemblerError>` - The complete program bytecode
-    fn second_pass(&self) -> Result<Vec<u8>, AssemblerError>
+    /// * `Vec<u8>` - The program bytecode (only meaningful if no errors were found)
+    fn second_pass(&self, errors: &mut Vec<AssemblerError>) -> Vec<u8>
     {
         let mut bytecode = Vec::new();
-        let mut current_addr = 0;
-
-        for inst in &self.instructions {
-            // Clone the instruction bytes since we might need to modify them
-            let mut inst_bytes = inst.encode()?;
-
-            // If this is a jump/call instruction, resolve the label
-            match inst.opcode.as_str() {
-                "JMP" | "JEQ" | "JGT" | "JNE" | "CALL" => {
-                    if let Some(label) = inst.operands.first() {
-                        // Check if the operand is a label (not a numeric value)
-                        if !label.starts_with("0x") && !label.chars().next().unwrap().is_numeric() {
-                            if let Some(&addr) = self.labels.get(label) {
-                                // Update the address in the instruction bytes
-                                inst_bytes[1] = addr as u8;
-                            } else {
-                                return Err(AssemblerError::UndefinedLabel(label.clone()));
-                            }
-                        }
-                    }
+
+        for SourceInstruction { address, inst, .. } in &self.instructions {
+            // Pad any gap left by an `.org` directive
+            if bytecode.len() < *address {
+                bytecode.resize(*address, 0);
+            }
+
+            // Label operands are resolved while encoding. Constants were substituted during
+            // the first pass, so a constant that's still unresolved was used too early.
+            match inst.encode(*address, Some(&self.labels)) {
+                Ok(inst_bytes) => bytecode.extend_from_slice(&inst_bytes),
+                Err(AssemblerError::UndefinedLabel(name)) if self.constants.contains_key(&name) => {
+                    errors.push(AssemblerError::UndefinedConstant(name).at_span(inst.span))
                 }
-                _ => {}
+                Err(e) => errors.push(e.at_span(inst.span)),
             }
+        }
+
+        bytecode
+    }
 
-            // Keep track of current address for label resolution
-            current_addr += inst_bytes.len();
+    /// Removes redundant instructions collected by the first pass
+    ///
+    /// Two patterns are removed:
+    /// - A move (`MOV`, `MOVW`, `MOVR` or `CLR`) whose register is overwritten by the
+    ///   next instruction, another move that doesn't read it
+    /// - `ADD reg, 0` or `SUB reg, 0` when the next instruction is an `ADD` or `SUB`,
+    ///   which overwrites the carry and sign flags the no-op would have set
+    ///
+    /// Instructions at a label's address are always kept, since they may be jumped to.
+    /// Programs with a jump, call or `.addr` to a numeric address aren't optimized at all.
+    /// The remaining instructions, labels and line addresses are moved down to close
+    /// the gaps, except after an `.org`, whose address is kept as written.
+    fn optimize_instructions(&mut self)
+    {
+        let targets: HashSet<usize> = self.labels.values().copied().collect();
+        let redundant: Vec<bool> = self
+            .instructions
+            .iter()
+            .enumerate()
+            .map(|(index, source)| {
+                let next = self.instructions.get(index + 1).map(|next| &next.inst);
+                !targets.contains(&source.address) && is_redundant(&source.inst, next)
+            })
+            .collect();
 
-            // Add the instruction bytes to the final bytecode
-            bytecode.extend_from_slice(&inst_bytes);
+        // Record how far each instruction moves: (old address, size, shift before, shift after)
+        let mut moves = Vec::with_capacity(self.instructions.len());
+        let mut shift = 0;
+        let mut next_address = 0;
+        for (source, &redundant) in self.instructions.iter().zip(&redundant) {
+            if source.address != next_address {
+                // An `.org` fixed this address, so nothing after it moves
+                shift = 0;
+            }
+            let size = self.calculate_instruction_size(&source.inst);
+            let before = shift;
+            if redundant {
+                shift += size;
+            }
+            moves.push((source.address, size, before, shift));
+            next_address = source.address + size;
         }
 
-        Ok(bytecode)
+        let shift_at = |address: usize| {
+            if let Some(&(_, _, before, _)) = moves.iter().find(|(old, ..)| *old == address) {
+                return before;
+            }
+            match moves.iter().rev().find(|(old, ..)| *old < address) {
+                Some(&(old, size, _, after)) if old + size == address => after,
+                _ => 0,
+            }
+        };
+
+        for address in self.labels.values_mut() {
+            *address -= shift_at(*address);
+        }
+        for address in &mut self.line_addresses {
+            *address -= shift_at(*address);
+        }
+
+        let instructions = std::mem::take(&mut self.instructions);
+        self.instructions = instructions
+            .into_iter()
+            .zip(moves)
+            .zip(redundant)
+            .filter(|(_, redundant)| !redundant)
+            .map(|((mut source, (_, _, before, _)), _)| {
+                source.address -= before;
+                source
+            })
+            .collect();
+    }
+
+    /// Reports instructions that can't be reached because they follow an
+    /// unconditional `HALT`, `JMP`, `JR` or `RET` with no label in between
+    ///
+    /// Data emitted with `.byte`/`DB` is never reported.
+    ///
+    /// # Returns
+    /// * `Vec<Diagnostic>` - One warning per unreachable block, ordered by line
+    pub fn analyze(&self) -> Vec<Diagnostic>
+    {
+        let mut diagnostics = Vec::new();
+        let mut dead_after: Option<&str> = None;
+        let mut reported = false;
+
+        for source in &self.instructions {
+            let opcode = source.inst.opcode.as_str();
+            if is_data(opcode) {
+                continue;
+            }
+
+            // A label makes the code after it reachable again
+            if self.labels.values().any(|&address| address == source.address) {
+                dead_after = None;
+            }
+
+            match dead_after {
+                Some(previous) if !reported => {
+                    diagnostics.push(Diagnostic {
+                        line: source.inst.span.line,
+                        span: source.inst.span,
+                        message: format!("unreachable code after {}", previous),
+                    });
+                    reported = true;
+                }
+                Some(_) => {}
+                None if matches!(opcode, "HALT" | "HLT" | "JMP" | "JR" | "JMPR" | "RET") => {
+                    dead_after = Some(opcode);
+                    reported = false;
+                }
+                None => {}
+            }
+        }
+
+        diagnostics
+    }
+
+    /// Builds the symbol table from the labels collected in the first pass
+    ///
+    /// A label is classified as data when the first thing placed at its address
+    /// is a `.byte`/`DB` directive, or when nothing follows it.
+    ///
+    /// # Returns
+    /// * `Vec<Symbol>` - Every label, ordered by address and then by name
+    pub fn symbol_table(&self) -> Vec<Symbol>
+    {
+        let mut symbols: Vec<Symbol> = self
+            .labels
+            .iter()
+            .map(|(name, &address)| {
+                let next = self.instructions.iter().find(|source| source.address >= address);
+                let kind = match next {
+                    Some(source) if !is_data(&source.inst.opcode) => SymbolKind::Code,
+                    _ => SymbolKind::Data,
+                };
+                Symbol {
+                    name: name.clone(),
+                    address,
+                    kind,
+                }
+            })
+            .collect();
+
+        symbols.sort_by(|a, b| a.address.cmp(&b.address).then_with(|| a.name.cmp(&b.name)));
+        symbols
     }
 
     /// Validates a label name
@@ -197,22 +817,56 @@ impl Parser
 
         Ok(())
     }
+}
 
-    fn parse_instruction(&mut self, tokens: &[&str]) -> Result<Instruction, AssemblerError>
-    {
-        match tokens[0].to_uppercase().as_str() {
-            // ... existing matches ...
-            "HALT" | "HLT" => {
-                if tokens.len() != 1 {
-                    return Err(AssemblerError::InvalidInstruction("HALT takes no arguments".to_string()));
-                }
-                Ok(Instruction::new("HALT".to_string(), vec![]))
-            }
-            // ... rest of the matches ...
-            _ => Err(AssemblerError::InvalidInstruction(format!(
-                "Unknown instruction: {}",
-                tokens[0]
-            ))),
+/// Whether an opcode emits data (`.byte`, `DB` or `.addr`) rather than an instruction
+fn is_data(opcode: &str) -> bool
+{
+    matches!(opcode, ".BYTE" | "DB" | ".ADDR")
+}
+
+/// Removes a `;` comment from a line, ignoring semicolons inside string literals
+pub(super) fn strip_comment(line: &str) -> &str
+{
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..index],
+            _ => {}
         }
     }
+    line
+}
+
+/// Returns the register a move instruction overwrites without reading it
+fn moved_register(inst: &Instruction) -> Option<&str>
+{
+    match inst.opcode.as_str() {
+        "MOV" | "MOVW" | "MOVR" if inst.operands.len() == 2 && inst.operands[0] != inst.operands[1] => {
+            Some(&inst.operands[0])
+        }
+        "CLR" if inst.operands.len() == 1 => Some(&inst.operands[0]),
+        _ => None,
+    }
+}
+
+/// Whether removing `inst` leaves the program's behavior unchanged, given the instruction after it
+fn is_redundant(inst: &Instruction, next: Option<&Instruction>) -> bool
+{
+    let Some(next) = next else {
+        return false;
+    };
+
+    if let Some(register) = moved_register(inst) {
+        return moved_register(next) == Some(register);
+    }
+
+    let adds_zero = matches!(inst.opcode.as_str(), "ADD" | "SUB" | "ADDI" | "SUBI")
+        && inst.operands.len() == 2
+        && matches!(parse_literal(&inst.operands[1]), Ok(0));
+    adds_zero && matches!(next.opcode.as_str(), "ADD" | "SUB" | "ADDI" | "SUBI")
 }
@@ -0,0 +1,23 @@
+//! Symbol table entries exported by the assembler
+//!
+//! After a successful assembly every label becomes a [`Symbol`], so tools
+//! such as debuggers can map addresses back to names.
+
+/// Whether a label marks instructions or data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind
+{
+    /// The label is followed by an instruction
+    Code,
+    /// The label is followed by `.byte`/`DB` data, or by nothing at all
+    Data,
+}
+
+/// A label with its resolved address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol
+{
+    pub name: String,
+    pub address: usize,
+    pub kind: SymbolKind,
+}
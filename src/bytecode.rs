@@ -0,0 +1,134 @@
+//! Bytecode file format
+//!
+//! Compiled programs are saved with a small header so that a file can be
+//! recognized before it's loaded into the VM:
+//!
+//! ```text
+//! +--------+---------+----------------------+------------------+
+//! | "RBVM" | version | length (u32, LE)     | program bytes... |
+//! +--------+---------+----------------------+------------------+
+//! ```
+
+use std::error::Error;
+use std::path::Path;
+use std::{fmt, fs, io};
+
+/// Magic bytes at the start of every bytecode file
+pub const MAGIC: &[u8; 4] = b"RBVM";
+
+/// Current version of the bytecode file format
+pub const VERSION: u8 = 1;
+
+/// Size of the header in bytes (magic + version + length)
+const HEADER_SIZE: usize = MAGIC.len() + 1 + 4;
+
+/// Errors that can occur while reading or writing bytecode files.
+#[derive(Debug)]
+pub enum BytecodeError
+{
+    /// The file couldn't be read or written
+    Io(io::Error),
+
+    /// The file doesn't start with the `RBVM` magic bytes
+    InvalidMagic,
+
+    /// The file was written by an unsupported format version
+    UnsupportedVersion(u8),
+
+    /// The header's program length doesn't match the data that follows it
+    LengthMismatch
+    {
+        /// Length recorded in the header
+        expected: usize,
+        /// Number of program bytes actually present
+        got: usize,
+    },
+}
+
+impl fmt::Display for BytecodeError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            BytecodeError::Io(e) => write!(f, "I/O error: {}", e),
+            BytecodeError::InvalidMagic => write!(f, "Not a bytecode file (missing RBVM header)"),
+            BytecodeError::UnsupportedVersion(v) => write!(f, "Unsupported bytecode version: {}", v),
+            BytecodeError::LengthMismatch { expected, got } => {
+                write!(f, "Program length mismatch: header says {} bytes, found {}", expected, got)
+            }
+        }
+    }
+}
+
+impl Error for BytecodeError {}
+
+impl From<io::Error> for BytecodeError
+{
+    fn from(e: io::Error) -> Self
+    {
+        BytecodeError::Io(e)
+    }
+}
+
+/// Prepends the file header to a program.
+///
+/// # Arguments
+/// * `bytecode` - The program bytes
+pub fn add_header(bytecode: &[u8]) -> Vec<u8>
+{
+    let mut file = Vec::with_capacity(HEADER_SIZE + bytecode.len());
+    file.extend_from_slice(MAGIC);
+    file.push(VERSION);
+    file.extend_from_slice(&(bytecode.len() as u32).to_le_bytes());
+    file.extend_from_slice(bytecode);
+    file
+}
+
+/// Validates the file header and returns the program bytes that follow it.
+///
+/// # Arguments
+/// * `file` - The full contents of a bytecode file
+pub fn strip_header(file: &[u8]) -> Result<&[u8], BytecodeError>
+{
+    if file.len() < HEADER_SIZE || &file[..MAGIC.len()] != MAGIC {
+        return Err(BytecodeError::InvalidMagic);
+    }
+
+    let version = file[MAGIC.len()];
+    if version != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+
+    let length_bytes: [u8; 4] = file[MAGIC.len() + 1..HEADER_SIZE].try_into().unwrap();
+    let expected = u32::from_le_bytes(length_bytes) as usize;
+    let program = &file[HEADER_SIZE..];
+    if program.len() != expected {
+        return Err(BytecodeError::LengthMismatch {
+            expected,
+            got: program.len(),
+        });
+    }
+
+    Ok(program)
+}
+
+/// Writes a program to a file with the bytecode header.
+///
+/// # Arguments
+/// * `path` - Where to write the file
+/// * `bytecode` - The program bytes
+pub fn write_bytecode(path: impl AsRef<Path>, bytecode: &[u8]) -> Result<(), BytecodeError>
+{
+    fs::write(path, add_header(bytecode))?;
+    Ok(())
+}
+
+/// Reads a program from a bytecode file, validating and stripping its header.
+///
+/// # Arguments
+/// * `path` - The file to read
+pub fn read_bytecode(path: impl AsRef<Path>) -> Result<Vec<u8>, BytecodeError>
+{
+    let file = fs::read(path)?;
+    Ok(strip_header(&file)?.to_vec())
+}
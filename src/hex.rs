@@ -0,0 +1,184 @@
+//! Intel HEX import and export
+//!
+//! Intel HEX is a text format understood by most programmers and emulators.
+//! Each line is a record:
+//!
+//! ```text
+//! :LLAAAATTDD...CC
+//! ```
+//!
+//! where `LL` is the number of data bytes, `AAAA` the address of the first one,
+//! `TT` the record type, `DD...` the data and `CC` a checksum that makes all the
+//! record's bytes sum to zero.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::vm::error::VMError;
+
+/// Maximum number of data bytes written per record
+const BYTES_PER_RECORD: usize = 16;
+
+/// Data record
+const DATA: u8 = 0x00;
+/// End-of-file record
+const END_OF_FILE: u8 = 0x01;
+/// Extended segment address record, giving a segment (address / 16) for later addresses
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+/// Start segment address record (CS:IP), which has no meaning for the VM
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+/// Extended linear address record, giving the upper 16 bits of later addresses
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+/// Start linear address record (EIP), which has no meaning for the VM
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Converts bytecode into Intel HEX records, starting at address 0.
+///
+/// # Returns
+/// * `Ok(String)` - The records, ending with an end-of-file record
+/// * `Err(VMError::InvalidHex)` - If the program is larger than the 32-bit address space
+///
+/// # Example
+/// ```
+/// # use vm::to_intel_hex;
+/// assert_eq!(
+///     to_intel_hex(&[0x04, 0x00, 0x05, 0xFF]).unwrap(),
+///     ":04000000040005FFF4\n:00000001FF\n"
+/// );
+/// ```
+pub fn to_intel_hex(bytecode: &[u8]) -> Result<String, VMError>
+{
+    to_intel_hex_at(bytecode, 0)
+}
+
+/// Converts bytecode into Intel HEX records, placing the first byte at `base_address`.
+///
+/// Data is written 16 bytes per record and ends with an end-of-file record.
+/// Programs placed above 64KiB get extended linear address records.
+///
+/// # Arguments
+/// * `bytecode` - The program bytes
+/// * `base_address` - Address of the first byte
+///
+/// # Returns
+/// * `Ok(String)` - The records
+/// * `Err(VMError::InvalidHex)` - If the last byte would be past address `0xFFFFFFFF`,
+///   the highest address Intel HEX can express
+pub fn to_intel_hex_at(bytecode: &[u8], base_address: u32) -> Result<String, VMError>
+{
+    let last = bytecode.len().saturating_sub(1);
+    if u32::try_from(last)
+        .ok()
+        .and_then(|last| base_address.checked_add(last))
+        .is_none()
+    {
+        return Err(VMError::InvalidHex(format!(
+            "{} bytes at 0x{:08X} run past the end of the 32-bit address space",
+            bytecode.len(),
+            base_address
+        )));
+    }
+
+    let mut output = String::new();
+    let mut upper = 0;
+    let mut offset = 0;
+
+    while offset < bytecode.len() {
+        let address = base_address + offset as u32;
+        if address >> 16 != upper {
+            upper = address >> 16;
+            write_record(&mut output, EXTENDED_LINEAR_ADDRESS, 0, &(upper as u16).to_be_bytes());
+        }
+
+        // Records can't cross a 64KiB boundary, as their address is only 16 bits
+        let until_boundary = 0x10000 - (address & 0xFFFF) as usize;
+        let len = BYTES_PER_RECORD.min(until_boundary).min(bytecode.len() - offset);
+        write_record(&mut output, DATA, address as u16, &bytecode[offset..offset + len]);
+        offset += len;
+    }
+
+    write_record(&mut output, END_OF_FILE, 0, &[]);
+    Ok(output)
+}
+
+/// Parses Intel HEX records into blocks of data.
+///
+/// Every record's checksum and type is checked, and the text must end with an
+/// end-of-file record. Start address records are accepted but ignored.
+///
+/// # Arguments
+/// * `hex` - The Intel HEX text, one record per line
+///
+/// # Returns
+/// * `Ok(Vec<(usize, Vec<u8>)>)` - The address and bytes of each data record, in file order
+/// * `Err(VMError::InvalidHex)` - If a record is malformed, has a bad checksum or an unknown type
+pub fn parse_intel_hex(hex: &str) -> Result<Vec<(usize, Vec<u8>)>, VMError>
+{
+    let mut blocks = Vec::new();
+    let mut base = 0;
+
+    for (index, line) in hex.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = |reason: &str| VMError::InvalidHex(format!("line {}: {}", index + 1, reason));
+
+        let digits = line
+            .strip_prefix(':')
+            .ok_or_else(|| invalid("record doesn't start with ':'"))?;
+        if digits.len() % 2 != 0 || !digits.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid("record isn't made of hex byte pairs"));
+        }
+        let bytes: Vec<u8> = (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+            .collect();
+
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(invalid("record length doesn't match its byte count"));
+        }
+        if checksum(bytes[..bytes.len() - 1].iter()) != bytes[bytes.len() - 1] {
+            return Err(invalid("bad checksum"));
+        }
+
+        let address = u16::from_be_bytes([bytes[1], bytes[2]]) as usize;
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            DATA => blocks.push((base + address, data.to_vec())),
+            END_OF_FILE => return Ok(blocks),
+            EXTENDED_SEGMENT_ADDRESS if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as usize) << 4;
+            }
+            EXTENDED_LINEAR_ADDRESS if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as usize) << 16;
+            }
+            START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS if data.len() == 4 => {}
+            EXTENDED_SEGMENT_ADDRESS..=START_LINEAR_ADDRESS => return Err(invalid("wrong amount of data for record type")),
+            other => return Err(invalid(&format!("unknown record type {:02X}", other))),
+        }
+    }
+
+    Err(VMError::InvalidHex("missing end-of-file record".to_string()))
+}
+
+/// Appends one record, with its checksum, to `output`.
+fn write_record(output: &mut String, record_type: u8, address: u16, data: &[u8])
+{
+    let [high, low] = address.to_be_bytes();
+    let header = [data.len() as u8, high, low, record_type];
+
+    output.push(':');
+    for byte in header.iter().chain(data) {
+        write!(output, "{:02X}", byte).unwrap();
+    }
+    writeln!(output, "{:02X}", checksum(header.iter().chain(data))).unwrap();
+}
+
+/// Two's complement of the sum of a record's bytes.
+fn checksum<'a>(bytes: impl Iterator<Item = &'a u8>) -> u8
+{
+    bytes.fold(0u8, |sum, &byte| sum.wrapping_add(byte)).wrapping_neg()
+}
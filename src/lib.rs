@@ -1,8 +1,30 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
 pub mod assembler;
+#[cfg(feature = "std")]
+pub mod bytecode;
+pub mod hex;
+#[cfg(feature = "std")]
+pub mod pipeline;
+pub mod span;
 pub mod vm;
 
 // Re-export commonly used items
+#[cfg(feature = "std")]
 pub use assembler::Assembler;
-pub use vm::cpu::CPU;
-pub use vm::error::VMError;
-pub use vm::VMConfig;
+#[cfg(feature = "std")]
+pub use bytecode::{read_bytecode, write_bytecode};
+pub use hex::{to_intel_hex, to_intel_hex_at};
+#[cfg(feature = "std")]
+pub use pipeline::{assemble_and_run, RustBucketError};
+pub use span::Span;
+pub use vm::cpu::{CpuSnapshot, RunOutcome, TraceEvent, TraceHook, WatchEvent, WatchHook, CPU, CPU16};
+pub use vm::error::{VMConfigError, VMError};
+pub use vm::output::{Discard, Output};
+pub use vm::{
+    disassemble, disassemble_with_spans, instructions, run_to_string, verify, verify_for, CpuState, Debugger,
+    InstructionHandler, Opcode, UnknownPolicy, VMConfig, Word,
+};
@@ -2,12 +2,7 @@
 //! This program demonstrates how to create and run a basic virtual machine
 //! that can execute assembly-like instructions.
 
-mod assembler;
-mod vm;
-
-use assembler::Assembler;
-use vm::cpu::CPU;
-use vm::VMConfig;
+use vm::{Assembler, RunOutcome, VMConfig, CPU};
 
 fn main()
 {
@@ -15,7 +10,13 @@ fn main()
     let config = VMConfig::new(256, true);
 
     // Create a new Virtual Machine instance with our configuration
-    let mut vm = CPU::new(config);
+    let mut vm = match CPU::new(config) {
+        Ok(vm) => vm,
+        Err(e) => {
+            eprintln!("Failed to create VM: {}", e);
+            return;
+        }
+    };
 
     // This is our test program written in our custom assembly language
     // Each line is an instruction that our VM will execute
@@ -40,11 +41,15 @@ fn main()
         Ok(bytecode) => {
             // Successfully created bytecode - show it and run it
             println!("Generated bytecode: {:02X?}", bytecode);
-            vm.load_program(&bytecode);
+            if let Err(e) = vm.load_program(&bytecode) {
+                eprintln!("Failed to load program: {}", e);
+                return;
+            }
 
             // Execute the program
             match vm.run() {
-                Ok(_) => println!("\nProgram completed successfully"),
+                Ok(RunOutcome::Halted) => println!("\nProgram completed successfully"),
+                Ok(outcome) => println!("\nProgram stopped without halting: {:?}", outcome),
                 Err(e) => {
                     eprintln!("\nProgram failed during execution: {}", e);
                     vm.dump_state(); // Print VM state for debugging
@@ -0,0 +1,92 @@
+//! Assembling and running a program in one step
+//!
+//! [`assemble_and_run`] goes from source code to a finished run, and
+//! [`RustBucketError`] covers what can go wrong along the way.
+
+use crate::assembler::{Assembler, AssemblerError};
+use crate::{RunOutcome, VMConfig, VMError, CPU};
+
+/// Any error from assembling or running a program.
+///
+/// Both [`AssemblerError`] and [`VMError`] convert into it, so a single `?`
+/// works across an assemble-then-run flow.
+#[derive(Debug)]
+pub enum RustBucketError
+{
+    /// The source code couldn't be assembled
+    Assembler(AssemblerError),
+    /// The program couldn't be loaded or failed while running
+    VM(VMError),
+}
+
+impl From<AssemblerError> for RustBucketError
+{
+    fn from(error: AssemblerError) -> Self
+    {
+        RustBucketError::Assembler(error)
+    }
+}
+
+impl From<VMError> for RustBucketError
+{
+    fn from(error: VMError) -> Self
+    {
+        RustBucketError::VM(error)
+    }
+}
+
+impl core::fmt::Display for RustBucketError
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result
+    {
+        match self {
+            RustBucketError::Assembler(error) => write!(f, "Assembly failed: {}", error),
+            RustBucketError::VM(error) => write!(f, "Execution failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for RustBucketError
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
+    {
+        match self {
+            RustBucketError::Assembler(error) => Some(error),
+            RustBucketError::VM(error) => Some(error),
+        }
+    }
+}
+
+/// Assembles `code` and runs it on a fresh 8-bit CPU until it stops.
+///
+/// The program may use as many registers as `config.num_registers` allows.
+/// `OUT` instructions write to stdout, as with [`CPU::new`].
+///
+/// # Arguments
+/// * `code` - The assembly source code
+/// * `config` - The machine to run it on
+///
+/// # Returns
+/// * `Ok(())` - If the program assembled and ran without an error
+/// * `Err(RustBucketError::Assembler)` - The first error found while assembling
+/// * `Err(RustBucketError::VM)` - If the program didn't fit, failed, or used up `config.max_cycles`
+///
+/// # Example
+/// ```
+/// # use vm::{assemble_and_run, RustBucketError, VMConfig};
+/// assemble_and_run("MOV r0, 1\nHALT", VMConfig::default()).unwrap();
+/// let error = assemble_and_run("FOO r0", VMConfig::default()).unwrap_err();
+/// assert!(matches!(error, RustBucketError::Assembler(_)));
+/// ```
+pub fn assemble_and_run(code: &str, config: VMConfig) -> Result<(), RustBucketError>
+{
+    let bytecode = Assembler::with_registers(config.num_registers).assemble(code)?;
+
+    let max_cycles = config.max_cycles;
+    let mut cpu = CPU::new(config)?;
+    cpu.load_program(&bytecode)?;
+    if cpu.run()? == RunOutcome::CycleLimit {
+        return Err(VMError::CycleLimitExceeded(max_cycles.unwrap_or(0)).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,39 @@
+//! Source positions shared by the assembler and the disassembler
+//!
+//! Tools such as editors can use a [`Span`] to underline the text an error
+//! refers to, or to map a disassembled instruction back to its bytes.
+
+/// A range of bytes and the line they start on.
+///
+/// For assembler instructions, diagnostics and errors, `start..end` is a byte range
+/// of the source text. For disassembled instructions it is the range of bytecode the
+/// instruction was decoded from, and `line` is its line in the disassembly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span
+{
+    /// Offset of the first byte
+    pub start: usize,
+    /// Offset just past the last byte
+    pub end: usize,
+    /// The 1-based line number
+    pub line: usize,
+}
+
+impl Span
+{
+    /// Returns the span of `part`, a slice of `text`, where `text` itself covers `self`.
+    ///
+    /// # Arguments
+    /// * `text` - The text this span covers
+    /// * `part` - A slice borrowed from `text`
+    #[cfg(feature = "std")]
+    pub(crate) fn subspan(self, text: &str, part: &str) -> Span
+    {
+        let start = self.start + (part.as_ptr() as usize - text.as_ptr() as usize);
+        Span {
+            start,
+            end: start + part.len(),
+            line: self.line,
+        }
+    }
+}
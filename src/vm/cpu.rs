@@ -1,37 +1,341 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use core::cell::RefCell;
+use core::ops::Range;
+
 use super::error::VMError;
-use super::{Opcode, VMConfig};
+use super::handler::{CpuState, InstructionHandler};
+use super::output::Output;
+use super::trace::json_trace_hook;
+use super::word::Word;
+use super::{Opcode, UnknownPolicy, VMConfig, DEFAULT_COST};
+use crate::hex::parse_intel_hex;
+
+/// A copy of the CPU's machine state, used to save and restore execution.
+///
+/// The configuration and output sink are not part of a snapshot, so a snapshot
+/// can only be restored into a CPU built with the same configuration.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuSnapshot<W = u8>
+{
+    /// Every general-purpose register, r0 first
+    pub registers: Vec<W>,
+    /// Address of the next instruction
+    pub pc: usize,
+    /// The whole of memory
+    pub memory: Vec<u8>,
+    /// Stack pointer
+    pub sp: usize,
+    /// Base pointer of the innermost `ENTER` frame
+    pub bp: usize,
+    /// Flags register (see [`CPU`] for the bit layout)
+    pub flags: u8,
+    /// Return addresses of the calls that haven't returned yet, outermost first
+    pub call_stack: Vec<usize>,
+    /// Exit code of the `HALT` that stopped the program, if one has run
+    pub exit_code: Option<u8>,
+}
+
+/// Information about one executed instruction, passed to the trace hook.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEvent<W = u8>
+{
+    /// Address the instruction was fetched from
+    pub pc: usize,
+    /// The decoded instruction
+    pub opcode: Opcode,
+    /// Register values after the instruction executed
+    pub registers: Vec<W>,
+    /// Flags after the instruction executed
+    pub flags: u8,
+}
+
+/// Why [`CPU::run`] stopped executing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome
+{
+    /// A `HALT` instruction was executed
+    Halted,
+    /// The program counter ran past the end of memory without a `HALT`
+    MemoryExhausted,
+    /// Execution stopped before the instruction at this address
+    Breakpoint(usize),
+    /// The configured `max_cycles` were used up
+    CycleLimit,
+}
+
+/// Callback invoked by the CPU after each executed instruction.
+pub type TraceHook<W = u8> = Box<dyn FnMut(&TraceEvent<W>)>;
+
+/// A write to a watched memory address, passed to the watch hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchEvent
+{
+    /// The address that was written
+    pub addr: usize,
+    /// The byte before the write
+    pub old: u8,
+    /// The byte after the write
+    pub new: u8,
+}
+
+/// Callback invoked by the CPU when a watched address is written.
+pub type WatchHook = Box<dyn FnMut(&WatchEvent)>;
 
 /// A struct representing a simple CPU for the virtual machine.
-pub struct CPU
+///
+/// The flags register uses the following bits:
+/// - Bit 0: Zero flag, set when a comparison is equal or a logical result is zero
+/// - Bit 1: Greater flag, set when the first compared value is greater
+/// - Bit 2: Carry flag, set when an addition overflows or a subtraction borrows
+/// - Bit 3: Sign flag, set when the result of arithmetic or a MOV has its high bit set
+///
+/// Registers hold a [`Word`], `u8` by default. Use [`CPU16`] for 16-bit registers;
+/// wider words are pushed, loaded and stored as little-endian bytes.
+pub struct CPU<W: Word = u8>
 {
-    registers: Vec<u8>,
+    registers: Vec<W>,
     pc: usize,
     memory: Vec<u8>,
     sp: usize,
+    /// Base pointer of the current `ENTER` frame
+    bp: usize,
     flags: u8,
     config: VMConfig,
     call_stack: Vec<usize>,
+    output: Box<dyn Output>,
+    trace_hook: Option<TraceHook<W>>,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeSet<usize>,
+    watch_hook: Option<WatchHook>,
+    /// Handlers consulted for unrecognized opcodes, in registration order
+    handlers: Vec<Box<dyn InstructionHandler<W>>>,
+    /// Exit code of the `HALT` instruction that stopped the program, if one has run
+    exit_code: Option<u8>,
+    /// Breakpoint `run` last stopped at, so the next `run` can resume past it
+    stopped_at: Option<usize>,
+    /// Number of instructions executed since the CPU was created or reset
+    cycles: usize,
+    /// Sum of the configured costs of those instructions
+    cost: u64,
 }
 
+/// A CPU with 16-bit registers.
+pub type CPU16 = CPU<u16>;
+
 impl CPU
 {
-    /// Creates a new CPU with a specified amount of memory.
+    /// Creates a new 8-bit CPU with a specified amount of memory.
     ///
     /// # Arguments
     /// * `config` - The configuration for the CPU.
-    pub fn new(config: VMConfig) -> Self
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidConfig)` - If the configuration fails [`VMConfig::validate`],
+    ///   e.g. the stack doesn't fit in memory or there are no registers.
+    pub fn new(config: VMConfig) -> Result<Self, VMError>
     {
-        // Calculate stack pointer start based on stack size
-        let sp_start = config.memory_size - config.stack_size;
+        Self::from_config(config)
+    }
+}
 
-        Self {
-            registers: vec![0; config.num_registers],
+impl<W: Word> CPU<W>
+{
+    /// Creates a new CPU for any register word type, e.g. `CPU16::from_config(config)`.
+    ///
+    /// # Arguments
+    /// * `config` - The configuration for the CPU.
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidConfig)` - If the configuration fails [`VMConfig::validate`]
+    pub fn from_config(config: VMConfig) -> Result<Self, VMError>
+    {
+        config.validate()?;
+
+        let sp_start = config.stack_start();
+
+        let mut cpu = Self {
+            registers: vec![W::default(); config.num_registers],
             pc: config.pc_start,
             memory: vec![0; config.memory_size],
             sp: sp_start, // Use calculated stack pointer
+            bp: sp_start,
             flags: 0,
             config,
             call_stack: Vec::new(),
+            output: default_output(),
+            trace_hook: None,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+            watch_hook: None,
+            handlers: Vec::new(),
+            exit_code: None,
+            stopped_at: None,
+            cycles: 0,
+            cost: 0,
+        };
+        cpu.seed_registers();
+        Ok(cpu)
+    }
+
+    /// Replaces the sink that `OUT` instructions write to.
+    ///
+    /// By default output goes to stdout, or is discarded without the `std` feature.
+    /// Each `OUT` writes the register value in decimal followed by a newline.
+    ///
+    /// # Arguments
+    /// * `output` - Any [`Output`], e.g. a file or an in-memory buffer.
+    ///   With the `std` feature every `std::io::Write` type is an `Output`.
+    pub fn set_output(&mut self, output: Box<dyn Output>)
+    {
+        self.output = output;
+    }
+
+    /// Installs a callback that is invoked after every executed instruction.
+    ///
+    /// Useful for tracing and profiling without modifying the VM. Replaces
+    /// any previously installed hook.
+    ///
+    /// # Arguments
+    /// * `hook` - Called with a [`TraceEvent`] describing each instruction
+    pub fn set_trace_hook(&mut self, hook: TraceHook<W>)
+    {
+        self.trace_hook = Some(hook);
+    }
+
+    /// Installs a trace hook that describes each instruction as a JSON object.
+    ///
+    /// Each record has the instruction's address, mnemonic and operands, the
+    /// registers it changed and the new flags (see [`super::trace`] for the format).
+    /// Changes are measured from the state when this is called. Replaces any
+    /// previously installed hook.
+    ///
+    /// # Arguments
+    /// * `sink` - Called with one record per instruction, e.g. to collect them or write them to a file
+    pub fn set_json_trace(&mut self, sink: impl FnMut(String) + 'static)
+    {
+        self.trace_hook = Some(json_trace_hook(self.registers.clone(), self.flags, sink));
+    }
+
+    /// Removes the trace hook, if one is installed.
+    pub fn clear_trace_hook(&mut self)
+    {
+        self.trace_hook = None;
+    }
+
+    /// Registers a handler for opcodes the VM doesn't recognize.
+    ///
+    /// Handlers are tried in the order they were registered; the first one that
+    /// accepts an opcode executes it. If none do, `VMConfig::on_unknown_opcode`
+    /// decides whether the opcode is an error, skipped or halts the program.
+    ///
+    /// # Arguments
+    /// * `handler` - The handler to add
+    pub fn register_handler(&mut self, handler: Box<dyn InstructionHandler<W>>)
+    {
+        self.handlers.push(handler);
+    }
+
+    /// Captures the current machine state.
+    pub fn snapshot(&self) -> CpuSnapshot<W>
+    {
+        CpuSnapshot {
+            registers: self.registers.clone(),
+            pc: self.pc,
+            memory: self.memory.clone(),
+            sp: self.sp,
+            bp: self.bp,
+            flags: self.flags,
+            call_stack: self.call_stack.clone(),
+            exit_code: self.exit_code,
+        }
+    }
+
+    /// Restores machine state previously captured with [`CPU::snapshot`].
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidSnapshot)` - If the snapshot doesn't fit this CPU's configuration:
+    ///   a different number of registers or memory size, or a PC, SP or BP outside memory
+    ///   or the stack region. The CPU is left unchanged.
+    pub fn restore(&mut self, snapshot: CpuSnapshot<W>) -> Result<(), VMError>
+    {
+        let invalid = |reason: String| Err(VMError::InvalidSnapshot(reason));
+        if snapshot.registers.len() != self.config.num_registers {
+            return invalid(format!(
+                "{} registers, expected {}",
+                snapshot.registers.len(),
+                self.config.num_registers
+            ));
+        }
+        if snapshot.memory.len() != self.config.memory_size {
+            return invalid(format!(
+                "{} bytes of memory, expected {}",
+                snapshot.memory.len(),
+                self.config.memory_size
+            ));
+        }
+        // A PC at the end of memory is a program that has stopped
+        if snapshot.pc > self.config.memory_size {
+            return invalid(format!("program counter {} is outside memory", snapshot.pc));
+        }
+        let stack = self.stack_limit()..=self.config.memory_size;
+        for (name, value) in [("stack", snapshot.sp), ("base", snapshot.bp)] {
+            if !stack.contains(&value) {
+                return invalid(format!("{} pointer {} is outside the stack", name, value));
+            }
+        }
+
+        self.registers = snapshot.registers;
+        self.pc = snapshot.pc;
+        self.memory = snapshot.memory;
+        self.sp = snapshot.sp;
+        self.bp = snapshot.bp;
+        self.flags = snapshot.flags;
+        self.call_stack = snapshot.call_stack;
+        self.exit_code = snapshot.exit_code;
+        self.stopped_at = None;
+        Ok(())
+    }
+
+    /// Resets the CPU to its initial state so it can run another program.
+    ///
+    /// Flags, the call stack and all of memory are cleared, and the registers,
+    /// program counter and stack pointer return to their configured starting
+    /// values. Existing allocations are reused.
+    pub fn reset(&mut self)
+    {
+        self.reset_registers();
+        self.memory.fill(0);
+    }
+
+    /// Resets registers, flags, PC, SP, BP and the call stack, the cycle count and cost, but leaves memory intact.
+    ///
+    /// Useful for re-running the program that's already loaded.
+    pub fn reset_registers(&mut self)
+    {
+        self.registers.fill(W::default());
+        self.seed_registers();
+        self.pc = self.config.pc_start;
+        self.sp = self.config.stack_start();
+        self.bp = self.sp;
+        self.flags = 0;
+        self.call_stack.clear();
+        self.stopped_at = None;
+        self.exit_code = None;
+        self.cycles = 0;
+        self.cost = 0;
+    }
+
+    /// Copies `config.initial_registers` into the first registers.
+    fn seed_registers(&mut self)
+    {
+        for (register, &value) in self.registers.iter_mut().zip(&self.config.initial_registers) {
+            *register = W::from_u8(value);
         }
     }
 
@@ -39,95 +343,78 @@ impl CPU
     ///
     /// # Arguments
     /// * `program` - A slice of bytes representing the program's instructions.
-    pub fn load_program(&mut self, program: &[u8])
+    ///
+    /// # Returns
+    /// * `Err(VMError::ProgramTooLarge)` - If the program is larger than memory
+    pub fn load_program(&mut self, program: &[u8]) -> Result<(), VMError>
     {
+        if program.len() > self.memory.len() {
+            return Err(VMError::ProgramTooLarge {
+                size: program.len(),
+                capacity: self.memory.len(),
+            });
+        }
         // Copy the program's bytes into the VM's memory starting at address 0.
-        self.memory[..program.len()].copy_from_slice(program);
+        self.load_program_at(program, 0)
     }
 
-    /// Fetches the next instruction from memory and increments the program counter.
+    /// Loads a program into the VM's memory starting at `offset`.
+    ///
+    /// Set `pc_start` to the same offset to run it from there.
+    ///
+    /// # Arguments
+    /// * `program` - A slice of bytes representing the program's instructions.
+    /// * `offset` - Address of the program's first byte
     ///
     /// # Returns
-    /// * `Opcode` - The next opcode (instruction) to be executed.
-    fn fetch(&mut self) -> Opcode
+    /// * `Err(VMError::InvalidMemoryAccess)` - If the program doesn't fit between `offset` and the end of memory
+    pub fn load_program_at(&mut self, program: &[u8], offset: usize) -> Result<(), VMError>
     {
-        if self.pc >= self.memory.len() {
-            return Opcode::Halt;
-        }
-
-        let opcode_byte = self.memory[self.pc];
-        self.pc += 1;
-
-        match opcode_byte {
-            // Single register instructions
-            0x01..=0x03 => {
-                let reg = self.memory[self.pc];
-                self.pc += 1;
-                match opcode_byte {
-                    0x01 => Opcode::Inc(reg),
-                    0x02 => Opcode::Dec(reg),
-                    0x03 => Opcode::Out(reg),
-                    _ => unreachable!(),
-                }
-            }
-
-            // Two register instructions
-            0x04 | 0x30..=0x33 | 0x43 => {
-                let dst = self.memory[self.pc];
-                self.pc += 1;
-                let src = self.memory[self.pc];
-                self.pc += 1;
-                match opcode_byte {
-                    0x04 => Opcode::Mov(dst, src),
-                    0x30 => Opcode::Add(dst, src),
-                    0x31 => Opcode::Sub(dst, src),
-                    0x32 => Opcode::Mul(dst, src),
-                    0x33 => Opcode::Div(dst, src),
-                    0x43 => Opcode::Cmp(dst, src),
-                    _ => unreachable!(),
-                }
-            }
-
-            // Register and memory address instructions
-            0x20..=0x23 => {
-                let reg = self.memory[self.pc];
-                self.pc += 1;
-                match opcode_byte {
-                    0x20 => Opcode::Load(reg),
-                    0x21 => Opcode::Store(reg),
-                    0x22 => Opcode::LdIdx(reg),
-                    0x23 => Opcode::StIdx(reg),
-                    _ => unreachable!(),
-                }
-            }
+        let end = offset
+            .checked_add(program.len())
+            .filter(|&end| end <= self.memory.len())
+            .ok_or(VMError::InvalidMemoryAccess(offset.max(self.memory.len())))?;
+        self.memory[offset..end].copy_from_slice(program);
+        Ok(())
+    }
 
-            // Jump instructions
-            0x40..=0x44 => match opcode_byte {
-                0x40 => Opcode::Jmp,
-                0x41 => Opcode::Jeq,
-                0x42 => Opcode::Jgt,
-                0x44 => Opcode::Jne,
-                _ => unreachable!(),
-            },
-
-            // Stack operations
-            0x10..=0x11 => {
-                let reg = self.memory[self.pc];
-                self.pc += 1;
-                match opcode_byte {
-                    0x10 => Opcode::Push(reg),
-                    0x11 => Opcode::Pop(reg),
-                    _ => unreachable!(),
-                }
-            }
+    /// Loads a program from Intel HEX text into memory at the addresses its records give.
+    ///
+    /// The whole file is checked before anything is written, so memory is left
+    /// untouched if it's rejected.
+    ///
+    /// # Arguments
+    /// * `hex` - Intel HEX records, e.g. from [`to_intel_hex`](crate::hex::to_intel_hex)
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidHex)` - If a record is malformed or has a bad checksum
+    /// * `Err(VMError::InvalidMemoryAccess)` - If a record writes past the end of memory
+    pub fn load_hex(&mut self, hex: &str) -> Result<(), VMError>
+    {
+        let blocks = parse_intel_hex(hex)?;
+        if let Some((address, _)) = blocks.iter().find(|(address, data)| address + data.len() > self.memory.len()) {
+            return Err(VMError::InvalidMemoryAccess(*address.max(&self.memory.len())));
+        }
 
-            // Function calls
-            0x12 => Opcode::Call,
-            0x13 => Opcode::Ret,
+        for (address, data) in blocks {
+            self.memory[address..address + data.len()].copy_from_slice(&data);
+        }
+        Ok(())
+    }
 
-            0xFF => Opcode::Halt,
-            _ => Opcode::Unknown(opcode_byte),
+    /// Fetches the next instruction from memory and increments the program counter.
+    ///
+    /// # Returns
+    /// * `Opcode` - The next opcode (instruction) to be executed.
+    fn fetch(&mut self) -> Result<Opcode, VMError>
+    {
+        if self.pc >= self.memory.len() {
+            return Ok(Opcode::Halt);
         }
+
+        let (opcode, size) = Opcode::decode(&self.memory, self.pc)?;
+        self.pc += size;
+        Ok(opcode)
     }
 
     /// Executes a single instruction based on the given opcode.
@@ -138,76 +425,186 @@ impl CPU
     {
         match opcode {
             Opcode::Inc(reg) => {
-                let reg = reg as usize;
-                if reg >= self.config.num_registers {
-                    return Err(VMError::InvalidRegister(reg));
-                }
-                self.registers[reg] = self.registers[reg].wrapping_add(1);
+                let reg = self.reg_checked(reg)?;
+                self.registers[reg] = self.registers[reg].wrapping_add(W::from_u8(1));
+                self.set_sign_flag(self.registers[reg].is_negative());
             }
             Opcode::Dec(reg) => {
-                let reg = reg as usize;
-                if reg >= self.config.num_registers {
-                    return Err(VMError::InvalidRegister(reg));
-                }
-                self.registers[reg] = self.registers[reg].wrapping_sub(1);
+                let reg = self.reg_checked(reg)?;
+                self.registers[reg] = self.registers[reg].wrapping_sub(W::from_u8(1));
+                self.set_sign_flag(self.registers[reg].is_negative());
+            }
+            Opcode::Not(reg) => {
+                let reg = self.reg_checked(reg)?;
+                self.registers[reg] = !self.registers[reg];
+                self.set_zero_flag(self.registers[reg] == W::default());
+            }
+            Opcode::Neg(reg) => {
+                let reg = self.reg_checked(reg)?;
+                let result = W::default().wrapping_sub(self.registers[reg]);
+                self.registers[reg] = result;
+                self.set_zero_flag(result == W::default());
+                self.set_sign_flag(result.is_negative());
             }
             Opcode::Out(reg) => {
-                print!("{} ", self.registers[reg as usize]);
-                if self.config.debug {
-                    println!();
-                }
+                let reg = self.reg_checked(reg)?;
+                self.output
+                    .write_bytes(format!("{}\n", self.registers[reg]).as_bytes())
+                    .map_err(VMError::OutputError)?;
+            }
+            Opcode::OutStr(reg) => {
+                let reg = self.reg_checked(reg)?;
+                let start = self.registers[reg].to_usize();
+                // Every byte up to the terminator must lie inside memory
+                let string = self.memory.get(start..).unwrap_or(&[]);
+                let len = string
+                    .iter()
+                    .position(|&byte| byte == 0)
+                    .ok_or(VMError::InvalidMemoryAccess(self.memory.len().max(start)))?;
+                self.output.write_bytes(&string[..len]).map_err(VMError::OutputError)?;
             }
             Opcode::Mov(dst, src) => {
-                self.registers[dst as usize] = src;
+                let dst = self.reg_checked(dst)?;
+                self.registers[dst] = W::from_u8(src);
+                self.set_sign_flag(self.registers[dst].is_negative());
+            }
+            Opcode::MovW(dst, imm) => {
+                let dst = self.reg_checked(dst)?;
+                self.registers[dst] = W::from_u16(imm);
+                self.set_sign_flag(self.registers[dst].is_negative());
+            }
+            Opcode::MovR(dst, src) => {
+                let (dst, src) = (self.reg_checked(dst)?, self.reg_checked(src)?);
+                self.registers[dst] = self.registers[src];
+                self.set_sign_flag(self.registers[dst].is_negative());
+            }
+            Opcode::Xchg(a, b) => {
+                let (a, b) = (self.reg_checked(a)?, self.reg_checked(b)?);
+                self.registers.swap(a, b);
             }
 
             Opcode::Add(dst, src) => {
-                self.registers[dst as usize] = self.registers[dst as usize].wrapping_add(self.registers[src as usize]);
+                let (dst, src) = (self.reg_checked(dst)?, self.reg_checked(src)?);
+                let (result, carry) = self.registers[dst].overflowing_add(self.registers[src]);
+                self.registers[dst] = result;
+                self.set_carry_flag(carry);
+                self.set_sign_flag(self.registers[dst].is_negative());
+            }
+            Opcode::Adc(dst, src) => {
+                let (dst, src) = (self.reg_checked(dst)?, self.reg_checked(src)?);
+                let carry_in = W::from_u8((self.flags & 4) >> 2);
+                let (partial, carry1) = self.registers[dst].overflowing_add(self.registers[src]);
+                let (result, carry2) = partial.overflowing_add(carry_in);
+                self.registers[dst] = result;
+                self.set_carry_flag(carry1 | carry2);
+                self.set_sign_flag(self.registers[dst].is_negative());
             }
             Opcode::Sub(dst, src) => {
-                self.registers[dst as usize] = self.registers[dst as usize].wrapping_sub(self.registers[src as usize]);
+                let (dst, src) = (self.reg_checked(dst)?, self.reg_checked(src)?);
+                let (result, borrow) = self.registers[dst].overflowing_sub(self.registers[src]);
+                self.registers[dst] = result;
+                self.set_carry_flag(borrow);
+                self.set_sign_flag(self.registers[dst].is_negative());
+            }
+            Opcode::AddI(dst, imm) => {
+                let dst = self.reg_checked(dst)?;
+                let (result, carry) = self.registers[dst].overflowing_add(W::from_u8(imm));
+                self.registers[dst] = result;
+                self.set_carry_flag(carry);
+                self.set_sign_flag(self.registers[dst].is_negative());
+            }
+            Opcode::SubI(dst, imm) => {
+                let dst = self.reg_checked(dst)?;
+                let (result, borrow) = self.registers[dst].overflowing_sub(W::from_u8(imm));
+                self.registers[dst] = result;
+                self.set_carry_flag(borrow);
+                self.set_sign_flag(self.registers[dst].is_negative());
             }
             Opcode::Mul(dst, src) => {
-                self.registers[dst as usize] = self.registers[dst as usize].wrapping_mul(self.registers[src as usize]);
+                let (dst, src) = (self.reg_checked(dst)?, self.reg_checked(src)?);
+                self.registers[dst] = self.registers[dst].wrapping_mul(self.registers[src]);
+                self.set_sign_flag(self.registers[dst].is_negative());
             }
             Opcode::Div(dst, src) => {
-                if self.registers[src as usize] == 0 {
+                let (dst, src) = (self.reg_checked(dst)?, self.reg_checked(src)?);
+                if self.registers[src] == W::default() {
                     return Err(VMError::DivisionByZero);
                 }
-                self.registers[dst as usize] = self.registers[dst as usize].wrapping_div(self.registers[src as usize]);
+                self.registers[dst] = self.registers[dst].wrapping_div(self.registers[src]);
+                self.set_sign_flag(self.registers[dst].is_negative());
+            }
+            Opcode::Mod(dst, src) => {
+                let (dst, src) = (self.reg_checked(dst)?, self.reg_checked(src)?);
+                if self.registers[src] == W::default() {
+                    return Err(VMError::DivisionByZero);
+                }
+                self.registers[dst] = self.registers[dst].wrapping_rem(self.registers[src]);
+                self.set_sign_flag(self.registers[dst].is_negative());
+            }
+
+            Opcode::And(dst, src) => {
+                let (dst, src) = (self.reg_checked(dst)?, self.reg_checked(src)?);
+                let result = self.registers[dst] & self.registers[src];
+                self.registers[dst] = result;
+                self.set_zero_flag(result == W::default());
+            }
+            Opcode::Or(dst, src) => {
+                let (dst, src) = (self.reg_checked(dst)?, self.reg_checked(src)?);
+                let result = self.registers[dst] | self.registers[src];
+                self.registers[dst] = result;
+                self.set_zero_flag(result == W::default());
+            }
+            Opcode::Xor(dst, src) => {
+                let (dst, src) = (self.reg_checked(dst)?, self.reg_checked(src)?);
+                let result = self.registers[dst] ^ self.registers[src];
+                self.registers[dst] = result;
+                self.set_zero_flag(result == W::default());
+            }
+            Opcode::Shl(dst, src) => {
+                let (dst, src) = (self.reg_checked(dst)?, self.reg_checked(src)?);
+                let amount = self.registers[src].to_usize() as u32;
+                self.registers[dst] = self.registers[dst].wrapping_shl(amount);
+            }
+            Opcode::Shr(dst, src) => {
+                let (dst, src) = (self.reg_checked(dst)?, self.reg_checked(src)?);
+                let amount = self.registers[src].to_usize() as u32;
+                self.registers[dst] = self.registers[dst].wrapping_shr(amount);
             }
 
             Opcode::Cmp(reg1, reg2) => {
-                let val1 = self.registers[reg1 as usize];
-                let val2 = self.registers[reg2 as usize];
+                let val1 = self.registers[self.reg_checked(reg1)?];
+                let val2 = self.registers[self.reg_checked(reg2)?];
                 self.set_zero_flag(val1 == val2);
                 self.set_greater_flag(val1 > val2);
             }
+            Opcode::CmpI(reg, imm) => {
+                let val = self.registers[self.reg_checked(reg)?];
+                let imm = W::from_u8(imm);
+                self.set_zero_flag(val == imm);
+                self.set_greater_flag(val > imm);
+            }
 
-            Opcode::Jmp => {
-                let addr = self.memory[self.pc] as usize;
-                self.pc = addr;
+            Opcode::Jmp(addr) => {
+                self.jump_to(addr as usize)?;
             }
-            Opcode::Jeq => {
-                let addr = self.memory[self.pc] as usize;
-                self.pc += 1;
+            Opcode::Jeq(addr) => {
                 if self.flags & 1 != 0 {
-                    self.pc = addr;
+                    self.jump_to(addr as usize)?;
                 }
             }
-            Opcode::Jgt => {
-                let addr = self.memory[self.pc] as usize;
-                self.pc += 1;
+            Opcode::Jgt(addr) => {
                 if self.flags & 2 != 0 {
-                    self.pc = addr;
+                    self.jump_to(addr as usize)?;
                 }
             }
 
-            Opcode::Call => {
-                let addr = self.memory[self.pc] as usize;
-                self.pc += 1;
-                self.call_stack.push(self.pc);
-                self.pc = addr;
+            Opcode::Call(addr) => {
+                if self.call_stack.len() >= self.config.max_call_depth {
+                    return Err(VMError::CallStackOverflow);
+                }
+                let return_addr = self.pc;
+                self.jump_to(addr as usize)?;
+                self.call_stack.push(return_addr);
             }
             Opcode::Ret => {
                 if let Some(return_addr) = self.call_stack.pop() {
@@ -216,78 +613,202 @@ impl CPU
             }
 
             Opcode::Push(reg) => {
-                if self.sp == 0 {
+                let reg = self.reg_checked(reg)?;
+                self.push_word(self.registers[reg])?;
+            }
+
+            Opcode::Pop(reg) => {
+                let reg = self.reg_checked(reg)?;
+                self.registers[reg] = self.pop_word()?;
+            }
+
+            Opcode::PushA => {
+                // Check the whole push up front so a failure leaves the stack untouched
+                if self.stack_room() < self.registers.len() * W::BYTES {
                     return Err(VMError::StackOverflow);
                 }
-                self.sp -= 1;
-                self.memory[self.sp] = self.registers[reg as usize];
+                for reg in 0..self.registers.len() {
+                    self.push_word(self.registers[reg])?;
+                }
             }
 
-            Opcode::Pop(reg) => {
-                if self.sp >= self.memory.len() {
+            Opcode::PopA => {
+                if self.stack_used(self.sp) < self.registers.len() * W::BYTES {
                     return Err(VMError::StackUnderflow);
                 }
-                self.registers[reg as usize] = self.memory[self.sp];
-                self.sp += 1;
+                for reg in (0..self.registers.len()).rev() {
+                    self.registers[reg] = self.pop_word()?;
+                }
             }
 
-            Opcode::Load(reg) => {
-                let addr = self.memory[self.pc] as usize;
-                self.pc += 1;
-                if addr >= self.memory.len() {
-                    return Err(VMError::InvalidMemoryAccess(addr));
+            Opcode::Enter(size) => {
+                // Save the caller's frame as a two-byte address, then reserve the locals
+                if self.stack_room() < 2 + size as usize {
+                    return Err(VMError::StackOverflow);
                 }
-                self.registers[reg as usize] = self.memory[addr];
+                let saved_bp = u16::try_from(self.bp).map_err(|_| VMError::InvalidMemoryAccess(self.bp))?;
+                let addr = self.reserve_stack(2);
+                self.write_u16(addr, saved_bp)?;
+                self.bp = self.sp;
+                self.reserve_stack(size as usize);
             }
 
-            Opcode::Store(reg) => {
-                let addr = self.memory[self.pc] as usize;
-                self.pc += 1;
-                if addr >= self.memory.len() {
-                    return Err(VMError::InvalidMemoryAccess(addr));
+            Opcode::Leave => {
+                if self.stack_used(self.bp) < 2 {
+                    return Err(VMError::StackUnderflow);
                 }
-                self.memory[addr] = self.registers[reg as usize];
+                self.sp = self.bp;
+                let addr = self.release_stack(2);
+                self.bp = self.read_u16(addr)? as usize;
             }
 
-            Opcode::LdIdx(reg) => {
-                let base_addr = self.memory[self.pc] as usize;
-                self.pc += 1;
-                let index = self.registers[1] as usize;
-                let addr = base_addr + index;
-                if addr >= self.memory.len() {
-                    return Err(VMError::InvalidMemoryAccess(addr));
-                }
-                self.registers[reg as usize] = self.memory[addr];
+            Opcode::LdSp(reg, offset) => {
+                let reg = self.reg_checked(reg)?;
+                self.registers[reg] = self.read_word(self.stack_offset_address(offset)?)?;
             }
 
-            Opcode::StIdx(reg) => {
-                let base_addr = self.memory[self.pc] as usize;
-                self.pc += 1;
-                let index = self.registers[1] as usize;
-                let addr = base_addr + index;
-                if addr >= self.memory.len() {
-                    return Err(VMError::InvalidMemoryAccess(addr));
-                }
-                self.memory[addr] = self.registers[reg as usize];
+            Opcode::StSp(reg, offset) => {
+                let reg = self.reg_checked(reg)?;
+                self.write_word(self.stack_offset_address(offset)?, self.registers[reg])?;
             }
 
-            Opcode::Unknown(byte) => {
-                return Err(VMError::InvalidOpcode(byte));
+            Opcode::Load(reg, addr) => {
+                let reg = self.reg_checked(reg)?;
+                self.registers[reg] = self.read_word(addr as usize)?;
+            }
+
+            Opcode::Store(reg, addr) => {
+                let reg = self.reg_checked(reg)?;
+                self.write_word(addr as usize, self.registers[reg])?;
+            }
+
+            Opcode::LoadW(reg, addr) => {
+                let reg = self.reg_checked(reg)?;
+                self.registers[reg] = self.read_word(addr as usize)?;
+            }
+
+            Opcode::StoreW(reg, addr) => {
+                let reg = self.reg_checked(reg)?;
+                self.write_word(addr as usize, self.registers[reg])?;
             }
 
-            Opcode::Halt => {
-                self.pc = self.memory.len();
-                return Ok(());
+            Opcode::LdIdx(reg, base_addr) => {
+                let reg = self.reg_checked(reg)?;
+                let addr = self.indexed_address(base_addr)?;
+                self.registers[reg] = self.read_word(addr)?;
             }
 
-            Opcode::Jne => {
-                let addr = self.memory[self.pc] as usize;
-                self.pc += 1;
+            Opcode::StIdx(reg, base_addr) => {
+                let reg = self.reg_checked(reg)?;
+                let addr = self.indexed_address(base_addr)?;
+                self.write_word(addr, self.registers[reg])?;
+            }
+
+            Opcode::MemCpy => {
+                let dst = self.registers[self.reg_checked(0)?].to_usize();
+                let src = self.registers[self.reg_checked(1)?].to_usize();
+                let len = self.registers[self.reg_checked(2)?].to_usize();
+                // Check both ranges up front so a failure leaves memory untouched
+                let src_end = self.block_end(src, len)?;
+                let dst_end = self.block_end(dst, len)?;
+                let old = self.watched_bytes(dst..dst_end);
+                self.memory.copy_within(src..src_end, dst);
+                self.notify_watchpoints(dst, old);
+            }
+
+            Opcode::Fill => {
+                let start = self.registers[self.reg_checked(0)?].to_usize();
+                let value = self.registers[self.reg_checked(1)?].to_usize() as u8;
+                let count = self.registers[self.reg_checked(2)?].to_usize();
+                let end = self.block_end(start, count)?;
+                let old = self.watched_bytes(start..end);
+                self.memory[start..end].fill(value);
+                self.notify_watchpoints(start, old);
+            }
+
+            Opcode::Unknown(byte) => {
+                for handler in &self.handlers {
+                    let mut state = CpuState {
+                        registers: &mut self.registers,
+                        memory: &mut self.memory,
+                        pc: &mut self.pc,
+                        flags: &mut self.flags,
+                    };
+                    if handler.execute(&mut state, byte)?.is_some() {
+                        return Ok(());
+                    }
+                }
+                match self.config.on_unknown_opcode {
+                    UnknownPolicy::Error => return Err(VMError::InvalidOpcode(byte)),
+                    // The PC is already past the byte
+                    UnknownPolicy::Skip => {}
+                    UnknownPolicy::Halt => self.halt(0),
+                }
+            }
+
+            Opcode::Nop => {}
+
+            Opcode::Halt => self.halt(0),
+            Opcode::HaltCode(code) => self.halt(code),
+
+            Opcode::Jne(addr) => {
                 if self.flags & 1 == 0 {
                     // Jump if zero flag is NOT set
-                    self.pc = addr;
+                    self.jump_to(addr as usize)?;
+                }
+            }
+            Opcode::Jlt(addr) => {
+                if self.flags & 3 == 0 {
+                    // Jump if neither zero nor greater flag is set
+                    self.jump_to(addr as usize)?;
+                }
+            }
+            Opcode::Jle(addr) => {
+                if self.flags & 2 == 0 {
+                    // Jump if greater flag is NOT set
+                    self.jump_to(addr as usize)?;
+                }
+            }
+            Opcode::Jge(addr) => {
+                if self.flags & 3 != 0 {
+                    // Jump if either zero or greater flag is set
+                    self.jump_to(addr as usize)?;
+                }
+            }
+            Opcode::Jc(addr) => {
+                if self.flags & 4 != 0 {
+                    // Jump if carry flag is set
+                    self.jump_to(addr as usize)?;
                 }
             }
+            Opcode::Jnc(addr) => {
+                if self.flags & 4 == 0 {
+                    // Jump if carry flag is NOT set
+                    self.jump_to(addr as usize)?;
+                }
+            }
+            Opcode::Js(addr) => {
+                if self.flags & 8 != 0 {
+                    self.jump_to(addr as usize)?;
+                }
+            }
+            Opcode::Jns(addr) => {
+                if self.flags & 8 == 0 {
+                    self.jump_to(addr as usize)?;
+                }
+            }
+            Opcode::Jr(offset) => {
+                // The offset is relative to the instruction after the jump
+                let target = self
+                    .pc
+                    .checked_add_signed(offset as isize)
+                    .ok_or(VMError::InvalidMemoryAccess(self.pc))?;
+                self.jump_to(target)?;
+            }
+            Opcode::JmpR(reg) => {
+                let reg = self.reg_checked(reg)?;
+                self.jump_to(self.registers[reg].to_usize())?;
+            }
         }
         Ok(())
     }
@@ -295,16 +816,465 @@ impl CPU
     /// Runs the program loaded into the VM's memory.
     ///
     /// This function repeatedly fetches, decodes, and executes instructions until
-    /// the program halts or the memory is exhausted.
-    pub fn run(&mut self) -> Result<(), VMError>
+    /// the program halts, the memory is exhausted, a breakpoint is reached or the
+    /// configured `max_cycles` are used up.
+    ///
+    /// When the program counter reaches a breakpoint, `run` stops before executing
+    /// that instruction. Calling `run` again resumes from the breakpoint.
+    ///
+    /// # Returns
+    /// * `Ok(RunOutcome)` - Why execution stopped
+    /// * `Err(VMError)` - If an instruction failed
+    pub fn run(&mut self) -> Result<RunOutcome, VMError>
     {
-        while self.pc < self.memory.len() {
-            let opcode = self.fetch();
-            self.execute(opcode)?;
+        self.run_with(Self::step)
+    }
+
+    /// Decodes the whole of memory into a list of instructions and their addresses.
+    ///
+    /// Decoding starts at address 0 and stops at the end of memory or at a truncated
+    /// instruction. Call this after `load_program` and pass the result to
+    /// [`CPU::run_compiled`] to avoid decoding the same bytes on every loop iteration.
+    ///
+    /// # Returns
+    /// * `Vec<(Opcode, usize)>` - Each decoded instruction with the address it starts at
+    pub fn compile(&self) -> Vec<(Opcode, usize)>
+    {
+        let mut program = Vec::new();
+        let mut pc = 0;
+        while let Ok((opcode, size)) = Opcode::decode(&self.memory, pc) {
+            program.push((opcode, pc));
+            pc += size;
         }
+        program
+    }
+
+    /// Runs a program previously decoded with [`CPU::compile`].
+    ///
+    /// Behaves exactly like [`CPU::run`], but instructions are looked up by address
+    /// instead of being decoded from memory. Jumps into addresses that weren't
+    /// decoded (e.g. into the middle of data) fall back to decoding from memory.
+    /// The compiled program must be regenerated if memory holding code is modified.
+    ///
+    /// # Arguments
+    /// * `program` - The output of `compile` for the currently loaded program
+    pub fn run_compiled(&mut self, program: &[(Opcode, usize)]) -> Result<RunOutcome, VMError>
+    {
+        // Instruction index for each address, so jump targets map straight to entries
+        let mut index = vec![None; self.memory.len()];
+        for (i, &(_, addr)) in program.iter().enumerate() {
+            if let Some(slot) = index.get_mut(addr) {
+                *slot = Some(i);
+            }
+        }
+
+        self.run_with(|cpu| {
+            let Some(i) = index.get(cpu.pc).copied().flatten() else {
+                return cpu.step();
+            };
+            let (opcode, addr) = program[i];
+            // Entries from `compile` are contiguous, so the next one starts where this ends
+            cpu.pc = program.get(i + 1).map_or(cpu.memory.len(), |&(_, next)| next);
+            cpu.step_decoded(addr, opcode)
+        })
+    }
+
+    /// The shared run loop, handling breakpoints and the cycle limit.
+    ///
+    /// # Arguments
+    /// * `step` - Executes one instruction, returning whether the program can continue
+    fn run_with(&mut self, mut step: impl FnMut(&mut Self) -> Result<bool, VMError>) -> Result<RunOutcome, VMError>
+    {
+        let mut cycles = 0;
+        loop {
+            if self.breakpoints.contains(&self.pc) && self.stopped_at != Some(self.pc) {
+                self.stopped_at = Some(self.pc);
+                return Ok(RunOutcome::Breakpoint(self.pc));
+            }
+            self.stopped_at = None;
+
+            if !step(self)? {
+                return Ok(if self.exit_code.is_some() {
+                    RunOutcome::Halted
+                } else {
+                    RunOutcome::MemoryExhausted
+                });
+            }
+
+            cycles += 1;
+            if let Some(max) = self.config.max_cycles {
+                if cycles >= max {
+                    return Ok(RunOutcome::CycleLimit);
+                }
+            }
+        }
+    }
+
+    /// Makes `run` stop before executing the instruction at `addr`.
+    ///
+    /// # Arguments
+    /// * `addr` - Address of the instruction to stop at
+    pub fn add_breakpoint(&mut self, addr: usize)
+    {
+        self.breakpoints.insert(addr);
+    }
+
+    /// Removes a breakpoint added with [`CPU::add_breakpoint`].
+    ///
+    /// # Returns
+    /// * `bool` - Whether a breakpoint was set at `addr`
+    pub fn remove_breakpoint(&mut self, addr: usize) -> bool
+    {
+        self.breakpoints.remove(&addr)
+    }
+
+    /// Reports writes to `addr` to the watch hook.
+    ///
+    /// `STORE`, `STOREW`, `STIDX`, `STSP`, `PUSH`, `PUSHA`, `ENTER`, `MEMCPY` and `FILL`
+    /// are watched. Writes made with
+    /// [`CPU::poke`], [`CPU::load_program`] or custom instruction handlers are not.
+    ///
+    /// # Arguments
+    /// * `addr` - The memory address to watch
+    pub fn add_watchpoint(&mut self, addr: usize)
+    {
+        self.watchpoints.insert(addr);
+    }
+
+    /// Removes a watchpoint added with [`CPU::add_watchpoint`].
+    ///
+    /// # Returns
+    /// * `bool` - Whether `addr` was being watched
+    pub fn remove_watchpoint(&mut self, addr: usize) -> bool
+    {
+        self.watchpoints.remove(&addr)
+    }
+
+    /// Installs the callback that is invoked when a watched address is written.
+    ///
+    /// The hook receives a [`WatchEvent`] for every watched byte an instruction
+    /// writes, even if the value doesn't change. Replaces any previously installed hook.
+    pub fn set_watch_hook(&mut self, hook: WatchHook)
+    {
+        self.watch_hook = Some(hook);
+    }
+
+    /// Executes a single instruction.
+    ///
+    /// # Returns
+    /// * `Ok(true)` - The instruction ran and the program can continue
+    /// * `Ok(false)` - The program has halted (the PC is past the end of memory)
+    /// * `Err(VMError)` - The instruction failed to execute
+    pub fn step(&mut self) -> Result<bool, VMError>
+    {
+        if self.pc >= self.memory.len() {
+            return Ok(false);
+        }
+
+        let pc = self.pc;
+        let opcode = self.fetch()?;
+        self.step_decoded(pc, opcode)
+    }
+
+    /// Executes an instruction that has already been fetched and notifies the trace hook.
+    ///
+    /// # Arguments
+    /// * `pc` - The address the instruction was fetched from
+    /// * `opcode` - The instruction, with the program counter already moved past it
+    fn step_decoded(&mut self, pc: usize, opcode: Opcode) -> Result<bool, VMError>
+    {
+        self.execute(opcode)?;
+        self.cycles += 1;
+        self.cost += self.config.costs.get(&opcode.byte()).copied().unwrap_or(DEFAULT_COST) as u64;
+
+        if let Some(hook) = self.trace_hook.as_mut() {
+            hook(&TraceEvent {
+                pc,
+                opcode,
+                registers: self.registers.clone(),
+                flags: self.flags,
+            });
+        }
+
+        Ok(self.pc < self.memory.len())
+    }
+
+    /// Returns the number of instructions executed since the CPU was created or reset.
+    ///
+    /// Every instruction counts as one cycle, including the final `HALT`. Instructions
+    /// that fail with an error are not counted.
+    pub fn cycle_count(&self) -> usize
+    {
+        self.cycles
+    }
+
+    /// Returns the weighted cost of the instructions executed since the CPU was created or reset.
+    ///
+    /// Each instruction adds its cost from `VMConfig::costs`, so by default a `MUL`
+    /// adds 4, a `DIV` adds 8, memory and stack accesses add 2 and everything else adds 1.
+    /// The memory and stack accesses are `OUTSTR`, `PUSH`/`POP`, `PUSHA`/`POPA`,
+    /// `ENTER`/`LEAVE`, the loads and stores (including `LDSP`/`STSP`), `MEMCPY` and `FILL`.
+    pub fn cost(&self) -> u64
+    {
+        self.cost
+    }
+
+    /// Stops the program with an exit code by moving the PC past the end of memory.
+    fn halt(&mut self, code: u8)
+    {
+        self.exit_code = Some(code);
+        self.pc = self.memory.len();
+    }
+
+    /// Moves the program counter to a jump or call target.
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidMemoryAccess)` - If the target lies outside memory
+    fn jump_to(&mut self, addr: usize) -> Result<(), VMError>
+    {
+        if addr >= self.memory.len() {
+            return Err(VMError::InvalidMemoryAccess(addr));
+        }
+        self.pc = addr;
+        Ok(())
+    }
+
+    /// Computes the effective address of an indexed load or store, `base + r1`.
+    ///
+    /// Addresses don't wrap around the end of memory: an address at or past
+    /// `memory.len()` is reported as an invalid access instead.
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidMemoryAccess)` - If the address is outside memory
+    fn indexed_address(&self, base: u8) -> Result<usize, VMError>
+    {
+        let index = self.registers[self.reg_checked(1)?].to_usize();
+        let addr = base as usize + index;
+        if addr >= self.memory.len() {
+            return Err(VMError::InvalidMemoryAccess(addr));
+        }
+        Ok(addr)
+    }
+
+    /// Computes the end of a block of `len` bytes starting at `addr`.
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidMemoryAccess)` - If any byte of the block is outside memory
+    fn block_end(&self, addr: usize, len: usize) -> Result<usize, VMError>
+    {
+        addr.checked_add(len)
+            .filter(|&end| end <= self.memory.len())
+            .ok_or(VMError::InvalidMemoryAccess(addr.max(self.memory.len())))
+    }
+
+    /// Reads a little-endian word from memory.
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidMemoryAccess)` - If any byte of the word is outside memory
+    fn read_word(&self, addr: usize) -> Result<W, VMError>
+    {
+        let bytes = self
+            .memory
+            .get(addr..addr + W::BYTES)
+            .ok_or(VMError::InvalidMemoryAccess(addr))?;
+        Ok(W::read_le(bytes))
+    }
+
+    /// Writes a word to memory in little-endian order.
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidMemoryAccess)` - If any byte of the word is outside memory
+    fn write_word(&mut self, addr: usize, value: W) -> Result<(), VMError>
+    {
+        let bytes = self
+            .memory
+            .get_mut(addr..addr + W::BYTES)
+            .ok_or(VMError::InvalidMemoryAccess(addr))?;
+        let old = (!self.watchpoints.is_empty()).then(|| bytes.to_vec());
+        value.write_le(bytes);
+        self.notify_watchpoints(addr, old);
+        Ok(())
+    }
+
+    /// Reads a little-endian two-byte value, such as a base pointer saved by `ENTER`.
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidMemoryAccess)` - If either byte is outside memory
+    fn read_u16(&self, addr: usize) -> Result<u16, VMError>
+    {
+        let bytes = self.memory.get(addr..addr + 2).ok_or(VMError::InvalidMemoryAccess(addr))?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Writes a little-endian two-byte value, reporting watched bytes like [`CPU::write_word`].
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidMemoryAccess)` - If either byte is outside memory
+    fn write_u16(&mut self, addr: usize, value: u16) -> Result<(), VMError>
+    {
+        let end = self.block_end(addr, 2)?;
+        let old = self.watched_bytes(addr..end);
+        self.memory[addr..end].copy_from_slice(&value.to_le_bytes());
+        self.notify_watchpoints(addr, old);
         Ok(())
     }
 
+    /// Copies the bytes in `range` if any watchpoints are set, for [`CPU::notify_watchpoints`].
+    fn watched_bytes(&self, range: core::ops::Range<usize>) -> Option<Vec<u8>>
+    {
+        (!self.watchpoints.is_empty()).then(|| self.memory[range].to_vec())
+    }
+
+    /// Reports every watched byte of a write starting at `addr` to the watch hook.
+    ///
+    /// `old` holds the bytes from before the write, or `None` if nothing is watched.
+    fn notify_watchpoints(&mut self, addr: usize, old: Option<Vec<u8>>)
+    {
+        if let (Some(old), Some(hook)) = (old, self.watch_hook.as_mut()) {
+            for (offset, old) in old.into_iter().enumerate() {
+                let addr = addr + offset;
+                if self.watchpoints.contains(&addr) {
+                    hook(&WatchEvent {
+                        addr,
+                        old,
+                        new: self.memory[addr],
+                    });
+                }
+            }
+        }
+    }
+
+    /// Pushes a word onto the stack.
+    ///
+    /// # Returns
+    /// * `Err(VMError::StackOverflow)` - If the stack is full
+    fn push_word(&mut self, value: W) -> Result<(), VMError>
+    {
+        if self.stack_room() < W::BYTES {
+            return Err(VMError::StackOverflow);
+        }
+        let addr = self.reserve_stack(W::BYTES);
+        self.write_word(addr, value)
+    }
+
+    /// Pops a word off the stack.
+    ///
+    /// # Returns
+    /// * `Err(VMError::StackUnderflow)` - If the stack is empty
+    fn pop_word(&mut self) -> Result<W, VMError>
+    {
+        if self.stack_used(self.sp) < W::BYTES {
+            return Err(VMError::StackUnderflow);
+        }
+        let addr = self.release_stack(W::BYTES);
+        self.read_word(addr)
+    }
+
+    /// Moves the stack pointer past `len` new bytes, in the direction the stack grows.
+    ///
+    /// The caller checks [`CPU::stack_room`] first.
+    ///
+    /// # Returns
+    /// * `usize` - The lowest address of the reserved bytes
+    fn reserve_stack(&mut self, len: usize) -> usize
+    {
+        if self.config.stack_grows_down {
+            self.sp -= len;
+            self.sp
+        } else {
+            self.sp += len;
+            self.sp - len
+        }
+    }
+
+    /// Moves the stack pointer back over the last `len` bytes pushed.
+    ///
+    /// The caller checks [`CPU::stack_used`] first.
+    ///
+    /// # Returns
+    /// * `usize` - The lowest address of the released bytes
+    fn release_stack(&mut self, len: usize) -> usize
+    {
+        if self.config.stack_grows_down {
+            self.sp += len;
+            self.sp - len
+        } else {
+            self.sp -= len;
+            self.sp
+        }
+    }
+
+    /// Number of bytes that can still be pushed before the stack overflows.
+    fn stack_room(&self) -> usize
+    {
+        if self.config.stack_grows_down {
+            self.sp.saturating_sub(self.stack_limit())
+        } else {
+            self.config.memory_size.saturating_sub(self.sp)
+        }
+    }
+
+    /// Number of bytes on the stack if the stack pointer were at `sp`.
+    fn stack_used(&self, sp: usize) -> usize
+    {
+        if self.config.stack_grows_down {
+            self.config.memory_size.saturating_sub(sp)
+        } else {
+            sp.saturating_sub(self.stack_limit())
+        }
+    }
+
+    /// Address of the word `offset` bytes into the stack for `LDSP`/`STSP`.
+    ///
+    /// Offset 0 is always the most recently pushed word, whichever way the stack grows.
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidMemoryAccess)` - If the word isn't inside the pushed part of the stack
+    fn stack_offset_address(&self, offset: u8) -> Result<usize, VMError>
+    {
+        let offset = offset as usize;
+        if self.config.stack_grows_down {
+            // Live region is [sp, memory_size)
+            let addr = self.sp + offset;
+            if addr + W::BYTES > self.config.memory_size {
+                return Err(VMError::InvalidMemoryAccess(addr));
+            }
+            Ok(addr)
+        } else {
+            // Live region is [stack_limit, sp)
+            let addr = self
+                .sp
+                .checked_sub(W::BYTES + offset)
+                .ok_or(VMError::InvalidMemoryAccess(self.sp))?;
+            if addr < self.stack_limit() {
+                return Err(VMError::InvalidMemoryAccess(addr));
+            }
+            Ok(addr)
+        }
+    }
+
+    /// Lowest address of the stack region.
+    ///
+    /// Everything below it belongs to the program and its data.
+    fn stack_limit(&self) -> usize
+    {
+        self.config.memory_size - self.config.stack_size
+    }
+
+    /// Validates a register operand decoded from bytecode.
+    ///
+    /// # Returns
+    /// * `Ok(usize)` - The register index, safe to use with `self.registers`
+    /// * `Err(VMError::InvalidRegister)` - If the VM has no such register
+    fn reg_checked(&self, idx: u8) -> Result<usize, VMError>
+    {
+        let idx = idx as usize;
+        if idx >= self.registers.len() {
+            return Err(VMError::InvalidRegister(idx));
+        }
+        Ok(idx)
+    }
+
     // Add helper methods for flag operations
     fn set_zero_flag(&mut self, value: bool)
     {
@@ -324,31 +1294,243 @@ impl CPU
         }
     }
 
-    // Add this method
+    fn set_carry_flag(&mut self, value: bool)
+    {
+        if value {
+            self.flags |= 4;
+        } else {
+            self.flags &= !4;
+        }
+    }
+
+    fn set_sign_flag(&mut self, value: bool)
+    {
+        if value {
+            self.flags |= 8;
+        } else {
+            self.flags &= !8;
+        }
+    }
+
+    /// Prints [`CPU::state_string`] to stdout when `config.debug` is enabled.
+    #[cfg(feature = "std")]
     pub fn dump_state(&self)
     {
         if !self.config.debug {
             return;
         }
 
-        println!("\nCPU State:");
-        println!("PC: {}", self.pc);
-        println!("Registers: {:?}", self.registers);
-        println!("Flags: {:08b}", self.flags);
-        println!("Stack pointer: {}", self.sp);
-        println!(
-            "Memory[0x50-0x52]: {:02X} {:02X} {:02X}",
-            self.memory[0x50], self.memory[0x51], self.memory[0x52]
-        );
-        println!("Call stack: {:?}", self.call_stack);
+        println!("\n{}", self.state_string());
+    }
+
+    /// Formats the machine state, including the memory at `0x50..0x53`.
+    ///
+    /// See [`CPU::state_string_with_memory`] to show a different memory window.
+    pub fn state_string(&self) -> String
+    {
+        self.state_string_with_memory(0x50..0x53)
+    }
+
+    /// Formats the registers, flags, PC, SP, call stack and a window of memory.
+    ///
+    /// The memory window is clamped to the size of memory, so this never panics.
+    ///
+    /// # Arguments
+    /// * `window` - The memory addresses to include
+    pub fn state_string_with_memory(&self, window: Range<usize>) -> String
+    {
+        let registers: Vec<String> = self
+            .registers
+            .iter()
+            .enumerate()
+            .map(|(index, value)| format!("r{}={}", index, value))
+            .collect();
+
+        let flag_names: Vec<&str> = [(1, "zero"), (2, "greater"), (4, "carry"), (8, "sign")]
+            .iter()
+            .filter(|&&(bit, _)| self.flags & bit != 0)
+            .map(|&(_, name)| name)
+            .collect();
+
+        let start = window.start.min(self.memory.len());
+        let end = window.end.clamp(start, self.memory.len());
+        let bytes: Vec<String> = self.memory[start..end].iter().map(|byte| format!("{:02X}", byte)).collect();
+
+        let mut state = String::from("CPU State:\n");
+        state.push_str(&format!("PC: 0x{:02X}\n", self.pc));
+        state.push_str(&format!("Registers: {}\n", registers.join(" ")));
+        state.push_str(&format!("Flags: {:08b} [{}]\n", self.flags, flag_names.join(", ")));
+        state.push_str(&format!("Stack pointer: 0x{:02X}\n", self.sp));
+        state.push_str(&format!("Base pointer: 0x{:02X}\n", self.bp));
+        state.push_str(&format!("Call stack: {:?}\n", self.call_stack));
+        state.push_str(&format!("Memory[0x{:02X}..0x{:02X}]: {}", start, end, bytes.join(" ")));
+        state
     }
 
     // Add this new method
-    pub fn get_register(&self, index: usize) -> Result<u8, VMError>
+    pub fn get_register(&self, index: usize) -> Result<W, VMError>
     {
         if index >= self.registers.len() {
             return Err(VMError::InvalidRegister(index));
         }
         Ok(self.registers[index])
     }
+
+    /// Returns the full register file.
+    pub fn registers(&self) -> &[W]
+    {
+        &self.registers
+    }
+
+    /// Returns the exit code the program halted with.
+    ///
+    /// `HALT` exits with 0 and `HALT code` with `code`. Returns `None` if no
+    /// `HALT` has run yet, e.g. the program ran off the end of memory.
+    pub fn exit_code(&self) -> Option<u8>
+    {
+        self.exit_code
+    }
+
+    /// Returns whether a `HALT` has run.
+    ///
+    /// A program that runs off the end of memory stops without halting, so this
+    /// stays `false` for it, like [`CPU::exit_code`] stays `None`.
+    pub fn is_halted(&self) -> bool
+    {
+        self.exit_code.is_some()
+    }
+
+    /// Returns the flags register (see [`CPU`] for the bit layout).
+    pub fn flags(&self) -> u8
+    {
+        self.flags
+    }
+
+    /// Returns the program counter.
+    pub fn pc(&self) -> usize
+    {
+        self.pc
+    }
+
+    /// Returns the stack pointer.
+    pub fn sp(&self) -> usize
+    {
+        self.sp
+    }
+
+    /// Returns the base pointer set by the innermost `ENTER`.
+    ///
+    /// Outside of any frame it's the initial stack pointer.
+    pub fn bp(&self) -> usize
+    {
+        self.bp
+    }
+
+    /// Returns how many bytes are on the stack, counting `ENTER` frames.
+    ///
+    /// Overflow happens once this would exceed `VMConfig::stack_size`.
+    pub fn stack_depth(&self) -> usize
+    {
+        self.stack_used(self.sp)
+    }
+
+    /// Returns how many `CALL`s are waiting for their `RET`.
+    ///
+    /// Overflow happens once this would exceed `VMConfig::max_call_depth`.
+    pub fn call_depth(&self) -> usize
+    {
+        self.call_stack.len()
+    }
+
+    /// Returns a read-only view of memory.
+    pub fn memory(&self) -> &[u8]
+    {
+        &self.memory
+    }
+
+    /// Reads a single byte of memory.
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidMemoryAccess)` - If `addr` is outside memory
+    pub fn peek(&self, addr: usize) -> Result<u8, VMError>
+    {
+        self.memory.get(addr).copied().ok_or(VMError::InvalidMemoryAccess(addr))
+    }
+
+    /// Writes a single byte of memory, e.g. to preload input data before `run`.
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidMemoryAccess)` - If `addr` is outside memory
+    pub fn poke(&mut self, addr: usize, value: u8) -> Result<(), VMError>
+    {
+        let byte = self.memory.get_mut(addr).ok_or(VMError::InvalidMemoryAccess(addr))?;
+        *byte = value;
+        Ok(())
+    }
+}
+
+/// Runs a program on a fresh 8-bit CPU and returns everything its `OUT` instructions wrote.
+///
+/// Nothing is printed to stdout, which makes this a small surface for embedding
+/// the VM, e.g. behind a WebAssembly binding.
+///
+/// # Arguments
+/// * `bytecode` - The program, loaded at address 0
+/// * `config` - The machine to run it on
+///
+/// # Returns
+/// * `Ok(String)` - The output once the program stops, one line per `OUT` with the
+///   trailing newline removed, so a single `OUT` gives just its value
+/// * `Err(VMError::CycleLimitExceeded)` - If `config.max_cycles` were used up
+/// * `Err(VMError)` - If the configuration is invalid, the program doesn't fit or an instruction failed
+///
+/// # Example
+/// ```
+/// # use vm::{run_to_string, VMConfig};
+/// // MOV r0, 8; OUT r0; HALT
+/// let output = run_to_string(&[0x04, 0x00, 0x08, 0x03, 0x00, 0xFF], VMConfig::default()).unwrap();
+/// assert_eq!(output, "8");
+/// ```
+pub fn run_to_string(bytecode: &[u8], config: VMConfig) -> Result<String, VMError>
+{
+    let max_cycles = config.max_cycles;
+    let output = CapturedOutput::default();
+
+    let mut cpu = CPU::new(config)?;
+    cpu.set_output(Box::new(output.clone()));
+    cpu.load_program(bytecode)?;
+    if cpu.run()? == RunOutcome::CycleLimit {
+        return Err(VMError::CycleLimitExceeded(max_cycles.unwrap_or(0)));
+    }
+
+    let bytes = output.0.borrow();
+    let text = String::from_utf8_lossy(&bytes);
+    Ok(text.strip_suffix('\n').unwrap_or(&text).into())
+}
+
+/// An output sink that can still be read after being handed to a CPU.
+#[derive(Clone, Default)]
+struct CapturedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl Output for CapturedOutput
+{
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), String>
+    {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// The sink `OUT` writes to until [`CPU::set_output`] replaces it.
+#[cfg(feature = "std")]
+fn default_output() -> Box<dyn Output>
+{
+    Box::new(std::io::stdout())
+}
+
+/// The sink `OUT` writes to until [`CPU::set_output`] replaces it.
+#[cfg(not(feature = "std"))]
+fn default_output() -> Box<dyn Output>
+{
+    Box::new(super::output::Discard)
 }
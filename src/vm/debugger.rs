@@ -0,0 +1,156 @@
+//! Interactive debugger
+//!
+//! [`Debugger`] wraps a [`CPU`] and exposes the usual debugger commands —
+//! step, continue, inspect registers and memory, set breakpoints — as method
+//! calls. Every command returns plain data rather than printing, so any
+//! front-end (a REPL, a GUI, a test) can decide how to render it.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use super::cpu::{RunOutcome, CPU};
+use super::error::VMError;
+use super::opcode::Opcode;
+use super::word::Word;
+
+/// The result of executing a single instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step
+{
+    /// Address the instruction was fetched from
+    pub pc: usize,
+    /// The instruction that was executed
+    pub opcode: Opcode,
+    /// Whether the program can continue, i.e. it hasn't halted or run off the end of memory
+    pub running: bool,
+}
+
+/// A copy of the CPU's registers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Registers<W = u8>
+{
+    /// General-purpose registers, r0 first
+    pub values: Vec<W>,
+    pub pc: usize,
+    pub sp: usize,
+    pub flags: u8,
+}
+
+/// A copy of a range of memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryDump
+{
+    /// Address of the first byte
+    pub start: usize,
+    pub bytes: Vec<u8>,
+}
+
+/// Drives a [`CPU`] one command at a time.
+pub struct Debugger<W: Word = u8>
+{
+    cpu: CPU<W>,
+}
+
+impl<W: Word> Debugger<W>
+{
+    /// Creates a debugger for a CPU that already has a program loaded.
+    pub fn new(cpu: CPU<W>) -> Self
+    {
+        Self { cpu }
+    }
+
+    /// Returns the CPU being debugged.
+    pub fn cpu(&self) -> &CPU<W>
+    {
+        &self.cpu
+    }
+
+    /// Returns the CPU being debugged, e.g. to change memory.
+    pub fn cpu_mut(&mut self) -> &mut CPU<W>
+    {
+        &mut self.cpu
+    }
+
+    /// Stops debugging and returns the CPU.
+    pub fn into_cpu(self) -> CPU<W>
+    {
+        self.cpu
+    }
+
+    /// Decodes the instruction at the program counter without executing it.
+    ///
+    /// # Returns
+    /// * `Ok(None)` - The program counter is past the end of memory
+    /// * `Err(VMError::InvalidMemoryAccess)` - If the instruction is truncated
+    pub fn peek(&self) -> Result<Option<Opcode>, VMError>
+    {
+        let pc = self.cpu.pc();
+        if pc >= self.cpu.memory().len() {
+            return Ok(None);
+        }
+        Opcode::decode(self.cpu.memory(), pc).map(|(opcode, _)| Some(opcode))
+    }
+
+    /// Executes the next instruction, ignoring breakpoints.
+    ///
+    /// # Returns
+    /// * `Ok(None)` - The program has already finished
+    /// * `Ok(Some(Step))` - The instruction that ran
+    pub fn step(&mut self) -> Result<Option<Step>, VMError>
+    {
+        let pc = self.cpu.pc();
+        let Some(opcode) = self.peek()? else {
+            return Ok(None);
+        };
+        let running = self.cpu.step()?;
+        Ok(Some(Step { pc, opcode, running }))
+    }
+
+    /// Runs until the program halts, hits a breakpoint or uses up its cycle limit.
+    ///
+    /// Continuing from a breakpoint executes the instruction it stopped at.
+    pub fn continue_execution(&mut self) -> Result<RunOutcome, VMError>
+    {
+        self.cpu.run()
+    }
+
+    /// Returns the current register values.
+    pub fn registers(&self) -> Registers<W>
+    {
+        Registers {
+            values: self.cpu.registers().to_vec(),
+            pc: self.cpu.pc(),
+            sp: self.cpu.sp(),
+            flags: self.cpu.flags(),
+        }
+    }
+
+    /// Returns a copy of the given memory range.
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidMemoryAccess)` - If the range extends past the end of memory
+    pub fn memory(&self, range: Range<usize>) -> Result<MemoryDump, VMError>
+    {
+        let bytes = self
+            .cpu
+            .memory()
+            .get(range.clone())
+            .ok_or(VMError::InvalidMemoryAccess(range.end))?;
+        Ok(MemoryDump {
+            start: range.start,
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    /// Stops [`Debugger::continue_execution`] before the instruction at `addr`.
+    pub fn set_breakpoint(&mut self, addr: usize)
+    {
+        self.cpu.add_breakpoint(addr);
+    }
+
+    /// Removes a breakpoint, returning whether one was set at `addr`.
+    pub fn clear_breakpoint(&mut self, addr: usize) -> bool
+    {
+        self.cpu.remove_breakpoint(addr)
+    }
+}
@@ -0,0 +1,134 @@
+//! Bytecode disassembler
+//!
+//! This module converts bytecode back into assembly text. It walks the bytes
+//! with [`Opcode::decode`], the same decoder that `CPU::fetch` uses, so the
+//! output reflects how the VM will actually interpret a program.
+//!
+//! # Example Output
+//! ```text
+//! MOV r0, 0x05
+//! MOV r1, 0x03
+//! ADD r0, r1
+//! HALT
+//! ```
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::error::VMError;
+use super::opcode::Opcode;
+use crate::span::Span;
+
+/// Converts bytecode into assembly source, one instruction per line.
+///
+/// Each instruction is written with the alternate form of [`Opcode`]'s `Display`,
+/// so registers are `rN` and immediates/addresses are in hex. Bytes that don't
+/// correspond to a known opcode are emitted as `; unknown 0xNN` comments.
+///
+/// # Arguments
+/// * `bytecode` - The program to disassemble
+///
+/// # Returns
+/// * `Ok(String)` - The assembly text
+/// * `Err(VMError::InvalidMemoryAccess)` - If the last instruction is missing operand bytes
+pub fn disassemble(bytecode: &[u8]) -> Result<String, VMError>
+{
+    let mut output = String::new();
+
+    for (_, line) in disassemble_with_spans(bytecode)? {
+        output.push_str(&line);
+        output.push('\n');
+    }
+
+    Ok(output)
+}
+
+/// Disassembles bytecode like [`disassemble`], keeping track of where each line came from.
+///
+/// Each line's span covers the bytes its instruction was decoded from, and its
+/// `line` is the line's 1-based position in the output of [`disassemble`].
+///
+/// # Arguments
+/// * `bytecode` - The program to disassemble
+///
+/// # Returns
+/// * `Ok(Vec<(Span, String)>)` - Every instruction's span and assembly text, in order
+/// * `Err(VMError::InvalidMemoryAccess)` - If the last instruction is missing operand bytes
+///
+/// # Example
+/// ```
+/// # use vm::{disassemble_with_spans, Span};
+/// let lines = disassemble_with_spans(&[0x04, 0x00, 0x05, 0xFF]).unwrap();
+/// assert_eq!(
+///     lines[1],
+///     (
+///         Span {
+///             start: 3,
+///             end: 4,
+///             line: 2
+///         },
+///         "HALT".to_string()
+///     )
+/// );
+/// ```
+pub fn disassemble_with_spans(bytecode: &[u8]) -> Result<Vec<(Span, String)>, VMError>
+{
+    let mut lines = Vec::new();
+    let mut pc = 0;
+
+    while pc < bytecode.len() {
+        let (opcode, size) = Opcode::decode(bytecode, pc)?;
+        let span = Span {
+            start: pc,
+            end: pc + size,
+            line: lines.len() + 1,
+        };
+        pc += size;
+
+        lines.push((span, format!("{:#}", opcode)));
+    }
+
+    Ok(lines)
+}
+
+/// Iterates over the instructions in bytecode without executing them.
+///
+/// Each item is the address of an instruction and its decoded opcode. This is the
+/// read-only counterpart of `CPU::fetch` and uses the same decoder.
+///
+/// # Arguments
+/// * `bytecode` - The program to walk
+///
+/// # Returns
+/// An iterator that yields `Err(VMError::InvalidMemoryAccess)` and then stops if the
+/// last instruction is missing operand bytes
+///
+/// # Example
+/// ```
+/// # use vm::{instructions, Opcode};
+/// let opcodes: Vec<_> = instructions(&[0x01, 0x00, 0xFF])
+///     .collect::<Result<_, _>>()
+///     .unwrap();
+/// assert_eq!(opcodes, vec![(0, Opcode::Inc(0)), (2, Opcode::Halt)]);
+/// ```
+pub fn instructions(bytecode: &[u8]) -> impl Iterator<Item = Result<(usize, Opcode), VMError>> + '_
+{
+    let mut pc = 0;
+    core::iter::from_fn(move || {
+        if pc >= bytecode.len() {
+            return None;
+        }
+        match Opcode::decode(bytecode, pc) {
+            Ok((opcode, size)) => {
+                let address = pc;
+                pc += size;
+                Some(Ok((address, opcode)))
+            }
+            Err(e) => {
+                pc = bytecode.len();
+                Some(Err(e))
+            }
+        }
+    })
+}
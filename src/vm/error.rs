@@ -1,4 +1,5 @@
-use std::fmt;
+use alloc::string::String;
+use core::fmt;
 
 #[derive(Debug)]
 pub enum VMError
@@ -7,12 +8,22 @@ pub enum VMError
     InvalidMemoryAccess(usize),
     StackOverflow,
     StackUnderflow,
+    CallStackOverflow,
     DivisionByZero,
     InvalidOpcode(u8),
-    ProgramComplete,
+    OutputError(String),
+    CycleLimitExceeded(usize),
+    InvalidConfig(VMConfigError),
+    InvalidHex(String),
+    InvalidSnapshot(String),
+    ProgramTooLarge
+    {
+        size: usize,
+        capacity: usize,
+    },
 }
 
-impl std::error::Error for VMError {}
+impl core::error::Error for VMError {}
 
 impl fmt::Display for VMError
 {
@@ -23,9 +34,70 @@ impl fmt::Display for VMError
             VMError::InvalidMemoryAccess(addr) => write!(f, "Invalid memory access at address: {}", addr),
             VMError::StackOverflow => write!(f, "Stack overflow"),
             VMError::StackUnderflow => write!(f, "Stack underflow"),
+            VMError::CallStackOverflow => write!(f, "Call stack overflow"),
             VMError::DivisionByZero => write!(f, "Division by zero"),
             VMError::InvalidOpcode(op) => write!(f, "Invalid opcode: {:#04x}", op),
-            VMError::ProgramComplete => write!(f, "Program completed execution"),
+            VMError::OutputError(msg) => write!(f, "Failed to write output: {}", msg),
+            VMError::CycleLimitExceeded(max) => write!(f, "Cycle limit exceeded after {} instructions", max),
+            VMError::InvalidConfig(e) => write!(f, "Invalid configuration: {}", e),
+            VMError::InvalidHex(msg) => write!(f, "Invalid Intel HEX: {}", msg),
+            VMError::InvalidSnapshot(msg) => write!(f, "Snapshot doesn't match this CPU: {}", msg),
+            VMError::ProgramTooLarge { size, capacity } => {
+                write!(f, "Program is {} bytes but memory only holds {}", size, capacity)
+            }
+        }
+    }
+}
+
+/// Errors produced when a [`VMConfig`](super::VMConfig) describes an impossible machine.
+#[derive(Debug, PartialEq)]
+pub enum VMConfigError
+{
+    /// The memory size is zero
+    EmptyMemory,
+    /// The stack is larger than the whole of memory
+    StackTooLarge
+    {
+        stack_size: usize, memory_size: usize
+    },
+    /// The VM has no general-purpose registers
+    NoRegisters,
+    /// The program counter would start outside of memory
+    PcOutOfRange(usize),
+    /// The stack pointer would start outside of memory
+    SpOutOfRange(usize),
+    /// More initial register values were given than there are registers
+    TooManyInitialRegisters
+    {
+        given: usize, num_registers: usize
+    },
+}
+
+impl core::error::Error for VMConfigError {}
+
+impl From<VMConfigError> for VMError
+{
+    fn from(e: VMConfigError) -> Self
+    {
+        VMError::InvalidConfig(e)
+    }
+}
+
+impl fmt::Display for VMConfigError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            VMConfigError::EmptyMemory => write!(f, "Memory size must be greater than zero"),
+            VMConfigError::StackTooLarge { stack_size, memory_size } => {
+                write!(f, "Stack size {} does not fit in {} bytes of memory", stack_size, memory_size)
+            }
+            VMConfigError::NoRegisters => write!(f, "Number of registers must be greater than zero"),
+            VMConfigError::PcOutOfRange(addr) => write!(f, "Program counter start is outside memory: {}", addr),
+            VMConfigError::SpOutOfRange(addr) => write!(f, "Stack pointer start is outside memory: {}", addr),
+            VMConfigError::TooManyInitialRegisters { given, num_registers } => {
+                write!(f, "{} initial register values given for {} registers", given, num_registers)
+            }
         }
     }
 }
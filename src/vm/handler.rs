@@ -0,0 +1,48 @@
+//! Custom instruction handlers
+//!
+//! Bytes that the VM doesn't recognize as an opcode are offered to any
+//! registered [`InstructionHandler`] before `VMConfig::on_unknown_opcode`
+//! decides what to do with them. This allows extra instructions to be added
+//! without changing the VM itself.
+
+use super::error::VMError;
+use super::word::Word;
+
+/// Mutable access to the parts of the CPU a custom instruction may change.
+///
+/// When a handler is called, `pc` points at the byte after the opcode. Handlers
+/// that take operands should read them with [`CpuState::fetch_byte`] so the
+/// program counter ends up after the whole instruction.
+pub struct CpuState<'a, W: Word = u8>
+{
+    pub registers: &'a mut [W],
+    pub memory: &'a mut [u8],
+    pub pc: &'a mut usize,
+    pub flags: &'a mut u8,
+}
+
+impl<W: Word> CpuState<'_, W>
+{
+    /// Reads the operand byte at the program counter and advances past it.
+    ///
+    /// # Returns
+    /// * `Err(VMError::InvalidMemoryAccess)` - If the instruction runs past the end of memory
+    pub fn fetch_byte(&mut self) -> Result<u8, VMError>
+    {
+        let byte = *self.memory.get(*self.pc).ok_or(VMError::InvalidMemoryAccess(*self.pc))?;
+        *self.pc += 1;
+        Ok(byte)
+    }
+}
+
+/// Executes opcodes the VM doesn't know about.
+pub trait InstructionHandler<W: Word = u8>
+{
+    /// Attempts to execute `opcode`.
+    ///
+    /// # Returns
+    /// * `Ok(Some(()))` - The handler executed the instruction
+    /// * `Ok(None)` - The handler doesn't implement this opcode
+    /// * `Err(VMError)` - The instruction failed
+    fn execute(&self, cpu: &mut CpuState<W>, opcode: u8) -> Result<Option<()>, VMError>;
+}
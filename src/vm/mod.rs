@@ -3,16 +3,35 @@
 //! This module contains the core components of our virtual machine:
 //! - CPU: The processor that executes instructions
 //! - Memory: Storage for program code and data
-//! - Registers: Fast storage for values being processed
+//! - Registers: Fast storage for values being processed, 8 or 16 bits wide
+//! - Disassembler: Converts bytecode back into assembly text
+//! - Debugger: Steps through a program and inspects the machine state
 //!
 //! The VM is configured using the VMConfig struct, which allows customization
 //! of memory size, number of registers, and other important parameters.
 
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
 pub mod error;
+pub mod handler;
 pub mod opcode;
+pub mod output;
+pub mod trace;
+pub mod verify;
+pub mod word;
 
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+pub use cpu::run_to_string;
+pub use debugger::Debugger;
+pub use disasm::{disassemble, disassemble_with_spans, instructions};
+pub use error::VMConfigError;
+pub use handler::{CpuState, InstructionHandler};
 pub use opcode::Opcode;
+pub use verify::{verify, verify_for};
+pub use word::Word;
 
 // Default configuration values
 const DEFAULT_MEMORY_SIZE: usize = 256; // Total memory in bytes
@@ -20,6 +39,37 @@ const DEFAULT_STACK_SIZE: usize = 64; // Size of the stack region
 const DEFAULT_NUM_REGISTERS: usize = 8; // Number of general-purpose registers
 const DEFAULT_PC_START: usize = 0; // Program Counter starts at memory address 0
 const DEFAULT_SP_START: usize = 255; // Stack Pointer starts at top of memory
+const DEFAULT_MAX_CALL_DEPTH: usize = 256; // Nested CALLs allowed before overflowing
+const DEFAULT_COST: u32 = 1; // Cost of an opcode missing from `costs`
+
+/// Default opcode costs: multiplication and division are slow, memory and stack access cost 2
+fn default_costs() -> BTreeMap<u8, u32>
+{
+    let mul = [(0x32, 4)]; // MUL
+    let div = [(0x33, 8), (0x39, 8)]; // DIV, MOD
+                                      // OUTSTR, PUSH, POP, PUSHA, POPA, ENTER, LEAVE, LOAD through STSP, MEMCPY, FILL
+    let memory = [
+        0x0A, 0x10, 0x11, 0x14, 0x15, 0x16, 0x17, 0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28, 0x29,
+    ]
+    .map(|opcode| (opcode, 2));
+    mul.into_iter().chain(div).chain(memory).collect()
+}
+
+/// What the CPU does with an opcode byte it doesn't recognize.
+///
+/// The policy only applies once every registered
+/// [`InstructionHandler`] has declined the opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownPolicy
+{
+    /// Stop with `VMError::InvalidOpcode`
+    #[default]
+    Error,
+    /// Treat the byte as a one-byte `NOP` and carry on
+    Skip,
+    /// Stop as if a `HALT` had run, with exit code 0
+    Halt,
+}
 
 /// Configuration for the Virtual Machine
 ///
@@ -37,12 +87,24 @@ pub struct VMConfig
     pub debug: bool,
     /// Size of the stack region in bytes
     pub stack_size: usize,
+    /// Whether the stack grows down from the end of memory, or up from the start of its region
+    pub stack_grows_down: bool,
     /// Number of general-purpose registers available
     pub num_registers: usize,
     /// Starting address for the Program Counter
     pub pc_start: usize,
     /// Starting address for the Stack Pointer
     pub sp_start: usize,
+    /// Maximum number of instructions `run` may execute (`None` for unlimited)
+    pub max_cycles: Option<usize>,
+    /// Maximum number of nested subroutine calls
+    pub max_call_depth: usize,
+    /// What to do with an opcode byte no instruction or handler recognizes
+    pub on_unknown_opcode: UnknownPolicy,
+    /// Starting values for the first registers, r0 first; the rest start at zero
+    pub initial_registers: Vec<u8>,
+    /// Cost of each opcode byte, added up by [`CPU::cost`](cpu::CPU::cost); missing opcodes cost 1
+    pub costs: BTreeMap<u8, u32>,
 }
 
 impl Default for VMConfig
@@ -54,9 +116,15 @@ impl Default for VMConfig
             memory_size: DEFAULT_MEMORY_SIZE,
             debug: false,
             stack_size: DEFAULT_STACK_SIZE,
+            stack_grows_down: true,
             num_registers: DEFAULT_NUM_REGISTERS,
             pc_start: DEFAULT_PC_START,
             sp_start: DEFAULT_SP_START,
+            max_cycles: None,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            on_unknown_opcode: UnknownPolicy::default(),
+            initial_registers: Vec::new(),
+            costs: default_costs(),
         }
     }
 }
@@ -64,7 +132,7 @@ impl Default for VMConfig
 impl VMConfig
 {
     /// Creates a new VMConfig with custom memory size and debug setting
-    /// Other values are set to defaults
+    /// Other values are set to defaults, with the stack pointer starting at the top of memory
     ///
     /// # Arguments
     /// * `memory_size` - Total memory size in bytes
@@ -72,6 +140,7 @@ impl VMConfig
     ///
     /// # Example
     /// ```
+    /// # use vm::VMConfig;
     /// let config = VMConfig::new(512, true); // 512 bytes of memory, debug enabled
     /// ```
     pub fn new(memory_size: usize, debug: bool) -> Self
@@ -79,6 +148,7 @@ impl VMConfig
         Self {
             memory_size,
             debug,
+            sp_start: memory_size.saturating_sub(1),
             ..Default::default()
         }
     }
@@ -87,16 +157,65 @@ impl VMConfig
     ///
     /// # Example
     /// ```
+    /// # use vm::VMConfig;
     /// let config = VMConfig::builder()
     ///     .memory_size(512)
     ///     .debug(true)
     ///     .stack_size(128)
-    ///     .build();
+    ///     .build()
+    ///     .unwrap();
     /// ```
     pub fn builder() -> VMConfigBuilder
     {
         VMConfigBuilder::default()
     }
+
+    /// Checks that the configuration describes a usable machine
+    ///
+    /// # Returns
+    /// * `Ok(())` - If memory, stack, registers and start addresses are consistent
+    /// * `Err(VMConfigError)` - The first problem found
+    pub fn validate(&self) -> Result<(), VMConfigError>
+    {
+        if self.memory_size == 0 {
+            return Err(VMConfigError::EmptyMemory);
+        }
+        if self.stack_size > self.memory_size {
+            return Err(VMConfigError::StackTooLarge {
+                stack_size: self.stack_size,
+                memory_size: self.memory_size,
+            });
+        }
+        if self.num_registers == 0 {
+            return Err(VMConfigError::NoRegisters);
+        }
+        if self.pc_start >= self.memory_size {
+            return Err(VMConfigError::PcOutOfRange(self.pc_start));
+        }
+        if self.sp_start >= self.memory_size {
+            return Err(VMConfigError::SpOutOfRange(self.sp_start));
+        }
+        if self.initial_registers.len() > self.num_registers {
+            return Err(VMConfigError::TooManyInitialRegisters {
+                given: self.initial_registers.len(),
+                num_registers: self.num_registers,
+            });
+        }
+        Ok(())
+    }
+
+    /// Where the stack pointer starts, with the stack empty.
+    ///
+    /// The stack occupies the last `stack_size` bytes of memory. Growing down it
+    /// starts at the end of memory, growing up at the start of that region.
+    pub(crate) fn stack_start(&self) -> usize
+    {
+        if self.stack_grows_down {
+            self.memory_size
+        } else {
+            self.memory_size - self.stack_size
+        }
+    }
 }
 
 /// Builder for creating customized VM configurations
@@ -104,10 +223,14 @@ impl VMConfig
 /// This struct provides a fluent interface for setting up
 /// a VMConfig with custom values. Each method returns self
 /// to allow method chaining.
+///
+/// Unless `sp_start` is set explicitly, the stack pointer starts at the
+/// top of whatever memory size is configured.
 #[derive(Default)]
 pub struct VMConfigBuilder
 {
     config: VMConfig,
+    sp_start: Option<usize>,
 }
 
 impl VMConfigBuilder
@@ -133,6 +256,13 @@ impl VMConfigBuilder
         self
     }
 
+    /// Set whether the stack grows down (the default) or up
+    pub fn stack_grows_down(mut self, grows_down: bool) -> Self
+    {
+        self.config.stack_grows_down = grows_down;
+        self
+    }
+
     /// Set the number of general-purpose registers
     pub fn num_registers(mut self, num: usize) -> Self
     {
@@ -150,14 +280,54 @@ impl VMConfigBuilder
     /// Set the starting address for the Stack Pointer
     pub fn sp_start(mut self, addr: usize) -> Self
     {
-        self.config.sp_start = addr;
+        self.sp_start = Some(addr);
+        self
+    }
+
+    /// Set the maximum number of instructions `run` may execute
+    pub fn max_cycles(mut self, max: usize) -> Self
+    {
+        self.config.max_cycles = Some(max);
+        self
+    }
+
+    /// Set the maximum number of nested subroutine calls
+    pub fn max_call_depth(mut self, depth: usize) -> Self
+    {
+        self.config.max_call_depth = depth;
+        self
+    }
+
+    /// Set what happens when the CPU reaches an unknown opcode
+    pub fn on_unknown_opcode(mut self, policy: UnknownPolicy) -> Self
+    {
+        self.config.on_unknown_opcode = policy;
+        self
+    }
+
+    /// Set the starting values of the first registers, r0 first
+    pub fn initial_registers(mut self, values: Vec<u8>) -> Self
+    {
+        self.config.initial_registers = values;
+        self
+    }
+
+    /// Set the cost of an opcode byte, replacing its default
+    pub fn cost(mut self, opcode: u8, cost: u32) -> Self
+    {
+        self.config.costs.insert(opcode, cost);
         self
     }
 
     /// Build the final VMConfig with all settings applied
-    pub fn build(self) -> VMConfig
+    ///
+    /// # Returns
+    /// * `Ok(VMConfig)` - The validated configuration
+    /// * `Err(VMConfigError)` - If the settings are inconsistent (see [`VMConfig::validate`])
+    pub fn build(mut self) -> Result<VMConfig, VMConfigError>
     {
-        // TODO: Add validation of configuration values
-        self.config
+        self.config.sp_start = self.sp_start.unwrap_or(self.config.memory_size.saturating_sub(1));
+        self.config.validate()?;
+        Ok(self.config)
     }
 }
@@ -1,3 +1,7 @@
+use core::fmt;
+
+use super::error::VMError;
+
 /// Enum representing the different opcodes the VM can execute.
 /// Each opcode represents a specific instruction that the VM can process.
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -7,65 +11,422 @@ pub enum Opcode
     Inc(u8),
     Dec(u8),
     Out(u8),
+    Not(u8),
+    Neg(u8),
+    /// Output the zero-terminated string at the address held in a register
+    OutStr(u8),
 
     // Two register/value instructions
+    /// Load an immediate byte into a register
     Mov(u8, u8),
+    /// Load a two-byte immediate into a register
+    MovW(u8, u16),
+    /// Copy the value of the second register into the first
+    MovR(u8, u8),
+    Xchg(u8, u8),
     Add(u8, u8),
     Sub(u8, u8),
     Mul(u8, u8),
     Div(u8, u8),
+    And(u8, u8),
+    Or(u8, u8),
+    Xor(u8, u8),
+    Shl(u8, u8),
+    Shr(u8, u8),
+    Mod(u8, u8),
+    AddI(u8, u8),
+    SubI(u8, u8),
+    Adc(u8, u8),
     Cmp(u8, u8),
+    CmpI(u8, u8),
 
-    // Memory operations
-    Load(u8),
-    Store(u8),
-    LdIdx(u8),
-    StIdx(u8),
+    // Memory operations (register, address)
+    Load(u8, u8),
+    Store(u8, u8),
+    /// Load from `base + r1`
+    LdIdx(u8, u8),
+    /// Store to `base + r1`
+    StIdx(u8, u8),
+    LoadW(u8, u16),
+    StoreW(u8, u16),
+    /// Load from `sp + offset`
+    LdSp(u8, u8),
+    /// Store to `sp + offset`
+    StSp(u8, u8),
+    /// Copy `r2` bytes from the address in `r1` to the address in `r0`
+    MemCpy,
+    /// Write `r2` copies of the byte in `r1` starting at the address in `r0`
+    Fill,
 
     // Stack operations
     Push(u8),
     Pop(u8),
+    /// Push every register, r0 first
+    PushA,
+    /// Pop every register, restoring the values saved by `PushA`
+    PopA,
+    /// Push the base pointer, point it at the new frame and reserve this many bytes
+    Enter(u8),
+    /// Drop the current frame and restore the caller's base pointer
+    Leave,
 
-    // Control flow
-    Call,
+    // Control flow (target address)
+    Call(u8),
     Ret,
-    Jmp,
-    Jeq,
-    Jgt,
-    Jne,
+    Jmp(u8),
+    Jeq(u8),
+    Jgt(u8),
+    Jne(u8),
+    Jlt(u8),
+    Jle(u8),
+    Jge(u8),
+    Jc(u8),
+    Jnc(u8),
+    Js(u8),
+    Jns(u8),
+    /// Jump by a signed offset from the address of the next instruction
+    Jr(i8),
+    /// Jump to the address held in a register
+    JmpR(u8),
 
     // System
+    Nop,
     Halt,
+    /// Halt with an exit code
+    HaltCode(u8),
     Unknown(u8),
 }
 
+/// Decodes a lone opcode byte, with any operands treated as zero.
 impl From<u8> for Opcode
 {
     fn from(byte: u8) -> Self
     {
-        match byte {
-            0x01 => Opcode::Inc(0),
-            0x02 => Opcode::Dec(0),
-            0x03 => Opcode::Out(0),
-            0x04 => Opcode::Mov(0, 0),
-            0x10 => Opcode::Push(0),
-            0x11 => Opcode::Pop(0),
-            0x12 => Opcode::Call,
-            0x13 => Opcode::Ret,
-            0x20 => Opcode::Load(0),
-            0x21 => Opcode::Store(0),
-            0x22 => Opcode::LdIdx(0),
-            0x23 => Opcode::StIdx(0),
-            0x30 => Opcode::Add(0, 0),
-            0x31 => Opcode::Sub(0, 0),
-            0x32 => Opcode::Mul(0, 0),
-            0x33 => Opcode::Div(0, 0),
-            0x40 => Opcode::Jmp,
-            0x41 => Opcode::Jeq,
-            0x42 => Opcode::Jgt,
-            0x43 => Opcode::Cmp(0, 0),
-            0xFF => Opcode::Halt,
-            _ => Opcode::Unknown(byte),
+        let bytes = [byte, 0, 0, 0];
+        let (opcode, _) = Opcode::decode(&bytes, 0).expect("no instruction is longer than four bytes");
+        opcode
+    }
+}
+
+/// Renders the opcode in assembly syntax, e.g. `ADD r0, r1` or `MOV r0, 5`.
+///
+/// Addresses are shown in hex and other immediates in decimal. The alternate
+/// flag (`{:#}`) shows every immediate in hex too, as the disassembler does.
+impl fmt::Display for Opcode
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let hex = f.alternate();
+        match *self {
+            Opcode::Inc(reg) => write!(f, "INC r{}", reg),
+            Opcode::Dec(reg) => write!(f, "DEC r{}", reg),
+            Opcode::Out(reg) => write!(f, "OUT r{}", reg),
+            Opcode::Not(reg) => write!(f, "NOT r{}", reg),
+            Opcode::Neg(reg) => write!(f, "NEG r{}", reg),
+            Opcode::OutStr(reg) => write!(f, "OUTSTR r{}", reg),
+
+            Opcode::Mov(dst, imm) => write!(f, "MOV r{}, {}", dst, Imm(imm, hex)),
+            Opcode::MovW(dst, imm) => write!(f, "MOVW r{}, {}", dst, Imm(imm, hex)),
+            Opcode::MovR(dst, src) => write!(f, "MOVR r{}, r{}", dst, src),
+            Opcode::Xchg(a, b) => write!(f, "XCHG r{}, r{}", a, b),
+            Opcode::Add(dst, src) => write!(f, "ADD r{}, r{}", dst, src),
+            Opcode::Sub(dst, src) => write!(f, "SUB r{}, r{}", dst, src),
+            Opcode::Mul(dst, src) => write!(f, "MUL r{}, r{}", dst, src),
+            Opcode::Div(dst, src) => write!(f, "DIV r{}, r{}", dst, src),
+            Opcode::And(dst, src) => write!(f, "AND r{}, r{}", dst, src),
+            Opcode::Or(dst, src) => write!(f, "OR r{}, r{}", dst, src),
+            Opcode::Xor(dst, src) => write!(f, "XOR r{}, r{}", dst, src),
+            Opcode::Shl(dst, src) => write!(f, "SHL r{}, r{}", dst, src),
+            Opcode::Shr(dst, src) => write!(f, "SHR r{}, r{}", dst, src),
+            Opcode::Mod(dst, src) => write!(f, "MOD r{}, r{}", dst, src),
+            Opcode::AddI(dst, imm) => write!(f, "ADDI r{}, {}", dst, Imm(imm, hex)),
+            Opcode::SubI(dst, imm) => write!(f, "SUBI r{}, {}", dst, Imm(imm, hex)),
+            Opcode::Adc(dst, src) => write!(f, "ADC r{}, r{}", dst, src),
+            Opcode::Cmp(reg1, reg2) => write!(f, "CMP r{}, r{}", reg1, reg2),
+            Opcode::CmpI(reg, imm) => write!(f, "CMPI r{}, {}", reg, Imm(imm, hex)),
+
+            Opcode::Load(reg, addr) => write!(f, "LOAD r{}, 0x{:02X}", reg, addr),
+            Opcode::Store(reg, addr) => write!(f, "STORE r{}, 0x{:02X}", reg, addr),
+            Opcode::LdIdx(reg, addr) => write!(f, "LDIDX r{}, 0x{:02X}", reg, addr),
+            Opcode::StIdx(reg, addr) => write!(f, "STIDX r{}, 0x{:02X}", reg, addr),
+            Opcode::LoadW(reg, addr) => write!(f, "LOADW r{}, 0x{:04X}", reg, addr),
+            Opcode::StoreW(reg, addr) => write!(f, "STOREW r{}, 0x{:04X}", reg, addr),
+            Opcode::LdSp(reg, offset) => write!(f, "LDSP r{}, {}", reg, Imm(offset, hex)),
+            Opcode::StSp(reg, offset) => write!(f, "STSP r{}, {}", reg, Imm(offset, hex)),
+            Opcode::MemCpy => write!(f, "MEMCPY"),
+            Opcode::Fill => write!(f, "FILL"),
+
+            Opcode::Push(reg) => write!(f, "PUSH r{}", reg),
+            Opcode::Pop(reg) => write!(f, "POP r{}", reg),
+            Opcode::PushA => write!(f, "PUSHA"),
+            Opcode::PopA => write!(f, "POPA"),
+            Opcode::Enter(size) => write!(f, "ENTER {}", Imm(size, hex)),
+            Opcode::Leave => write!(f, "LEAVE"),
+
+            Opcode::Call(addr) => write!(f, "CALL 0x{:02X}", addr),
+            Opcode::Ret => write!(f, "RET"),
+            Opcode::Jmp(addr) => write!(f, "JMP 0x{:02X}", addr),
+            Opcode::Jeq(addr) => write!(f, "JEQ 0x{:02X}", addr),
+            Opcode::Jgt(addr) => write!(f, "JGT 0x{:02X}", addr),
+            Opcode::Jne(addr) => write!(f, "JNE 0x{:02X}", addr),
+            Opcode::Jlt(addr) => write!(f, "JLT 0x{:02X}", addr),
+            Opcode::Jle(addr) => write!(f, "JLE 0x{:02X}", addr),
+            Opcode::Jge(addr) => write!(f, "JGE 0x{:02X}", addr),
+            Opcode::Jc(addr) => write!(f, "JC 0x{:02X}", addr),
+            Opcode::Jnc(addr) => write!(f, "JNC 0x{:02X}", addr),
+            Opcode::Js(addr) => write!(f, "JS 0x{:02X}", addr),
+            Opcode::Jns(addr) => write!(f, "JNS 0x{:02X}", addr),
+            Opcode::Jr(offset) => write!(f, "JR {}", offset),
+            Opcode::JmpR(reg) => write!(f, "JMPR r{}", reg),
+
+            Opcode::Nop => write!(f, "NOP"),
+            Opcode::Halt => write!(f, "HALT"),
+            Opcode::HaltCode(code) => write!(f, "HALT {}", Imm(code, hex)),
+            Opcode::Unknown(byte) => write!(f, "; unknown 0x{:02X}", byte),
+        }
+    }
+}
+
+/// An immediate operand, shown in hex when `hex` is set and in decimal otherwise.
+struct Imm<T>(T, bool);
+
+impl<T: fmt::Display + fmt::UpperHex> fmt::Display for Imm<T>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        if self.1 {
+            write!(f, "0x{:01$X}", self.0, 2 * core::mem::size_of::<T>())
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+impl Opcode
+{
+    /// Decodes the instruction that starts at `pc`.
+    ///
+    /// This is the only decoder in the VM: `CPU::fetch`, the disassembler and
+    /// `From<u8>` all go through it, so they can't disagree about an encoding.
+    ///
+    /// # Returns
+    /// * `(Opcode, usize)` - The decoded opcode and the number of bytes it occupies
+    /// * `Err(VMError::InvalidMemoryAccess)` - If the instruction is truncated
+    pub fn decode(bytes: &[u8], pc: usize) -> Result<(Opcode, usize), VMError>
+    {
+        let operand = |offset: usize| {
+            bytes
+                .get(pc + offset)
+                .copied()
+                .ok_or(VMError::InvalidMemoryAccess(pc + offset))
+        };
+
+        let opcode_byte = operand(0)?;
+
+        let decoded = match opcode_byte {
+            0x00 => (Opcode::Nop, 1),
+
+            // Single register instructions
+            0x01..=0x03 | 0x05 | 0x08 | 0x0A | 0x4D => {
+                let reg = operand(1)?;
+                let opcode = match opcode_byte {
+                    0x01 => Opcode::Inc(reg),
+                    0x02 => Opcode::Dec(reg),
+                    0x03 => Opcode::Out(reg),
+                    0x05 => Opcode::Not(reg),
+                    0x08 => Opcode::Neg(reg),
+                    0x0A => Opcode::OutStr(reg),
+                    0x4D => Opcode::JmpR(reg),
+                    _ => unreachable!(),
+                };
+                (opcode, 2)
+            }
+
+            // Two register (or register and immediate) instructions
+            0x04 | 0x07 | 0x09 | 0x30..=0x3D | 0x43 => {
+                let dst = operand(1)?;
+                let src = operand(2)?;
+                let opcode = match opcode_byte {
+                    0x04 => Opcode::Mov(dst, src),
+                    0x07 => Opcode::Xchg(dst, src),
+                    0x09 => Opcode::MovR(dst, src),
+                    0x30 => Opcode::Add(dst, src),
+                    0x31 => Opcode::Sub(dst, src),
+                    0x32 => Opcode::Mul(dst, src),
+                    0x33 => Opcode::Div(dst, src),
+                    0x34 => Opcode::And(dst, src),
+                    0x35 => Opcode::Or(dst, src),
+                    0x36 => Opcode::Xor(dst, src),
+                    0x37 => Opcode::Shl(dst, src),
+                    0x38 => Opcode::Shr(dst, src),
+                    0x39 => Opcode::Mod(dst, src),
+                    0x3A => Opcode::AddI(dst, src),
+                    0x3B => Opcode::SubI(dst, src),
+                    0x3C => Opcode::Adc(dst, src),
+                    0x3D => Opcode::CmpI(dst, src),
+                    0x43 => Opcode::Cmp(dst, src),
+                    _ => unreachable!(),
+                };
+                (opcode, 3)
+            }
+
+            // Register and two-byte little-endian immediate
+            0x06 => {
+                let dst = operand(1)?;
+                let imm = u16::from_le_bytes([operand(2)?, operand(3)?]);
+                (Opcode::MovW(dst, imm), 4)
+            }
+
+            // Register and memory address (or stack offset) instructions
+            0x20..=0x23 | 0x26..=0x27 => {
+                let reg = operand(1)?;
+                let addr = operand(2)?;
+                let opcode = match opcode_byte {
+                    0x20 => Opcode::Load(reg, addr),
+                    0x21 => Opcode::Store(reg, addr),
+                    0x22 => Opcode::LdIdx(reg, addr),
+                    0x23 => Opcode::StIdx(reg, addr),
+                    0x26 => Opcode::LdSp(reg, addr),
+                    0x27 => Opcode::StSp(reg, addr),
+                    _ => unreachable!(),
+                };
+                (opcode, 3)
+            }
+
+            // Register and two-byte little-endian address instructions
+            0x24..=0x25 => {
+                let reg = operand(1)?;
+                let addr = u16::from_le_bytes([operand(2)?, operand(3)?]);
+                let opcode = match opcode_byte {
+                    0x24 => Opcode::LoadW(reg, addr),
+                    0x25 => Opcode::StoreW(reg, addr),
+                    _ => unreachable!(),
+                };
+                (opcode, 4)
+            }
+
+            // Jump instructions
+            0x40..=0x49 | 0x4B..=0x4C => {
+                let addr = operand(1)?;
+                let opcode = match opcode_byte {
+                    0x40 => Opcode::Jmp(addr),
+                    0x41 => Opcode::Jeq(addr),
+                    0x42 => Opcode::Jgt(addr),
+                    0x44 => Opcode::Jne(addr),
+                    0x45 => Opcode::Jlt(addr),
+                    0x46 => Opcode::Jle(addr),
+                    0x47 => Opcode::Jge(addr),
+                    0x48 => Opcode::Jc(addr),
+                    0x49 => Opcode::Jnc(addr),
+                    0x4B => Opcode::Js(addr),
+                    0x4C => Opcode::Jns(addr),
+                    _ => unreachable!(),
+                };
+                (opcode, 2)
+            }
+
+            // Stack operations
+            0x10..=0x11 => {
+                let reg = operand(1)?;
+                let opcode = match opcode_byte {
+                    0x10 => Opcode::Push(reg),
+                    0x11 => Opcode::Pop(reg),
+                    _ => unreachable!(),
+                };
+                (opcode, 2)
+            }
+
+            // Block memory operations, with operands in r0-r2
+            0x28 => (Opcode::MemCpy, 1),
+            0x29 => (Opcode::Fill, 1),
+
+            // Relative jump (signed offset)
+            0x4A => (Opcode::Jr(operand(1)? as i8), 2),
+
+            // Function calls
+            0x12 => (Opcode::Call(operand(1)?), 2),
+            0x13 => (Opcode::Ret, 1),
+
+            // Whole register file stack operations
+            0x14 => (Opcode::PushA, 1),
+            0x15 => (Opcode::PopA, 1),
+
+            // Stack frames
+            0x16 => (Opcode::Enter(operand(1)?), 2),
+            0x17 => (Opcode::Leave, 1),
+
+            0xFE => (Opcode::HaltCode(operand(1)?), 2),
+            0xFF => (Opcode::Halt, 1),
+            _ => (Opcode::Unknown(opcode_byte), 1),
+        };
+
+        Ok(decoded)
+    }
+
+    /// Returns the opcode byte this instruction is encoded with, the first byte [`Opcode::decode`] reads.
+    pub fn byte(self) -> u8
+    {
+        match self {
+            Opcode::Nop => 0x00,
+            Opcode::Inc(_) => 0x01,
+            Opcode::Dec(_) => 0x02,
+            Opcode::Out(_) => 0x03,
+            Opcode::Mov(..) => 0x04,
+            Opcode::Not(_) => 0x05,
+            Opcode::MovW(..) => 0x06,
+            Opcode::Xchg(..) => 0x07,
+            Opcode::Neg(_) => 0x08,
+            Opcode::MovR(..) => 0x09,
+            Opcode::OutStr(_) => 0x0A,
+            Opcode::Push(_) => 0x10,
+            Opcode::Pop(_) => 0x11,
+            Opcode::Call(_) => 0x12,
+            Opcode::Ret => 0x13,
+            Opcode::PushA => 0x14,
+            Opcode::PopA => 0x15,
+            Opcode::Enter(_) => 0x16,
+            Opcode::Leave => 0x17,
+            Opcode::Load(..) => 0x20,
+            Opcode::Store(..) => 0x21,
+            Opcode::LdIdx(..) => 0x22,
+            Opcode::StIdx(..) => 0x23,
+            Opcode::LoadW(..) => 0x24,
+            Opcode::StoreW(..) => 0x25,
+            Opcode::LdSp(..) => 0x26,
+            Opcode::StSp(..) => 0x27,
+            Opcode::MemCpy => 0x28,
+            Opcode::Fill => 0x29,
+            Opcode::Add(..) => 0x30,
+            Opcode::Sub(..) => 0x31,
+            Opcode::Mul(..) => 0x32,
+            Opcode::Div(..) => 0x33,
+            Opcode::And(..) => 0x34,
+            Opcode::Or(..) => 0x35,
+            Opcode::Xor(..) => 0x36,
+            Opcode::Shl(..) => 0x37,
+            Opcode::Shr(..) => 0x38,
+            Opcode::Mod(..) => 0x39,
+            Opcode::AddI(..) => 0x3A,
+            Opcode::SubI(..) => 0x3B,
+            Opcode::Adc(..) => 0x3C,
+            Opcode::CmpI(..) => 0x3D,
+            Opcode::Jmp(_) => 0x40,
+            Opcode::Jeq(_) => 0x41,
+            Opcode::Jgt(_) => 0x42,
+            Opcode::Cmp(..) => 0x43,
+            Opcode::Jne(_) => 0x44,
+            Opcode::Jlt(_) => 0x45,
+            Opcode::Jle(_) => 0x46,
+            Opcode::Jge(_) => 0x47,
+            Opcode::Jc(_) => 0x48,
+            Opcode::Jnc(_) => 0x49,
+            Opcode::Jr(_) => 0x4A,
+            Opcode::Js(_) => 0x4B,
+            Opcode::Jns(_) => 0x4C,
+            Opcode::JmpR(_) => 0x4D,
+            Opcode::HaltCode(_) => 0xFE,
+            Opcode::Halt => 0xFF,
+            Opcode::Unknown(byte) => byte,
         }
     }
 }
@@ -0,0 +1,54 @@
+//! Output sinks
+//!
+//! `OUT` writes through the [`Output`] trait instead of `std::io::Write`, so the
+//! CPU can run without the standard library. With the `std` feature every
+//! `std::io::Write` type is an `Output`, including stdout, files and `Vec<u8>`.
+
+use alloc::string::String;
+#[cfg(feature = "std")]
+use alloc::string::ToString;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+/// Somewhere the CPU can send the text produced by `OUT`.
+pub trait Output
+{
+    /// Writes all of `bytes`.
+    ///
+    /// # Returns
+    /// * `Err(String)` - A description of why the bytes couldn't be written
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), String>;
+}
+
+#[cfg(feature = "std")]
+impl<T: std::io::Write + ?Sized> Output for T
+{
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), String>
+    {
+        self.write_all(bytes).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Output for Vec<u8>
+{
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), String>
+    {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// An output sink that throws everything away.
+///
+/// This is where `OUT` writes by default when the `std` feature is disabled.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Discard;
+
+impl Output for Discard
+{
+    fn write_bytes(&mut self, _bytes: &[u8]) -> Result<(), String>
+    {
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+//! JSON execution traces
+//!
+//! [`CPU::set_json_trace`](super::cpu::CPU::set_json_trace) turns every executed
+//! instruction into a single-line JSON object, for tools that visualize a run:
+//!
+//! ```text
+//! {"pc":0,"opcode":"MOV","operands":["r0","5"],"registers":{"r0":5},"flags":null}
+//! ```
+//!
+//! `registers` holds only the registers the instruction changed, and `flags` is
+//! the new flags value, or `null` if they didn't change.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use super::cpu::{TraceEvent, TraceHook};
+use super::opcode::Opcode;
+use super::word::Word;
+
+/// Builds a trace hook that sends a JSON record for each instruction to `sink`.
+///
+/// # Arguments
+/// * `registers` - Register values before the first traced instruction
+/// * `flags` - Flags before the first traced instruction
+/// * `sink` - Receives each record
+pub(crate) fn json_trace_hook<W: Word>(
+    mut registers: Vec<W>,
+    mut flags: u8,
+    mut sink: impl FnMut(String) + 'static,
+) -> TraceHook<W>
+{
+    Box::new(move |event: &TraceEvent<W>| {
+        sink(json_record(event, &registers, flags));
+        registers.clone_from(&event.registers);
+        flags = event.flags;
+    })
+}
+
+/// Formats one instruction as a JSON object, comparing against the state before it ran.
+fn json_record<W: Word>(event: &TraceEvent<W>, previous: &[W], previous_flags: u8) -> String
+{
+    let (name, operands) = match event.opcode {
+        Opcode::Unknown(byte) => (String::from("UNKNOWN"), alloc::vec![format!("0x{:02X}", byte)]),
+        opcode => {
+            let text = format!("{}", opcode);
+            match text.split_once(' ') {
+                Some((name, operands)) => (name.into(), operands.split(", ").map(String::from).collect()),
+                None => (text, Vec::new()),
+            }
+        }
+    };
+
+    let mut record = format!("{{\"pc\":{},\"opcode\":\"{}\",\"operands\":[", event.pc, name);
+    for (index, operand) in operands.iter().enumerate() {
+        let separator = if index == 0 { "" } else { "," };
+        write!(record, "{}\"{}\"", separator, operand).unwrap();
+    }
+
+    record.push_str("],\"registers\":{");
+    let changed = event
+        .registers
+        .iter()
+        .enumerate()
+        .filter(|&(index, value)| previous.get(index) != Some(value));
+    for (count, (index, value)) in changed.enumerate() {
+        let separator = if count == 0 { "" } else { "," };
+        write!(record, "{}\"r{}\":{}", separator, index, value).unwrap();
+    }
+
+    if event.flags == previous_flags {
+        record.push_str("},\"flags\":null}");
+    } else {
+        write!(record, "}},\"flags\":{}}}", event.flags).unwrap();
+    }
+    record
+}
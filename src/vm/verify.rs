@@ -0,0 +1,238 @@
+//! Static program verifier
+//!
+//! [`verify`] walks a program with [`Opcode::decode`] and reports the faults
+//! that are visible without running it: registers the VM doesn't have,
+//! addresses outside memory, unknown opcodes and a truncated last instruction.
+//! It's meant as a gate before running bytecode from an untrusted source.
+//!
+//! Only code reachable from address 0 is checked: the walk follows fall-through
+//! and the static targets of `CALL`, `JR` and the jumps, and stops at `HALT`,
+//! `RET`, `JMP` and `JMPR`. Bytes nothing jumps to, such as string data after
+//! the last `HALT`, are left alone.
+//!
+//! Anything that depends on register values at run time, such as the target of
+//! `JMPR` or the effective address of `LDIDX`, can only be checked by running
+//! the program. Code only reachable through `JMPR` isn't checked.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::error::VMError;
+use super::opcode::Opcode;
+use super::word::Word;
+use super::{UnknownPolicy, VMConfig};
+
+/// Checks a program for faults the VM would hit when running it, without executing it.
+///
+/// Every reachable instruction is checked, so the errors cover the whole program
+/// rather than stopping at the first fault. The program is assumed to be loaded at
+/// address 0, as [`CPU::load_program`](super::cpu::CPU::load_program) does.
+///
+/// Memory accesses are checked for the 8-bit [`CPU`](super::cpu::CPU); use
+/// [`verify_for`] with the word type of the machine for wider words.
+///
+/// Unknown opcodes are reported unless `config.on_unknown_opcode` skips or halts
+/// on them, even though an [`InstructionHandler`](super::InstructionHandler)
+/// might implement them.
+///
+/// # Arguments
+/// * `bytecode` - The program to check
+/// * `config` - The machine the program will run on
+///
+/// # Returns
+/// * `Ok(())` - If no fault was found
+/// * `Err(Vec<VMError>)` - Every fault found, in address order:
+///   * `VMError::InvalidConfig` - If the configuration itself is invalid
+///   * `VMError::ProgramTooLarge` - If the program doesn't fit in memory
+///   * `VMError::InvalidRegister` - If an instruction names a register the VM doesn't have
+///   * `VMError::InvalidMemoryAccess` - If an accessed word or jump target is outside memory,
+///     or the last instruction is missing operand bytes
+///   * `VMError::InvalidOpcode` - If a byte isn't a known opcode and the policy is `UnknownPolicy::Error`
+///
+/// # Example
+/// ```
+/// # use vm::{verify, VMConfig, VMError};
+/// let config = VMConfig::default();
+/// assert!(verify(&[0x04, 0x00, 0x05, 0xFF], &config).is_ok());
+///
+/// // MOV r9, 5 on a machine with eight registers
+/// let errors = verify(&[0x04, 0x09, 0x05, 0xFF], &config).unwrap_err();
+/// assert!(matches!(errors[..], [VMError::InvalidRegister(9)]));
+/// ```
+pub fn verify(bytecode: &[u8], config: &VMConfig) -> Result<(), Vec<VMError>>
+{
+    verify_for::<u8>(bytecode, config)
+}
+
+/// Checks a program for a machine with `W`-sized words, such as [`CPU16`](crate::CPU16).
+///
+/// Same as [`verify`], except that `LOAD`, `STORE`, `LOADW`, `STOREW` and the
+/// indexed accesses must fit a whole `W` in memory.
+///
+/// # Example
+/// ```
+/// # use vm::{verify, verify_for, VMConfig, VMError};
+/// let config = VMConfig::builder().memory_size(64).build().unwrap();
+///
+/// // LOADW r0, 63 reads one byte on an 8-bit machine, two on a 16-bit one
+/// let program = [0x24, 0x00, 0x3F, 0x00, 0xFF];
+/// assert!(verify(&program, &config).is_ok());
+/// let errors = verify_for::<u16>(&program, &config).unwrap_err();
+/// assert!(matches!(errors[..], [VMError::InvalidMemoryAccess(63)]));
+/// ```
+pub fn verify_for<W: Word>(bytecode: &[u8], config: &VMConfig) -> Result<(), Vec<VMError>>
+{
+    if let Err(e) = config.validate() {
+        return Err(vec![e.into()]);
+    }
+
+    let mut errors = Vec::new();
+    if bytecode.len() > config.memory_size {
+        errors.push(VMError::ProgramTooLarge {
+            size: bytecode.len(),
+            capacity: config.memory_size,
+        });
+    }
+
+    // Faults found along the walk, keyed by the address of the instruction
+    let mut found: Vec<(usize, VMError)> = Vec::new();
+    let mut visited = vec![false; bytecode.len()];
+    let mut pending = vec![0];
+
+    while let Some(pc) = pending.pop() {
+        if pc >= bytecode.len() || visited[pc] {
+            continue;
+        }
+        visited[pc] = true;
+
+        let (opcode, size) = match Opcode::decode(bytecode, pc) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                found.push((pc, e));
+                continue;
+            }
+        };
+        let next = pc + size;
+
+        for reg in registers(&opcode) {
+            if reg as usize >= config.num_registers {
+                found.push((pc, VMError::InvalidRegister(reg as usize)));
+            }
+        }
+
+        // A word access needs every byte of the word in memory, as the CPU checks it
+        let mut check_word = |addr: usize| {
+            if addr + W::BYTES > config.memory_size {
+                found.push((pc, VMError::InvalidMemoryAccess(addr)));
+            }
+        };
+
+        let mut falls_through = true;
+        match opcode {
+            Opcode::Load(_, addr) | Opcode::Store(_, addr) | Opcode::LdIdx(_, addr) | Opcode::StIdx(_, addr) => {
+                check_word(addr as usize)
+            }
+            Opcode::LoadW(_, addr) | Opcode::StoreW(_, addr) => check_word(addr as usize),
+
+            Opcode::Call(addr)
+            | Opcode::Jmp(addr)
+            | Opcode::Jeq(addr)
+            | Opcode::Jgt(addr)
+            | Opcode::Jne(addr)
+            | Opcode::Jlt(addr)
+            | Opcode::Jle(addr)
+            | Opcode::Jge(addr)
+            | Opcode::Jc(addr)
+            | Opcode::Jnc(addr)
+            | Opcode::Js(addr)
+            | Opcode::Jns(addr) => {
+                let target = addr as usize;
+                if target >= config.memory_size {
+                    found.push((pc, VMError::InvalidMemoryAccess(target)));
+                } else {
+                    pending.push(target);
+                }
+                falls_through = !matches!(opcode, Opcode::Jmp(_));
+            }
+
+            // The offset is relative to the instruction after the jump
+            Opcode::Jr(offset) => match next.checked_add_signed(offset as isize) {
+                Some(target) if target < config.memory_size => pending.push(target),
+                Some(target) => found.push((pc, VMError::InvalidMemoryAccess(target))),
+                None => found.push((pc, VMError::InvalidMemoryAccess(next))),
+            },
+
+            Opcode::Halt | Opcode::HaltCode(_) | Opcode::Ret | Opcode::JmpR(_) => falls_through = false,
+
+            Opcode::Unknown(byte) => match config.on_unknown_opcode {
+                UnknownPolicy::Error => found.push((pc, VMError::InvalidOpcode(byte))),
+                UnknownPolicy::Halt => falls_through = false,
+                UnknownPolicy::Skip => {}
+            },
+            _ => {}
+        }
+
+        if falls_through {
+            pending.push(next);
+        }
+    }
+
+    // The walk visits branches out of order; report faults by address
+    found.sort_by_key(|(pc, _)| *pc);
+    errors.extend(found.into_iter().map(|(_, e)| e));
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Every register an instruction reads or writes, including implicit ones.
+fn registers(opcode: &Opcode) -> Vec<u8>
+{
+    match *opcode {
+        Opcode::Inc(reg)
+        | Opcode::Dec(reg)
+        | Opcode::Out(reg)
+        | Opcode::Not(reg)
+        | Opcode::Neg(reg)
+        | Opcode::OutStr(reg)
+        | Opcode::Mov(reg, _)
+        | Opcode::MovW(reg, _)
+        | Opcode::AddI(reg, _)
+        | Opcode::SubI(reg, _)
+        | Opcode::CmpI(reg, _)
+        | Opcode::Load(reg, _)
+        | Opcode::Store(reg, _)
+        | Opcode::LoadW(reg, _)
+        | Opcode::StoreW(reg, _)
+        | Opcode::LdSp(reg, _)
+        | Opcode::StSp(reg, _)
+        | Opcode::Push(reg)
+        | Opcode::Pop(reg)
+        | Opcode::JmpR(reg) => vec![reg],
+
+        Opcode::MovR(a, b)
+        | Opcode::Xchg(a, b)
+        | Opcode::Add(a, b)
+        | Opcode::Sub(a, b)
+        | Opcode::Mul(a, b)
+        | Opcode::Div(a, b)
+        | Opcode::And(a, b)
+        | Opcode::Or(a, b)
+        | Opcode::Xor(a, b)
+        | Opcode::Shl(a, b)
+        | Opcode::Shr(a, b)
+        | Opcode::Mod(a, b)
+        | Opcode::Adc(a, b)
+        | Opcode::Cmp(a, b) => vec![a, b],
+
+        // Indexed accesses add r1 to the base address
+        Opcode::LdIdx(reg, _) | Opcode::StIdx(reg, _) => vec![reg, 1],
+        // Block operations take their operands from r0-r2
+        Opcode::MemCpy | Opcode::Fill => vec![0, 1, 2],
+
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,158 @@
+//! Register word types
+//!
+//! The CPU is generic over the type held in its registers. `u8` gives the
+//! classic 8-bit machine, while `u16` lets counters and values go past 255.
+//! Memory stays byte-addressed; wider words are stored little-endian.
+
+use core::fmt::{Debug, Display};
+use core::ops::{BitAnd, BitOr, BitXor, Not};
+
+/// An unsigned integer type that can be used as the CPU's register word.
+pub trait Word:
+    Copy
+    + Default
+    + Debug
+    + Display
+    + PartialEq
+    + PartialOrd
+    + BitAnd<Output = Self>
+    + BitOr<Output = Self>
+    + BitXor<Output = Self>
+    + Not<Output = Self>
+    + 'static
+{
+    /// Number of bytes a word occupies in memory
+    const BYTES: usize;
+
+    /// Widens a byte into a word
+    fn from_u8(value: u8) -> Self;
+
+    /// Converts a 16-bit value into a word, keeping only the low bits if it doesn't fit
+    fn from_u16(value: u16) -> Self;
+
+    /// Converts the word into an address or shift amount
+    fn to_usize(self) -> usize;
+
+    /// Whether the high bit is set, i.e. the word is negative as a signed value
+    fn is_negative(self) -> bool;
+
+    /// Reads a little-endian word from exactly `Self::BYTES` bytes
+    fn read_le(bytes: &[u8]) -> Self;
+
+    /// Writes the word little-endian into exactly `Self::BYTES` bytes
+    fn write_le(self, bytes: &mut [u8]);
+
+    /// Adds, returning the wrapped sum and whether it carried out of the word
+    fn overflowing_add(self, rhs: Self) -> (Self, bool);
+
+    /// Subtracts, returning the wrapped difference and whether it borrowed
+    fn overflowing_sub(self, rhs: Self) -> (Self, bool);
+
+    /// Adds, wrapping around at the word size
+    fn wrapping_add(self, rhs: Self) -> Self;
+
+    /// Subtracts, wrapping around at the word size
+    fn wrapping_sub(self, rhs: Self) -> Self;
+
+    /// Multiplies, keeping only the low bits of the product
+    fn wrapping_mul(self, rhs: Self) -> Self;
+
+    /// Divides; the caller checks for a zero divisor
+    fn wrapping_div(self, rhs: Self) -> Self;
+
+    /// Takes the remainder; the caller checks for a zero divisor
+    fn wrapping_rem(self, rhs: Self) -> Self;
+
+    /// Shifts left, with the amount taken modulo the word's bit width
+    fn wrapping_shl(self, amount: u32) -> Self;
+
+    /// Shifts right, with the amount taken modulo the word's bit width
+    fn wrapping_shr(self, amount: u32) -> Self;
+}
+
+macro_rules! impl_word {
+    ($t:ty) => {
+        impl Word for $t
+        {
+            const BYTES: usize = core::mem::size_of::<$t>();
+
+            fn from_u8(value: u8) -> Self
+            {
+                value as $t
+            }
+
+            fn from_u16(value: u16) -> Self
+            {
+                value as $t
+            }
+
+            fn to_usize(self) -> usize
+            {
+                self as usize
+            }
+
+            fn is_negative(self) -> bool
+            {
+                self >> (<$t>::BITS - 1) != 0
+            }
+
+            fn read_le(bytes: &[u8]) -> Self
+            {
+                <$t>::from_le_bytes(bytes.try_into().expect("word read with the wrong number of bytes"))
+            }
+
+            fn write_le(self, bytes: &mut [u8])
+            {
+                bytes.copy_from_slice(&self.to_le_bytes());
+            }
+
+            fn overflowing_add(self, rhs: Self) -> (Self, bool)
+            {
+                <$t>::overflowing_add(self, rhs)
+            }
+
+            fn overflowing_sub(self, rhs: Self) -> (Self, bool)
+            {
+                <$t>::overflowing_sub(self, rhs)
+            }
+
+            fn wrapping_add(self, rhs: Self) -> Self
+            {
+                <$t>::wrapping_add(self, rhs)
+            }
+
+            fn wrapping_sub(self, rhs: Self) -> Self
+            {
+                <$t>::wrapping_sub(self, rhs)
+            }
+
+            fn wrapping_mul(self, rhs: Self) -> Self
+            {
+                <$t>::wrapping_mul(self, rhs)
+            }
+
+            fn wrapping_div(self, rhs: Self) -> Self
+            {
+                <$t>::wrapping_div(self, rhs)
+            }
+
+            fn wrapping_rem(self, rhs: Self) -> Self
+            {
+                <$t>::wrapping_rem(self, rhs)
+            }
+
+            fn wrapping_shl(self, amount: u32) -> Self
+            {
+                <$t>::wrapping_shl(self, amount)
+            }
+
+            fn wrapping_shr(self, amount: u32) -> Self
+            {
+                <$t>::wrapping_shr(self, amount)
+            }
+        }
+    };
+}
+
+impl_word!(u8);
+impl_word!(u16);
@@ -0,0 +1,35 @@
+// Tests for the VM core that also run with `--no-default-features`,
+// i.e. without the assembler or any standard library I/O.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use vm::{Output, RunOutcome, VMConfig, CPU};
+
+/// An output sink that implements only the crate's own `Output` trait.
+#[derive(Clone, Default)]
+struct MemorySink(Rc<RefCell<Vec<u8>>>);
+
+impl Output for MemorySink
+{
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), String>
+    {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_out_writes_to_memory_sink()
+{
+    // MOV r0, 5; MOV r1, 3; ADD r0, r1; OUT r0; HALT
+    let program = [0x04, 0x00, 0x05, 0x04, 0x01, 0x03, 0x30, 0x00, 0x01, 0x03, 0x00, 0xFF];
+
+    let sink = MemorySink::default();
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.set_output(Box::new(sink.clone()));
+    vm.load_program(&program).unwrap();
+
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(sink.0.borrow().as_slice(), b"8\n");
+}
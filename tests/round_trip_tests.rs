@@ -0,0 +1,152 @@
+// Round-trip tests between the assembler, the decoder and the disassembler.
+// They need the assembler, which needs the standard library.
+#![cfg(feature = "std")]
+
+use vm::assembler::Assembler;
+use vm::{disassemble, instructions, Opcode};
+
+/// Number of operand combinations tried for each instruction
+const ROUNDS: usize = 64;
+
+/// A small xorshift generator, so every run tries the same operands.
+struct Operands(u32);
+
+impl Operands
+{
+    fn next(&mut self) -> u32
+    {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    fn reg(&mut self) -> u8
+    {
+        (self.next() % 8) as u8
+    }
+
+    fn byte(&mut self) -> u8
+    {
+        self.next() as u8
+    }
+
+    fn wide(&mut self) -> u16
+    {
+        self.next() as u16
+    }
+}
+
+/// One instruction of every kind the assembler can produce, with operands from `ops`.
+fn every_instruction(ops: &mut Operands) -> Vec<Opcode>
+{
+    vec![
+        Opcode::Nop,
+        Opcode::Inc(ops.reg()),
+        Opcode::Dec(ops.reg()),
+        Opcode::Out(ops.reg()),
+        Opcode::Mov(ops.reg(), ops.byte()),
+        Opcode::Not(ops.reg()),
+        Opcode::MovW(ops.reg(), ops.wide()),
+        Opcode::Xchg(ops.reg(), ops.reg()),
+        Opcode::Neg(ops.reg()),
+        Opcode::MovR(ops.reg(), ops.reg()),
+        Opcode::OutStr(ops.reg()),
+        Opcode::Push(ops.reg()),
+        Opcode::Pop(ops.reg()),
+        Opcode::Call(ops.byte()),
+        Opcode::Ret,
+        Opcode::PushA,
+        Opcode::PopA,
+        Opcode::Enter(ops.byte()),
+        Opcode::Leave,
+        Opcode::Load(ops.reg(), ops.byte()),
+        Opcode::Store(ops.reg(), ops.byte()),
+        Opcode::LdIdx(ops.reg(), ops.byte()),
+        Opcode::StIdx(ops.reg(), ops.byte()),
+        Opcode::LoadW(ops.reg(), ops.wide()),
+        Opcode::StoreW(ops.reg(), ops.wide()),
+        Opcode::LdSp(ops.reg(), ops.byte()),
+        Opcode::StSp(ops.reg(), ops.byte()),
+        Opcode::MemCpy,
+        Opcode::Fill,
+        Opcode::Add(ops.reg(), ops.reg()),
+        Opcode::Sub(ops.reg(), ops.reg()),
+        Opcode::Mul(ops.reg(), ops.reg()),
+        Opcode::Div(ops.reg(), ops.reg()),
+        Opcode::And(ops.reg(), ops.reg()),
+        Opcode::Or(ops.reg(), ops.reg()),
+        Opcode::Xor(ops.reg(), ops.reg()),
+        Opcode::Shl(ops.reg(), ops.reg()),
+        Opcode::Shr(ops.reg(), ops.reg()),
+        Opcode::Mod(ops.reg(), ops.reg()),
+        Opcode::AddI(ops.reg(), ops.byte()),
+        Opcode::SubI(ops.reg(), ops.byte()),
+        Opcode::Adc(ops.reg(), ops.reg()),
+        Opcode::CmpI(ops.reg(), ops.byte()),
+        Opcode::Cmp(ops.reg(), ops.reg()),
+        Opcode::Jmp(ops.byte()),
+        Opcode::Jeq(ops.byte()),
+        Opcode::Jgt(ops.byte()),
+        Opcode::Jne(ops.byte()),
+        Opcode::Jlt(ops.byte()),
+        Opcode::Jle(ops.byte()),
+        Opcode::Jge(ops.byte()),
+        Opcode::Jc(ops.byte()),
+        Opcode::Jnc(ops.byte()),
+        Opcode::Jr(ops.byte() as i8),
+        Opcode::JmpR(ops.reg()),
+        Opcode::Js(ops.byte()),
+        Opcode::Jns(ops.byte()),
+        Opcode::HaltCode(ops.byte()),
+        Opcode::Halt,
+    ]
+}
+
+#[test]
+fn test_encode_decode_round_trip()
+{
+    let mut ops = Operands(0x2545_F491);
+
+    for _ in 0..ROUNDS {
+        for opcode in every_instruction(&mut ops) {
+            let source = opcode.to_string();
+            let bytecode = Assembler::new()
+                .assemble(&source)
+                .unwrap_or_else(|e| panic!("{}: {}", source, e));
+
+            // The decoder gives back the instruction and consumes every encoded byte
+            let (decoded, size) = Opcode::decode(&bytecode, 0).unwrap();
+            assert_eq!(decoded, opcode, "{}", source);
+            assert_eq!(size, bytecode.len(), "{}", source);
+            assert_eq!(decoded.byte(), bytecode[0], "{}", source);
+
+            // The alternate form, with immediates in hex, assembles to the same bytes
+            let hex = format!("{:#}", opcode);
+            assert_eq!(Assembler::new().assemble(&hex).unwrap(), bytecode, "{}", hex);
+
+            // The next instruction starts right after it, as it does for `CPU::fetch`
+            let mut program = bytecode.clone();
+            program.push(0xFF);
+            let addresses: Vec<_> = instructions(&program).map(|inst| inst.unwrap().0).collect();
+            assert_eq!(addresses, vec![0, bytecode.len()], "{}", source);
+        }
+    }
+}
+
+#[test]
+fn test_disassembly_reassembles()
+{
+    let mut ops = Operands(0x9E37_79B9);
+
+    for _ in 0..ROUNDS {
+        let bytecode: Vec<u8> = every_instruction(&mut ops)
+            .iter()
+            .flat_map(|opcode| Assembler::new().assemble(&opcode.to_string()).unwrap())
+            .collect();
+
+        let source = disassemble(&bytecode).unwrap();
+        let reassembled = Assembler::new().assemble(&source).unwrap();
+        assert_eq!(reassembled, bytecode);
+    }
+}
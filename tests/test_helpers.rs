@@ -2,7 +2,7 @@ use vm::{VMConfig, CPU};
 
 pub fn create_test_vm() -> CPU
 {
-    CPU::new(VMConfig::default())
+    CPU::new(VMConfig::default()).unwrap()
 }
 
 pub fn assert_registers(vm: &CPU, expected: &[u8])
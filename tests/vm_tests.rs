@@ -1,5 +1,35 @@
-use vm::assembler::Assembler;
-use vm::{VMConfig, CPU};
+// These tests use the assembler, which needs the standard library
+#![cfg(feature = "std")]
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use vm::assembler::{Assembler, AssemblerError, Symbol, SymbolKind};
+use vm::bytecode::BytecodeError;
+use vm::{
+    assemble_and_run, disassemble, disassemble_with_spans, instructions, read_bytecode, run_to_string, to_intel_hex,
+    to_intel_hex_at, verify, verify_for, write_bytecode, CpuState, Debugger, InstructionHandler, Opcode, RunOutcome,
+    RustBucketError, UnknownPolicy, VMConfig, VMConfigError, VMError, WatchEvent, CPU, CPU16,
+};
+
+/// An output sink that can still be read after being handed to the CPU.
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        Ok(())
+    }
+}
 
 #[test]
 fn test_memory_operations()
@@ -17,13 +47,13 @@ fn test_memory_operations()
             vec![0, 42, 0, 0],
         ),
         (
-            // Test indexed load/store
+            // Test indexed load/store (index in r1)
             r#"
             MOV r0, 42
             MOV r1, 0x50
-            STIDX r0, r1
+            STIDX r0, 0
             MOV r0, 0
-            LDIDX r2, r1
+            LDIDX r2, 0
             HALT
             "#,
             vec![0, 0x50, 42, 0],
@@ -33,24 +63,3189 @@ fn test_memory_operations()
     run_test_cases(test_cases);
 }
 
+#[test]
+fn test_bitwise_operations()
+{
+    let test_cases = vec![
+        (
+            // Test AND
+            r#"
+            MOV r0, 12
+            MOV r1, 10
+            AND r0, r1
+            HALT
+            "#,
+            vec![8, 10, 0, 0],
+        ),
+        (
+            // Test OR
+            r#"
+            MOV r0, 12
+            MOV r1, 10
+            OR r0, r1
+            HALT
+            "#,
+            vec![14, 10, 0, 0],
+        ),
+        (
+            // Test XOR
+            r#"
+            MOV r0, 12
+            MOV r1, 10
+            XOR r0, r1
+            HALT
+            "#,
+            vec![6, 10, 0, 0],
+        ),
+        (
+            // Test that a zero result sets the zero flag for JEQ
+            r#"
+            MOV r0, 0xF0
+            MOV r1, 0x0F
+            AND r0, r1
+            JEQ 0x0F     ; Skip to the second MOV r2
+            MOV r2, 1
+            HALT
+            MOV r2, 2
+            HALT
+            "#,
+            vec![0, 0x0F, 2, 0],
+        ),
+        (
+            // Test NOT
+            r#"
+            MOV r0, 0
+            NOT r0
+            MOV r1, 0xF0
+            NOT r1
+            HALT
+            "#,
+            vec![255, 0x0F, 0, 0],
+        ),
+    ];
+
+    run_test_cases(test_cases);
+}
+
+#[test]
+fn test_shift_operations()
+{
+    let test_cases = vec![
+        (
+            // Test SHL
+            r#"
+            MOV r0, 1
+            MOV r1, 3
+            SHL r0, r1
+            HALT
+            "#,
+            vec![8, 3, 0, 0],
+        ),
+        (
+            // Test SHR
+            r#"
+            MOV r0, 0x80
+            MOV r1, 4
+            SHR r0, r1
+            HALT
+            "#,
+            vec![0x08, 4, 0, 0],
+        ),
+        (
+            // Shift amounts wrap modulo the register width
+            r#"
+            MOV r0, 1
+            MOV r1, 9
+            SHL r0, r1
+            HALT
+            "#,
+            vec![2, 9, 0, 0],
+        ),
+    ];
+
+    run_test_cases(test_cases);
+}
+
+#[test]
+fn test_immediate_arithmetic()
+{
+    let test_cases = vec![
+        (
+            // Register form is still selected for register operands
+            r#"
+            MOV r0, 5
+            MOV r1, 3
+            ADD r0, r1
+            SUB r1, r1
+            HALT
+            "#,
+            vec![8, 0, 0, 0],
+        ),
+        (
+            // Numeric operands select the immediate form
+            r#"
+            MOV r0, 5
+            ADD r0, 5
+            MOV r1, 10
+            SUB r1, 0x03
+            ADDI r2, 250
+            ADDI r2, 10
+            HALT
+            "#,
+            vec![10, 7, 4, 0],
+        ),
+    ];
+
+    run_test_cases(test_cases);
+}
+
+#[test]
+fn test_immediate_arithmetic_encoding()
+{
+    let mut assembler = Assembler::new();
+    assert_eq!(assembler.assemble("ADD r0, r1").unwrap(), vec![0x30, 0, 1]);
+    assert_eq!(assembler.assemble("ADD r0, 5").unwrap(), vec![0x3A, 0, 5]);
+    assert_eq!(assembler.assemble("SUB r2, 7").unwrap(), vec![0x3B, 2, 7]);
+}
+
+#[test]
+fn test_modulo_operation()
+{
+    let test_cases = vec![(
+        r#"
+            MOV r0, 17
+            MOV r1, 5
+            MOD r0, r1
+            HALT
+            "#,
+        vec![2, 5, 0, 0],
+    )];
+
+    run_test_cases(test_cases);
+}
+
+#[test]
+#[should_panic(expected = "DivisionByZero")]
+fn test_modulo_by_zero()
+{
+    let test_cases = vec![(
+        r#"
+            MOV r0, 17
+            MOV r1, 0
+            MOD r0, r1
+            HALT
+            "#,
+        vec![],
+    )];
+
+    run_test_cases(test_cases);
+}
+
+#[test]
+fn test_conditional_jumps_after_less_than()
+{
+    // After CMP with r0 < r1 only JLT and JLE should branch
+    let jumps = [
+        ("JEQ", false),
+        ("JNE", true),
+        ("JGT", false),
+        ("JLT", true),
+        ("JLE", true),
+        ("JGE", false),
+    ];
+
+    for (mnemonic, taken) in jumps {
+        let program = format!(
+            r#"
+            MOV r0, 1
+            MOV r1, 2
+            CMP r0, r1
+            {} 0x0F      ; Skip to the second MOV r2
+            MOV r2, 1
+            HALT
+            MOV r2, 2
+            HALT
+            "#,
+            mnemonic
+        );
+        let expected = if taken { 2 } else { 1 };
+        run_test_cases(vec![(program.as_str(), vec![1, 2, expected, 0])]);
+    }
+}
+
+#[test]
+fn test_conditional_jumps_after_equal()
+{
+    let jumps = [("JLT", false), ("JLE", true), ("JGE", true)];
+
+    for (mnemonic, taken) in jumps {
+        let program = format!(
+            r#"
+            MOV r0, 2
+            MOV r1, 2
+            CMP r0, r1
+            {} 0x0F      ; Skip to the second MOV r2
+            MOV r2, 1
+            HALT
+            MOV r2, 2
+            HALT
+            "#,
+            mnemonic
+        );
+        let expected = if taken { 2 } else { 1 };
+        run_test_cases(vec![(program.as_str(), vec![2, 2, expected, 0])]);
+    }
+}
+
+#[test]
+fn test_nop_has_no_effect()
+{
+    let test_cases = vec![(
+        r#"
+            NOP
+            NOP
+            NOP
+            HALT
+            "#,
+        vec![0, 0, 0, 0, 0, 0, 0, 0],
+    )];
+
+    run_test_cases(test_cases);
+
+    let mut assembler = Assembler::new();
+    assert_eq!(assembler.assemble("NOP\nNOP\nHALT").unwrap(), vec![0x00, 0x00, 0xFF]);
+}
+
+#[test]
+fn test_out_writes_to_configured_sink()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler
+        .assemble(
+            r#"
+            MOV r0, 5
+            MOV r1, 3
+            ADD r0, r1
+            OUT r0
+            OUT r1
+            HALT
+            "#,
+        )
+        .unwrap();
+
+    let buffer = SharedBuffer::default();
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.set_output(Box::new(buffer.clone()));
+    vm.load_program(&bytecode).unwrap();
+    vm.run().unwrap();
+
+    assert_eq!(buffer.0.borrow().as_slice(), b"8\n3\n");
+}
+
+#[test]
+fn test_step_executes_one_instruction()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler
+        .assemble(
+            r#"
+            MOV r0, 5
+            MOV r1, 3
+            ADD r0, r1
+            HALT
+            "#,
+        )
+        .unwrap();
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+
+    assert!(vm.step().unwrap());
+    assert_eq!(vm.get_register(0).unwrap(), 5);
+    assert_eq!(vm.get_register(1).unwrap(), 0);
+
+    assert!(vm.step().unwrap());
+    assert_eq!(vm.get_register(1).unwrap(), 3);
+
+    assert!(vm.step().unwrap());
+    assert_eq!(vm.get_register(0).unwrap(), 8);
+
+    // HALT stops the program, and further steps do nothing
+    assert!(!vm.step().unwrap());
+    assert!(!vm.step().unwrap());
+    assert_eq!(vm.get_register(0).unwrap(), 8);
+}
+
+#[test]
+fn test_forward_jump_skips_instructions()
+{
+    let test_cases = vec![(
+        r#"
+            JMP 0x05     ; Skip over the first MOV
+            MOV r0, 1
+            MOV r1, 2
+            HALT
+            "#,
+        vec![0, 2, 0, 0],
+    )];
+
+    run_test_cases(test_cases);
+}
+
+#[test]
+fn test_jump_outside_memory_is_an_error()
+{
+    let mut assembler = Assembler::new();
+
+    let cases = [
+        ("JMP 0xFF", 0xFF),
+        ("CALL 0xFF", 0xFF),
+        ("MOV r0, 1\nMOV r1, 1\nCMP r0, r1\nJEQ 0x20", 0x20),
+    ];
+
+    for (program, target) in cases {
+        let bytecode = assembler.assemble(program).unwrap();
+        let mut vm = CPU::new(VMConfig::builder().memory_size(16).stack_size(4).build().unwrap()).unwrap();
+        vm.load_program(&bytecode).unwrap();
+
+        match vm.run() {
+            Err(VMError::InvalidMemoryAccess(addr)) => assert_eq!(addr, target),
+            other => panic!("Expected invalid memory access for {:?}, got {:?}", program, other),
+        }
+    }
+}
+
+#[test]
+fn test_cycle_limit_stops_infinite_loop()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.assemble("JMP 0x00").unwrap();
+
+    let mut vm = CPU::new(VMConfig::builder().max_cycles(100).build().unwrap()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+
+    assert_eq!(vm.run().unwrap(), RunOutcome::CycleLimit);
+}
+
+#[test]
+fn test_cycle_limit_allows_short_programs()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.assemble("MOV r0, 5\nMOV r1, 3\nADD r0, r1\nHALT").unwrap();
+
+    // Exactly enough cycles for all four instructions
+    let mut vm = CPU::new(VMConfig::builder().max_cycles(4).build().unwrap()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.get_register(0).unwrap(), 8);
+}
+
+#[test]
+fn test_disassemble_round_trip()
+{
+    let program = r#"
+        MOV r0, 5
+        MOV r1, 3
+        ADD r0, r1
+        SUB r0, 2
+        CMP r0, r1
+        JEQ 0x14
+        STORE r0, 0x50
+        OUT r0
+        HALT
+    "#;
+
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.assemble(program).unwrap();
+
+    let text = disassemble(&bytecode).unwrap();
+    assert_eq!(
+        text,
+        "MOV r0, 0x05\nMOV r1, 0x03\nADD r0, r1\nSUBI r0, 0x02\nCMP r0, r1\nJEQ 0x14\nSTORE r0, 0x50\nOUT r0\nHALT\n"
+    );
+
+    let reassembled = assembler.assemble(&text).unwrap();
+    assert_eq!(reassembled, bytecode);
+}
+
+#[test]
+fn test_disassemble_unknown_and_truncated()
+{
+    assert_eq!(disassemble(&[0x00, 0x99, 0xFF]).unwrap(), "NOP\n; unknown 0x99\nHALT\n");
+
+    // A LOAD missing its address byte
+    assert!(matches!(disassemble(&[0x20, 0x00]), Err(VMError::InvalidMemoryAccess(2))));
+}
+
+#[test]
+fn test_instructions_iterator()
+{
+    let program = r#"
+        MOV r0, 5
+        MOV r1, 3
+        ADD r0, r1
+        OUT r0
+        HALT
+    "#;
+    let bytecode = Assembler::new().assemble(program).unwrap();
+    let decoded: Vec<(usize, Opcode)> = instructions(&bytecode).collect::<Result<_, _>>().unwrap();
+    assert_eq!(
+        decoded,
+        vec![
+            (0, Opcode::Mov(0, 5)),
+            (3, Opcode::Mov(1, 3)),
+            (6, Opcode::Add(0, 1)),
+            (9, Opcode::Out(0)),
+            (11, Opcode::Halt),
+        ]
+    );
+
+    // A truncated instruction is reported once, then the iterator stops
+    let mut truncated = instructions(&[0x00, 0x20, 0x00]);
+    assert!(matches!(truncated.next(), Some(Ok((0, Opcode::Nop)))));
+    assert!(matches!(truncated.next(), Some(Err(VMError::InvalidMemoryAccess(3)))));
+    assert!(truncated.next().is_none());
+}
+
+#[test]
+fn test_labels_as_values()
+{
+    let test_cases = vec![
+        (
+            // Labels can be used as LOAD/STORE addresses and MOV immediates
+            r#"
+            MOV r0, 42
+            STORE r0, buffer
+            LOAD r1, buffer
+            MOV r2, buffer
+            HALT
+        buffer:
+            "#,
+            vec![42, 42, 13, 0],
+        ),
+        (
+            // Forward jump to a label
+            r#"
+            JMP skip
+            MOV r0, 1
+        skip:
+            MOV r1, 2
+            HALT
+            "#,
+            vec![0, 2, 0, 0],
+        ),
+    ];
+
+    run_test_cases(test_cases);
+}
+
+#[test]
+fn test_undefined_label_is_an_error()
+{
+    let mut assembler = Assembler::new();
+    let err = assembler.assemble("LOAD r0, missing\nHALT").unwrap_err();
+    assert_eq!(err.to_string(), "line 1: Undefined label: missing");
+}
+
+#[test]
+fn test_org_directive()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler
+        .assemble(
+            r#"
+            JMP start
+            .org 0x20
+        start:
+            MOV r0, 7
+            HALT
+            "#,
+        )
+        .unwrap();
+
+    assert_eq!(assembler.labels()["start"], 0x20);
+    assert_eq!(bytecode.len(), 0x24);
+    assert_eq!(&bytecode[..2], &[0x40, 0x20]);
+    assert!(bytecode[2..0x20].iter().all(|&b| b == 0));
+    assert_eq!(&bytecode[0x20..], &[0x04, 0, 7, 0xFF]);
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.get_register(0).unwrap(), 7);
+}
+
+#[test]
+fn test_org_cannot_move_backwards()
+{
+    let mut assembler = Assembler::new();
+    let err = assembler.assemble(".org 0x10\nHALT\n.org 0x04\nHALT").unwrap_err();
+    assert!(err.to_string().starts_with("line 3: Invalid address"), "{}", err);
+}
+
+#[test]
+fn test_byte_directive()
+{
+    let program = r#"
+            LOAD r0, first
+            LOAD r1, second
+            HALT
+        first:
+            .byte 42, 0x10
+        second:
+            DB 7
+        "#;
+
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.assemble(program).unwrap();
+    assert_eq!(assembler.labels()["first"], 7);
+    assert_eq!(assembler.labels()["second"], 9);
+    assert_eq!(&bytecode[7..], &[42, 0x10, 7]);
+
+    run_test_cases(vec![(program, vec![42, 7, 0, 0])]);
+}
+
+#[test]
+fn test_equ_constants()
+{
+    let program = r#"
+        COUNT EQU 10
+        BUFFER EQU 0x50
+        LIMIT EQU COUNT
+            MOV r0, COUNT
+            STORE r0, BUFFER
+            LOAD r1, BUFFER
+            MOV r2, LIMIT
+            HALT
+        "#;
+
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.assemble(program).unwrap();
+    assert_eq!(&bytecode[..3], &[0x04, 0, 10]);
+    assert_eq!(assembler.constants()["BUFFER"], 0x50);
+
+    run_test_cases(vec![(program, vec![10, 10, 10, 0])]);
+}
+
+#[test]
+fn test_equ_constant_errors()
+{
+    let mut assembler = Assembler::new();
+
+    let err = assembler.assemble("MOV r0, COUNT\nCOUNT EQU 10\nHALT").unwrap_err();
+    assert_eq!(err.to_string(), "line 1: Constant used before definition: COUNT");
+
+    let err = assembler.assemble("start:\nstart EQU 1\nHALT").unwrap_err();
+    assert!(err.to_string().starts_with("line 2: Invalid label"), "{}", err);
+
+    let err = assembler.assemble("COUNT EQU 1\nCOUNT:\nHALT").unwrap_err();
+    assert!(err.to_string().starts_with("line 2: Invalid label"), "{}", err);
+}
+
+#[test]
+fn test_errors_report_line_numbers()
+{
+    let program = r#"
+        ; Blank lines and comments still count
+
+        MOV r0, 5
+        MOV r1, 3
+    loop:
+        ADD r0, r9
+        HALT
+    "#;
+
+    let mut assembler = Assembler::new();
+    let err = assembler.assemble(program).unwrap_err();
+    assert_eq!(err.to_string(), "line 7: Invalid register: r9");
+
+    // Errors found in the second pass are tagged too
+    let err = assembler.assemble("NOP\n\nJMP nowhere").unwrap_err();
+    assert_eq!(err.to_string(), "line 3: Undefined label: nowhere");
+}
+
+#[test]
+fn test_assemble_all_collects_every_error()
+{
+    let program = r#"
+        MOV r0, 5
+        ADD r0, r9
+        FOO r1
+        MOV r1, 3
+        JMP nowhere
+        HALT
+    "#;
+
+    let mut assembler = Assembler::new();
+    let errors = assembler.assemble_all(program).unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "line 3: Invalid register: r9",
+            "line 4: Invalid instruction: FOO",
+            "line 6: Undefined label: nowhere",
+        ]
+    );
+
+    // assemble still reports only the first error
+    let err = assembler.assemble(program).unwrap_err();
+    assert_eq!(err.to_string(), "line 3: Invalid register: r9");
+}
+
+#[test]
+fn test_signed_immediates()
+{
+    let mut assembler = Assembler::new();
+    assert_eq!(assembler.assemble("MOV r0, -1").unwrap(), vec![0x04, 0, 0xFF]);
+    assert_eq!(assembler.assemble("MOV r0, -128").unwrap(), vec![0x04, 0, 0x80]);
+    assert_eq!(assembler.assemble("MOV r0, 127").unwrap(), vec![0x04, 0, 0x7F]);
+    assert_eq!(assembler.assemble("MOV r0, 255").unwrap(), vec![0x04, 0, 0xFF]);
+    assert!(assembler.assemble("MOV r0, -129").is_err());
+
+    // Two's-complement values work with the wrapping arithmetic
+    run_test_cases(vec![("MOV r0, 10\nADD r0, -3\nHALT", vec![7])]);
+}
+
+#[test]
+fn test_carry_flag()
+{
+    let test_cases = vec![
+        (
+            // 200 + 100 overflows, so JC branches
+            r#"
+            MOV r0, 200
+            MOV r1, 100
+            ADD r0, r1
+            JC carry
+            MOV r2, 1
+            HALT
+        carry:
+            MOV r2, 2
+            HALT
+            "#,
+            vec![44, 100, 2, 0],
+        ),
+        (
+            // No overflow, so JNC branches
+            r#"
+            MOV r0, 100
+            ADD r0, 100
+            JNC no_carry
+            MOV r2, 1
+            HALT
+        no_carry:
+            MOV r2, 2
+            HALT
+            "#,
+            vec![200, 0, 2, 0],
+        ),
+        (
+            // Subtraction that borrows sets the carry flag
+            r#"
+            MOV r0, 1
+            SUB r0, 2
+            JC borrow
+            MOV r2, 1
+            HALT
+        borrow:
+            MOV r2, 2
+            HALT
+            "#,
+            vec![255, 0, 2, 0],
+        ),
+    ];
+
+    run_test_cases(test_cases);
+}
+
+#[test]
+fn test_add_with_carry()
+{
+    let test_cases = vec![
+        (
+            // 0x01FF + 0x0001 = 0x0200, with low bytes in r0/r2 and high bytes in r1/r3
+            r#"
+            MOV r0, 0xFF
+            MOV r1, 0x01
+            MOV r2, 0x01
+            MOV r3, 0x00
+            ADD r0, r2
+            ADC r1, r3
+            HALT
+            "#,
+            vec![0x00, 0x02, 0x01, 0x00],
+        ),
+        (
+            // Without a carry in, ADC is a plain add
+            r#"
+            MOV r0, 3
+            MOV r1, 4
+            ADC r0, r1
+            HALT
+            "#,
+            vec![7, 4, 0, 0],
+        ),
+        (
+            // Carry out is set when only the carry in overflows
+            r#"
+            MOV r0, 0xFF
+            ADD r0, 1
+            MOV r1, 0xFF
+            MOV r2, 0
+            ADC r1, r2
+            JC carry
+            HALT
+        carry:
+            MOV r3, 1
+            HALT
+            "#,
+            vec![0, 0, 0, 1],
+        ),
+    ];
+
+    run_test_cases(test_cases);
+}
+
+#[test]
+fn test_reset_clears_state()
+{
+    let mut assembler = Assembler::new();
+    let first = assembler
+        .assemble("MOV r0, 42\nSTORE r0, 0x50\nMOV r1, 1\nMOV r2, 1\nCMP r1, r2\nPUSH r0\nHALT")
+        .unwrap();
+    let second = assembler.assemble("LOAD r1, 0x50\nJEQ 0x00\nHALT").unwrap();
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&first).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.get_register(0).unwrap(), 42);
+
+    vm.reset();
+    for i in 0..8 {
+        assert_eq!(vm.get_register(i).unwrap(), 0);
+    }
+
+    // Memory and the zero flag were cleared, so LOAD reads 0 and JEQ falls through
+    vm.load_program(&second).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.get_register(1).unwrap(), 0);
+}
+
+#[test]
+fn test_reset_registers_keeps_memory()
+{
+    let mut assembler = Assembler::new();
+    let program = assembler.assemble("MOV r0, 7\nADD r0, 1\nHALT").unwrap();
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&program).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.get_register(0).unwrap(), 8);
+
+    // The program is still in memory, so it can run again from the start
+    vm.reset_registers();
+    assert_eq!(vm.get_register(0).unwrap(), 0);
+    vm.run().unwrap();
+    assert_eq!(vm.get_register(0).unwrap(), 8);
+}
+
+#[test]
+fn test_state_accessors()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler
+        .assemble("MOV r0, 5\nMOV r1, 3\nADD r0, r1\nCMP r0, r1\nPUSH r0\nHALT")
+        .unwrap();
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.pc(), 0);
+    assert_eq!(vm.sp(), 256);
+
+    vm.run().unwrap();
+
+    assert_eq!(vm.registers(), &[8, 3, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(vm.flags(), 0b010);
+    assert_eq!(vm.pc(), vm.memory().len());
+    assert_eq!(vm.sp(), 255);
+    assert_eq!(vm.memory()[vm.sp()], 8);
+    assert_eq!(&vm.memory()[..3], &[0x04, 0, 5]);
+}
+
+#[test]
+fn test_peek_and_poke()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.assemble("LOAD r0, 0x50\nADD r0, 1\nSTORE r0, 0x51\nHALT").unwrap();
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    vm.poke(0x50, 41).unwrap();
+    vm.run().unwrap();
+
+    assert_eq!(vm.get_register(0).unwrap(), 42);
+    assert_eq!(vm.peek(0x51).unwrap(), 42);
+
+    assert!(matches!(vm.peek(256), Err(VMError::InvalidMemoryAccess(256))));
+    assert!(matches!(vm.poke(300, 1), Err(VMError::InvalidMemoryAccess(300))));
+}
+
+#[test]
+fn test_snapshot_and_restore()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler
+        .assemble("MOV r0, 1\nOUT r0\nADD r0, 2\nSTORE r0, 0x50\nOUT r0\nCALL done\ndone:\nOUT r0\nHALT")
+        .unwrap();
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    vm.set_output(Box::new(SharedBuffer::default()));
+    vm.step().unwrap();
+    vm.step().unwrap();
+    let snapshot = vm.snapshot();
+
+    let first = SharedBuffer::default();
+    vm.set_output(Box::new(first.clone()));
+    vm.run().unwrap();
+    let finished = vm.snapshot();
+
+    vm.restore(snapshot.clone()).unwrap();
+    assert_eq!(vm.snapshot(), snapshot);
+
+    let second = SharedBuffer::default();
+    vm.set_output(Box::new(second.clone()));
+    vm.run().unwrap();
+
+    assert_eq!(first.0.borrow().as_slice(), b"3\n3\n");
+    assert_eq!(first.0.borrow().as_slice(), second.0.borrow().as_slice());
+    assert_eq!(vm.snapshot(), finished);
+
+    // A snapshot from a differently configured CPU is rejected and changes nothing
+    let small = CPU::<u8>::new(VMConfig::builder().memory_size(64).stack_size(16).build().unwrap()).unwrap();
+    assert!(matches!(vm.restore(small.snapshot()), Err(VMError::InvalidSnapshot(_))));
+    assert_eq!(vm.snapshot(), finished);
+
+    let mut bad = snapshot.clone();
+    bad.registers.pop();
+    assert!(matches!(vm.restore(bad), Err(VMError::InvalidSnapshot(_))));
+    let mut bad = snapshot.clone();
+    bad.pc = 257;
+    assert!(matches!(vm.restore(bad), Err(VMError::InvalidSnapshot(_))));
+    let mut bad = snapshot;
+    bad.sp = 0x10;
+    assert!(matches!(vm.restore(bad), Err(VMError::InvalidSnapshot(_))));
+
+    // The PUSH that used to fail after a mismatched restore still works
+    vm.reset();
+    vm.load_program(&[0x10, 0x00, 0xFF]).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_snapshot_serde_round_trip()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.assemble("MOV r0, 5\nPUSH r0\nHALT").unwrap();
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    vm.run().unwrap();
+
+    let snapshot = vm.snapshot();
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let decoded: vm::CpuSnapshot = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded, snapshot);
+}
+
+#[test]
+fn test_bytecode_file_round_trip()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.assemble("MOV r0, 5\nMOV r1, 3\nADD r0, r1\nHALT").unwrap();
+
+    let path = std::env::temp_dir().join(format!("rustbucket_round_trip_{}.rbvm", std::process::id()));
+    write_bytecode(&path, &bytecode).unwrap();
+
+    let file = std::fs::read(&path).unwrap();
+    assert_eq!(&file[..5], b"RBVM\x01");
+    assert_eq!(&file[5..9], &(bytecode.len() as u32).to_le_bytes());
+
+    let loaded = read_bytecode(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded, bytecode);
+}
+
+#[test]
+fn test_bytecode_file_rejects_bad_magic()
+{
+    let path = std::env::temp_dir().join(format!("rustbucket_bad_magic_{}.rbvm", std::process::id()));
+    std::fs::write(&path, b"NOPE\x01\x01\x00\x00\x00\xFF").unwrap();
+
+    let result = read_bytecode(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(result, Err(BytecodeError::InvalidMagic)));
+}
+
+#[test]
+fn test_config_builder_validation()
+{
+    let cases = [
+        (VMConfig::builder().memory_size(0), VMConfigError::EmptyMemory),
+        (
+            VMConfig::builder().memory_size(16).stack_size(32),
+            VMConfigError::StackTooLarge {
+                stack_size: 32,
+                memory_size: 16,
+            },
+        ),
+        (VMConfig::builder().num_registers(0), VMConfigError::NoRegisters),
+        (VMConfig::builder().pc_start(256), VMConfigError::PcOutOfRange(256)),
+        (
+            VMConfig::builder().memory_size(128).sp_start(128),
+            VMConfigError::SpOutOfRange(128),
+        ),
+    ];
+
+    for (builder, expected) in cases {
+        assert_eq!(builder.build().unwrap_err(), expected);
+    }
+
+    // The stack pointer follows the memory size unless set explicitly
+    let config = VMConfig::builder().memory_size(16).stack_size(4).build().unwrap();
+    assert_eq!(config.sp_start, 15);
+    assert!(VMConfig::new(128, false).validate().is_ok());
+}
+
+#[test]
+fn test_cpu_new_rejects_invalid_config()
+{
+    // Built by hand to bypass the builder's validation
+    let config = VMConfig {
+        memory_size: 16,
+        stack_size: 64,
+        ..VMConfig::default()
+    };
+
+    match CPU::new(config) {
+        Err(VMError::InvalidConfig(VMConfigError::StackTooLarge { .. })) => {}
+        other => panic!("Expected an invalid config error, got {:?}", other.err()),
+    }
+
+    let config = VMConfig {
+        num_registers: 0,
+        ..VMConfig::default()
+    };
+    assert!(matches!(
+        CPU::new(config),
+        Err(VMError::InvalidConfig(VMConfigError::NoRegisters))
+    ));
+}
+
+#[test]
+fn test_out_of_range_register_is_an_error()
+{
+    let programs: [&[u8]; 4] = [
+        &[0x04, 0x07, 0x01, 0xFF], // MOV r7, 1
+        &[0x30, 0x00, 0x07, 0xFF], // ADD r0, r7
+        &[0x03, 0x07, 0xFF],       // OUT r7
+        &[0x10, 0x07, 0xFF],       // PUSH r7
+    ];
+
+    for program in programs {
+        let config = VMConfig::builder().num_registers(4).build().unwrap();
+        let mut vm = CPU::new(config).unwrap();
+        vm.load_program(program).unwrap();
+        assert!(
+            matches!(vm.run(), Err(VMError::InvalidRegister(7))),
+            "Expected invalid register for {:02X?}",
+            program
+        );
+    }
+}
+
+#[test]
+fn test_truncated_operand_is_an_error()
+{
+    // LOAD r0 in the last two bytes of memory, with no room for its address
+    let mut program = vec![0x00; 14];
+    program.extend_from_slice(&[0x20, 0x00]);
+
+    let mut vm = CPU::new(VMConfig::builder().memory_size(16).stack_size(4).build().unwrap()).unwrap();
+    vm.load_program(&program).unwrap();
+
+    assert!(matches!(vm.run(), Err(VMError::InvalidMemoryAccess(16))));
+}
+
+#[test]
+fn test_trace_hook_sees_each_instruction()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.assemble("MOV r0, 5\nMOV r1, 3\nADD r0, r1\nHALT").unwrap();
+
+    let trace = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    let events = Rc::clone(&trace);
+    vm.set_trace_hook(Box::new(move |event| events.borrow_mut().push((event.pc, event.opcode))));
+    vm.load_program(&bytecode).unwrap();
+    vm.run().unwrap();
+
+    assert_eq!(
+        *trace.borrow(),
+        vec![
+            (0, Opcode::Mov(0, 5)),
+            (3, Opcode::Mov(1, 3)),
+            (6, Opcode::Add(0, 1)),
+            (9, Opcode::Halt),
+        ]
+    );
+}
+
+#[test]
+fn test_breakpoint_stops_and_resumes()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.assemble("MOV r0, 5\nMOV r1, 3\nADD r0, r1\nHALT").unwrap();
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    vm.add_breakpoint(6); // ADD r0, r1
+
+    assert_eq!(vm.run().unwrap(), RunOutcome::Breakpoint(6));
+    assert_eq!(vm.pc(), 6);
+    assert_eq!(vm.registers()[..2], [5, 3]);
+
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.get_register(0).unwrap(), 8);
+
+    assert!(vm.remove_breakpoint(6));
+    assert!(!vm.remove_breakpoint(6));
+}
+
+#[test]
+fn test_run_outcome_halt_vs_end_of_memory()
+{
+    let mut assembler = Assembler::new();
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&assembler.assemble("MOV r0, 1\nHALT").unwrap()).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+
+    // Without HALT, execution slides through zeroed memory (NOPs) to the end
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&assembler.assemble("MOV r0, 1").unwrap()).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::MemoryExhausted);
+    assert_eq!(vm.get_register(0).unwrap(), 1);
+}
+
+#[test]
+fn test_missing_halt_is_reported_after_restore()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.assemble("MOV r0, 5\nMOV r1, 3\nADD r0, r1\nHALT").unwrap();
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    let start = vm.snapshot();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+
+    // A HALT from a previous run must not count once the state is restored
+    vm.restore(start).unwrap();
+    vm.poke(9, 0x00).unwrap(); // Replace HALT with NOP
+    assert_eq!(vm.run().unwrap(), RunOutcome::MemoryExhausted);
+    assert_eq!(vm.get_register(0).unwrap(), 8);
+
+    // A snapshot taken after a HALT restores as halted
+    vm.reset();
+    vm.load_program(&bytecode).unwrap();
+    vm.run().unwrap();
+    let halted = vm.snapshot();
+    vm.reset();
+    vm.restore(halted).unwrap();
+    assert!(vm.is_halted());
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+}
+
+#[test]
+fn test_stack_overflow_at_configured_limit()
+{
+    let config = VMConfig::builder().memory_size(64).stack_size(4).build().unwrap();
+    let mut vm = CPU::new(config).unwrap();
+
+    // PUSH r0 five times into a four byte stack
+    let mut program = [0x10, 0x00].repeat(5);
+    program.push(0xFF);
+    vm.load_program(&program).unwrap();
+
+    assert!(matches!(vm.run(), Err(VMError::StackOverflow)));
+    assert_eq!(vm.sp(), 60);
+    assert_eq!(vm.pc(), 10); // Failed on the fifth PUSH
+}
+
+#[test]
+fn test_call_depth_limit()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler
+        .assemble(
+            r#"
+            recurse:
+            ADD r0, 1
+            CALL recurse
+            HALT
+            "#,
+        )
+        .unwrap();
+
+    let mut vm = CPU::new(VMConfig::builder().max_call_depth(10).build().unwrap()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+
+    assert!(matches!(vm.run(), Err(VMError::CallStackOverflow)));
+    // Ten calls succeeded, so the ADD ran once more than that before the failing CALL
+    assert_eq!(vm.get_register(0).unwrap(), 11);
+}
+
+#[test]
+fn test_16_bit_arithmetic()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler
+        .assemble(
+            r#"
+            MOV r0, 200
+            MOV r1, 100
+            ADD r0, r1      ; 300, no carry in 16 bits
+            MOVW r2, 1000
+            MUL r2, r1      ; 100000 wraps to 34464
+            MOVW r3, 0xFFFF
+            ADD r3, 1       ; Carries out of 16 bits
+            JC carried
+            HALT
+            carried:
+            MOV r4, 1
+            PUSH r0
+            POP r5
+            STORE r0, 0x80
+            LOAD r6, 0x80
+            OUT r0
+            HALT
+            "#,
+        )
+        .unwrap();
+
+    let buffer = SharedBuffer::default();
+    let mut vm = CPU16::from_config(VMConfig::default()).unwrap();
+    vm.set_output(Box::new(buffer.clone()));
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+
+    assert_eq!(vm.registers()[..7], [300, 100, 34464, 0, 1, 300, 300]);
+    assert_eq!(&vm.memory()[0x80..0x82], &[0x2C, 0x01]);
+    assert_eq!(String::from_utf8(buffer.0.borrow().clone()).unwrap(), "300\n");
+
+    // The same bytecode on an 8-bit CPU wraps 200 + 100 at 256
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.get_register(0).unwrap(), 44);
+
+    // Wide immediates must be asked for with MOVW
+    let error = Assembler::new().assemble_padded("MOV r0, 300", 256).unwrap_err();
+    assert!(matches!(error, AssemblerError::AtLine { ref error, .. }
+        if matches!(**error, AssemblerError::InvalidValue(_))));
+    assert_eq!(Assembler::new().assemble("MOVW r0, 300").unwrap(), vec![0x06, 0, 0x2C, 0x01]);
+}
+
+#[test]
+fn test_wide_address_load_store()
+{
+    let mut assembler = Assembler::new();
+    let bytecode = assembler
+        .assemble(
+            r#"
+            MOV r0, 99
+            STORE r0, 0x0120
+            LOAD r1, 0x0120
+            LOADW r2, 0x10
+            HALT
+            "#,
+        )
+        .unwrap();
+    assert_eq!(&bytecode[3..7], &[0x25, 0x00, 0x20, 0x01]);
+
+    let mut vm = CPU::new(VMConfig::new(512, false)).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    vm.poke(0x10, 7).unwrap();
+    vm.run().unwrap();
+
+    assert_eq!(vm.peek(0x0120).unwrap(), 99);
+    assert_eq!(vm.registers()[..3], [99, 99, 7]);
+
+    // Addresses past the end of memory are still rejected
+    let bytecode = assembler.assemble("LOAD r0, 0x0200").unwrap();
+    let mut vm = CPU::new(VMConfig::new(512, false)).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert!(matches!(vm.run(), Err(VMError::InvalidMemoryAccess(0x200))));
+
+    // Labels above 0xFF take the wide form too, moving the code after them
+    let program = r#"
+        LOAD r0, far
+        STORE r0, near
+        JMP done
+        done: HALT
+        near: .byte 0
+        .org 0x100
+        far: .byte 42
+    "#;
+    let bytecode = assembler.assemble(program).unwrap();
+    assert_eq!(&bytecode[..4], &[0x24, 0x00, 0x00, 0x01]);
+    assert_eq!(&bytecode[4..9], &[0x21, 0x00, 0x0A, 0x40, 0x09]);
+    let mut vm = CPU::new(VMConfig::new(512, false)).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.peek(0x0A).unwrap(), 42);
+
+    // An operand that only fits one byte once widened stays wide instead of flipping forever
+    let bytecode = assembler.assemble(".org 0xFC\nLOAD r0, 0x1FF - x\nx: HALT").unwrap();
+    assert_eq!(&bytecode[0xFC..], &[0x24, 0x00, 0xFF, 0x00, 0xFF]);
+
+    // A label past two bytes is out of range
+    let error = assembler
+        .assemble_padded("LOADW r0, huge\n.org 0x10000\nhuge: HALT", 0x10001)
+        .unwrap_err();
+    assert!(matches!(error, AssemblerError::AtLine { ref error, .. }
+        if matches!(**error, AssemblerError::InvalidAddress(_))));
+}
+
+#[test]
+fn test_symbol_table()
+{
+    let mut assembler = Assembler::new();
+    assembler
+        .assemble(
+            r#"
+            start:
+            LOAD r0, value
+            JMP start
+            value:
+            .byte 42
+            "#,
+        )
+        .unwrap();
+
+    assert_eq!(
+        assembler.symbol_table(),
+        vec![
+            Symbol {
+                name: "start".to_string(),
+                address: 0,
+                kind: SymbolKind::Code,
+            },
+            Symbol {
+                name: "value".to_string(),
+                address: 5,
+                kind: SymbolKind::Data,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_assembly_listing()
+{
+    let source = "; Add two numbers\nMOV r0, 5\nMOV r1, 3\nloop:\n    ADD r0, r1 ; accumulate\n\nJMP loop";
+
+    let mut assembler = Assembler::new();
+    let (bytecode, listing) = assembler.assemble_with_listing(source).unwrap();
+
+    assert_eq!(bytecode, assembler.assemble(source).unwrap());
+    assert_eq!(
+        listing,
+        "0000:             ; Add two numbers\n0000: 04 00 05    MOV r0, 5\n0003: 04 01 03    MOV r1, 3\n0006:             \
+         loop:\n0006: 30 00 01    ADD r0, r1 ; accumulate\n0009:\n0009: 40 06       JMP loop\n"
+    );
+}
+
+#[test]
+fn test_opcode_display()
+{
+    let cases = [
+        (Opcode::Add(0, 1), "ADD r0, r1"),
+        (Opcode::Mov(0, 5), "MOV r0, 5"),
+        (Opcode::MovW(2, 300), "MOVW r2, 300"),
+        (Opcode::AddI(3, 10), "ADDI r3, 10"),
+        (Opcode::Cmp(1, 2), "CMP r1, r2"),
+        (Opcode::Not(4), "NOT r4"),
+        (Opcode::Push(7), "PUSH r7"),
+        (Opcode::StoreW(0, 0x0120), "STOREW r0, 0x0120"),
+        (Opcode::Load(1, 0x50), "LOAD r1, 0x50"),
+        (Opcode::Jmp(0x06), "JMP 0x06"),
+        (Opcode::Halt, "HALT"),
+        (Opcode::Unknown(0xAB), "; unknown 0xAB"),
+    ];
+
+    for (opcode, expected) in cases {
+        assert_eq!(format!("{}", opcode), expected);
+    }
+}
+
+#[test]
+fn test_decode_agrees_with_from_u8()
+{
+    for byte in 0..=255u8 {
+        let (decoded, _) = Opcode::decode(&[byte, 0, 0, 0], 0).unwrap();
+        assert_eq!(Opcode::from(byte), decoded, "opcode byte 0x{:02X}", byte);
+    }
+    assert_eq!(Opcode::from(0x44), Opcode::Jne(0));
+}
+
+#[test]
+fn test_decode_agrees_with_fetch()
+{
+    // One of every instruction, arranged so that none of them fail
+    let program = r#"
+        MOV r1, 2
+        MOVW r2, 3
+        ADD r0, r1
+        SUB r0, r1
+        ADDI r0, 9
+        SUBI r0, 1
+        ADC r0, r1
+        MUL r0, r1
+        DIV r0, r1
+        MOD r0, r1
+        AND r0, r2
+        OR r0, r2
+        XOR r0, r2
+        SHL r0, r1
+        SHR r0, r1
+        NOT r3
+        NEG r3
+        NEG r3
+        MOVR r3, r3
+        XCHG r3, r4
+        XCHG r3, r4
+        INC r3
+        DEC r3
+        OUT r0
+        MOV r3, msg
+        OUTSTR r3
+        PUSH r0
+        STSP r0, 0
+        LDSP r4, 0
+        POP r4
+        PUSHA
+        POPA
+        STORE r0, 0xF0
+        LOAD r5, 0xF0
+        STIDX r0, 0xF0
+        LDIDX r6, 0xF0
+        STOREW r0, 0x100
+        LOADW r7, 0x100
+        MOV r0, 0xF4
+        MOV r1, 0xF0
+        MOV r2, 2
+        MEMCPY
+        FILL
+        CALL sub
+        ENTER 2
+        LEAVE
+        CMP r1, 1
+        CMP r1, r1
+        JEQ eq
+        eq:
+        JNE ne
+        ne:
+        JGT gt
+        gt:
+        JLT lt
+        lt:
+        JLE le
+        le:
+        JGE ge
+        ge:
+        JC c
+        c:
+        JNC nc
+        nc:
+        JS s
+        s:
+        JNS ns
+        ns:
+        JR jr
+        jr:
+        MOV r0, indirect
+        JMPR r0
+        indirect:
+        JMP end
+        sub:
+        NOP
+        RET
+        end:
+        HALT
+        msg: .strz "ok"
+    "#;
+
+    // The data addresses above must stay clear of the program
+    let bytecode = Assembler::new().assemble(program).unwrap();
+    assert!(bytecode.len() < 0xF0);
+    let mut vm = CPU::new(VMConfig::builder().memory_size(512).build().unwrap()).unwrap();
+    vm.set_output(Box::new(SharedBuffer::default()));
+    vm.load_program(&bytecode).unwrap();
+
+    let trace = Rc::new(RefCell::new(Vec::new()));
+    let events = Rc::clone(&trace);
+    vm.set_trace_hook(Box::new(move |event| events.borrow_mut().push((event.pc, event.opcode))));
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+
+    let mut mnemonics = std::collections::BTreeSet::new();
+    for &(pc, fetched) in trace.borrow().iter() {
+        let (decoded, _) = Opcode::decode(&bytecode, pc).unwrap();
+        assert_eq!(decoded, fetched, "instruction at 0x{:02X}", pc);
+        mnemonics.insert(fetched.to_string().split_whitespace().next().unwrap().to_string());
+    }
+
+    // Every opcode except Unknown and HaltCode was executed
+    assert_eq!(mnemonics.len(), 58);
+}
+
+#[test]
+fn test_run_compiled_matches_run()
+{
+    // Nested loops: 200 * 250 iterations of the inner body
+    let program = r#"
+        MOV r0, 200
+        outer:
+        MOV r1, 250
+        inner:
+        ADD r2, 1
+        ADC r3, r4
+        SUB r1, 1
+        CMP r1, r4
+        JNE inner
+        SUB r0, 1
+        CMP r0, r4
+        JNE outer
+        OUT r3
+        HALT
+    "#;
+    let bytecode = Assembler::new().assemble(program).unwrap();
+
+    let mut interpreted = CPU::new(VMConfig::default()).unwrap();
+    interpreted.set_output(Box::new(SharedBuffer::default()));
+    interpreted.load_program(&bytecode).unwrap();
+    assert_eq!(interpreted.run().unwrap(), RunOutcome::Halted);
+
+    let mut compiled = CPU::new(VMConfig::default()).unwrap();
+    compiled.set_output(Box::new(SharedBuffer::default()));
+    compiled.load_program(&bytecode).unwrap();
+    let instructions = compiled.compile();
+    assert_eq!(compiled.run_compiled(&instructions).unwrap(), RunOutcome::Halted);
+
+    assert_eq!(compiled.snapshot(), interpreted.snapshot());
+    assert_eq!(compiled.registers()[..4], [0, 0, 80, 195]);
+}
+
+/// Handles opcode 0x70, which doubles r0.
+struct DoubleR0;
+
+impl InstructionHandler for DoubleR0
+{
+    fn execute(&self, cpu: &mut CpuState, opcode: u8) -> Result<Option<()>, VMError>
+    {
+        if opcode != 0x70 {
+            return Ok(None);
+        }
+        cpu.registers[0] = cpu.registers[0].wrapping_mul(2);
+        Ok(Some(()))
+    }
+}
+
+#[test]
+fn test_custom_instruction_handler()
+{
+    let bytecode = Assembler::new().assemble("MOV r0, 21\n.byte 0x70\nMOV r1, 1\nHALT").unwrap();
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert!(matches!(vm.run(), Err(VMError::InvalidOpcode(0x70))));
+
+    vm.reset_registers();
+    vm.register_handler(Box::new(DoubleR0));
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.registers()[..2], [42, 1]);
+}
+
+#[test]
+fn test_relative_jump_countdown()
+{
+    let program = r#"
+        MOV r0, 5
+        MOV r2, 0
+        loop:
+        OUT r0
+        SUB r0, 1
+        CMP r0, r2
+        JEQ done
+        JR loop
+        done:
+        HALT
+    "#;
+    let bytecode = Assembler::new().assemble(program).unwrap();
+
+    // JR at 0x10 jumps back to 0x06, 12 bytes before the next instruction
+    assert_eq!(bytecode[0x10..0x12], [0x4A, (-12i8) as u8]);
+    assert_eq!(Opcode::decode(&bytecode, 0x10).unwrap(), (Opcode::Jr(-12), 2));
+
+    let output = SharedBuffer::default();
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.set_output(Box::new(output.clone()));
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(output.0.borrow().as_slice(), b"5\n4\n3\n2\n1\n");
+}
+
+#[test]
+fn test_relative_jump_out_of_range()
+{
+    let error = Assembler::new().assemble("JR far\n.org 0x90\nfar:\nHALT").unwrap_err();
+    assert!(error.to_string().contains("out of range"), "{}", error);
+}
+
+#[test]
+fn test_xchg_swaps_registers()
+{
+    let bytecode = Assembler::new().assemble("MOV r0, 1\nMOV r1, 2\nXCHG r0, r1\nHALT").unwrap();
+    assert_eq!(bytecode[6..9], [0x07, 0x00, 0x01]);
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.registers()[..2], [2, 1]);
+}
+
+#[test]
+fn test_clr_matches_mov_zero()
+{
+    let mut assembler = Assembler::new();
+    let clr = assembler.assemble("CLR r3\nHALT").unwrap();
+    assert_eq!(clr, assembler.assemble("MOV r3, 0\nHALT").unwrap());
+    assert_eq!(clr, [0x04, 0x03, 0x00, 0xFF]);
+
+    assert!(assembler.assemble("CLR r3, r4").is_err());
+}
+
+#[test]
+fn test_label_on_instruction_line()
+{
+    let program = r#"
+        MOV r0, 3
+        MOV r2, 0
+        loop: SUB r0, 1   ; label and instruction share a line
+        ADD r1, 2
+        CMP r0, r2
+        JNE loop
+        start: end: HALT
+    "#;
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.assemble(program).unwrap();
+    let symbols = assembler.symbol_table();
+    let address_of = |name: &str| symbols.iter().find(|s| s.name == name).unwrap().address;
+    assert_eq!(address_of("loop"), 6);
+    assert_eq!(address_of("start"), 17);
+    assert_eq!(address_of("end"), 17);
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.registers()[..2], [0, 6]);
+
+    // A label alone on its line (with a trailing comment) still works
+    assert_eq!(
+        assembler.assemble("JMP done\ndone: ; finish\nHALT").unwrap(),
+        [0x40, 0x02, 0xFF]
+    );
+}
+
+#[test]
+fn test_mnemonic_aliases()
+{
+    let mut assembler = Assembler::new();
+    let canonical = assembler.assemble("start:\nJEQ start\nJNE start\nHALT").unwrap();
+    assert_eq!(assembler.assemble("start:\nJZ start\nJNZ start\nHLT").unwrap(), canonical);
+
+    // Mnemonics are case-insensitive
+    assert_eq!(assembler.assemble("start:\njz start\nJnz start\nhalt").unwrap(), canonical);
+    assert_eq!(
+        assembler.assemble("movw r0, 300\n.Byte 1").unwrap(),
+        assembler.assemble("MOVW r0, 300\nDB 1").unwrap()
+    );
+}
+
+#[test]
+fn test_indexed_op_encoding()
+{
+    // Indexed ops take a register and a base address byte, matching Opcode::decode
+    let bytecode = Assembler::new()
+        .assemble("BASE EQU 0x60\nSTIDX r2, 0x50\nLDIDX r3, BASE")
+        .unwrap();
+    assert_eq!(bytecode, [0x23, 0x02, 0x50, 0x22, 0x03, 0x60]);
+    assert_eq!(Opcode::decode(&bytecode, 0).unwrap(), (Opcode::StIdx(2, 0x50), 3));
+    assert_eq!(Opcode::decode(&bytecode, 3).unwrap(), (Opcode::LdIdx(3, 0x60), 3));
+
+    // The base is an address, not a register
+    assert!(Assembler::new().assemble("STIDX r0, r1").is_err());
+}
+
+#[test]
+fn test_indexed_round_trip()
+{
+    // Fill 0x60..0x64 with 10, 20, 30, 40 using r1 as the index, then sum it back
+    let program = r#"
+        MOV r0, 10
+        MOV r1, 0
+        MOV r2, 4
+        fill:
+        STIDX r0, 0x60
+        ADD r0, 10
+        ADD r1, 1
+        CMP r1, r2
+        JNE fill
+
+        MOV r1, 0
+        MOV r3, 0
+        sum:
+        LDIDX r4, 0x60
+        ADD r3, r4
+        ADD r1, 1
+        CMP r1, r2
+        JNE sum
+        HALT
+    "#;
+    let bytecode = Assembler::new().assemble(program).unwrap();
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.memory()[0x60..0x64], [10, 20, 30, 40]);
+    assert_eq!(vm.registers()[3], 100);
+}
+
+#[test]
+fn test_indexed_address_at_memory_end()
+{
+    let run = |program: &str| {
+        let bytecode = Assembler::new().assemble(program).unwrap();
+        let mut vm = CPU::new(VMConfig::default()).unwrap();
+        vm.load_program(&bytecode).unwrap();
+        vm.run().map(|_| vm.memory()[0xFF])
+    };
+
+    // 0xF0 + 0x0F is the last byte of memory
+    assert_eq!(run("MOV r0, 7\nMOV r1, 0x0F\nSTIDX r0, 0xF0\nHALT").unwrap(), 7);
+
+    // base + index == memory.len() doesn't wrap to address 0
+    assert!(matches!(
+        run("MOV r1, 0x10\nSTIDX r0, 0xF0\nHALT"),
+        Err(VMError::InvalidMemoryAccess(0x100))
+    ));
+    assert!(matches!(
+        run("MOV r1, 0x11\nLDIDX r0, 0xF0\nHALT"),
+        Err(VMError::InvalidMemoryAccess(0x101))
+    ));
+}
+
+#[test]
+fn test_halt_exit_code()
+{
+    let bytecode = Assembler::new().assemble("MOV r0, 1\nHALT 42\nHALT").unwrap();
+    assert_eq!(bytecode[3..5], [0xFE, 42]);
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.exit_code(), None);
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.exit_code(), Some(42));
+
+    // Plain HALT exits with 0
+    vm.reset();
+    vm.load_program(&Assembler::new().assemble("HALT").unwrap()).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.exit_code(), Some(0));
+
+    // Running off the end of memory isn't a HALT
+    vm.reset();
+    assert_eq!(vm.run().unwrap(), RunOutcome::MemoryExhausted);
+    assert_eq!(vm.exit_code(), None);
+}
+
+#[test]
+fn test_debugger_commands()
+{
+    let program = r#"
+        MOV r0, 5
+        MOV r1, 3
+        ADD r0, r1
+        STORE r0, 0x50
+        HALT
+    "#;
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&Assembler::new().assemble(program).unwrap()).unwrap();
+    let mut debugger = Debugger::new(vm);
+
+    assert_eq!(debugger.peek().unwrap(), Some(Opcode::Mov(0, 5)));
+    let step = debugger.step().unwrap().unwrap();
+    assert_eq!((step.pc, step.opcode, step.running), (0, Opcode::Mov(0, 5), true));
+    debugger.step().unwrap();
+
+    let registers = debugger.registers();
+    assert_eq!(registers.values[..2], [5, 3]);
+    assert_eq!(registers.pc, 6);
+
+    // Stop before the STORE, then check memory is still untouched
+    debugger.set_breakpoint(9);
+    assert_eq!(debugger.continue_execution().unwrap(), RunOutcome::Breakpoint(9));
+    assert_eq!(debugger.registers().values[0], 8);
+    assert_eq!(debugger.memory(0x50..0x52).unwrap().bytes, [0, 0]);
+
+    assert!(debugger.clear_breakpoint(9));
+    assert_eq!(debugger.continue_execution().unwrap(), RunOutcome::Halted);
+    let dump = debugger.memory(0x50..0x52).unwrap();
+    assert_eq!((dump.start, dump.bytes), (0x50, vec![8, 0]));
+
+    assert_eq!(debugger.step().unwrap(), None);
+    assert!(matches!(
+        debugger.memory(0xF0..0x110),
+        Err(VMError::InvalidMemoryAccess(0x110))
+    ));
+}
+
+#[test]
+fn test_watchpoint_reports_writes()
+{
+    let program = r#"
+        MOV r0, 7
+        STORE r0, 0x50
+        MOV r0, 9
+        STORE r0, 0x50
+        STORE r0, 0x51
+        PUSH r0
+        HALT
+    "#;
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&Assembler::new().assemble(program).unwrap()).unwrap();
+    vm.add_watchpoint(0x50);
+    vm.add_watchpoint(0xFF);
+
+    let writes = Rc::new(RefCell::new(Vec::new()));
+    let events = Rc::clone(&writes);
+    vm.set_watch_hook(Box::new(move |event| events.borrow_mut().push(*event)));
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+
+    let watch = |addr, old, new| WatchEvent { addr, old, new };
+    assert_eq!(*writes.borrow(), [watch(0x50, 0, 7), watch(0x50, 7, 9), watch(0xFF, 0, 9)]);
+    assert!(vm.remove_watchpoint(0x50));
+    assert!(!vm.remove_watchpoint(0x50));
+
+    // ENTER saves the base pointer, 0x100, in the two bytes below it
+    vm.reset();
+    vm.load_program(&Assembler::new().assemble("ENTER 0\nLEAVE\nHALT").unwrap())
+        .unwrap();
+    vm.add_watchpoint(0xFF);
+    writes.borrow_mut().clear();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(*writes.borrow(), [watch(0xFF, 0, 0x01)]);
+    assert_eq!(vm.bp(), 0x100);
+}
+
+#[test]
+fn test_state_string()
+{
+    let mut vm = CPU::new(VMConfig::builder().memory_size(16).stack_size(4).build().unwrap()).unwrap();
+    vm.load_program(&Assembler::new().assemble("MOV r0, 5\nCMP r0, r1\nHALT").unwrap())
+        .unwrap();
+    vm.run().unwrap();
+
+    // The default window starts at 0x50, past the end of this VM's memory
+    let expected = [
+        "CPU State:",
+        "PC: 0x10",
+        "Registers: r0=5 r1=0 r2=0 r3=0 r4=0 r5=0 r6=0 r7=0",
+        "Flags: 00000010 [greater]",
+        "Stack pointer: 0x10",
+        "Base pointer: 0x10",
+        "Call stack: []",
+        "Memory[0x10..0x10]: ",
+    ];
+    assert_eq!(vm.state_string(), expected.join("\n"));
+
+    let state = vm.state_string_with_memory(0x00..0x20);
+    assert!(
+        state.ends_with("Memory[0x00..0x10]: 04 00 05 43 00 01 FF 00 00 00 00 00 00 00 00 00"),
+        "{}",
+        state
+    );
+}
+
+#[test]
+fn test_dump_state_on_small_memory()
+{
+    let config = VMConfig::builder().memory_size(16).stack_size(4).debug(true).build().unwrap();
+    let mut vm = CPU::new(config).unwrap();
+    vm.set_output(Box::new(SharedBuffer::default()));
+    vm.load_program(&Assembler::new().assemble("MOV r0, 1\nOUT r0\nHALT").unwrap())
+        .unwrap();
+
+    vm.dump_state();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    vm.dump_state();
+}
+
+#[test]
+fn test_compare_immediate()
+{
+    let test_cases = vec![
+        // Equal: zero flag only
+        ("MOV r0, 5\nCMP r0, 5\nHALT", 0b001),
+        // Greater
+        ("MOV r0, 6\nCMPI r0, 5\nHALT", 0b010),
+        // Less: neither flag
+        ("MOV r0, 4\nCMP r0, 5\nHALT", 0b000),
+    ];
+
+    for (program, flags) in test_cases {
+        let bytecode = Assembler::new().assemble(program).unwrap();
+        assert_eq!(bytecode[3..6], [0x3D, 0x00, 0x05]);
+
+        let mut vm = CPU::new(VMConfig::default()).unwrap();
+        vm.load_program(&bytecode).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.flags(), flags, "{}", program);
+    }
+}
+
+#[test]
+fn test_sign_flag_and_js()
+{
+    let program = r#"
+        MOV r0, 3
+        SUB r0, 5       ; 0xFE, negative as a signed byte
+        JS negative
+        MOV r1, 1
+        HALT
+        negative:
+        MOV r1, 2
+        ADD r0, 3       ; back to 1, sign clear
+        JNS positive
+        HALT
+        positive:
+        MOV r2, 3
+        HALT
+    "#;
+    let bytecode = Assembler::new().assemble(program).unwrap();
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    vm.add_breakpoint(6);
+    assert_eq!(vm.run().unwrap(), RunOutcome::Breakpoint(6));
+    assert_eq!(vm.registers()[0], 0xFE);
+    assert_eq!(vm.flags() & 0b1000, 0b1000);
+
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.registers()[..3], [1, 2, 3]);
+    assert_eq!(vm.flags() & 0b1000, 0);
+
+    // MOV sets the flag from the moved value, on 16-bit registers from bit 15
+    let mut vm = CPU16::from_config(VMConfig::default()).unwrap();
+    vm.load_program(&Assembler::new().assemble("MOV r0, 0x80\nMOVW r1, 0x8000\nHALT").unwrap())
+        .unwrap();
+    vm.add_breakpoint(3);
+    vm.run().unwrap();
+    assert_eq!(vm.flags() & 0b1000, 0);
+    vm.run().unwrap();
+    assert_eq!(vm.flags() & 0b1000, 0b1000);
+}
+
+#[test]
+fn test_neg()
+{
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&Assembler::new().assemble("MOV r0, 5\nNEG r0\nHALT").unwrap())
+        .unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.registers()[0], 0xFB);
+    assert_eq!(vm.flags(), 0b1000);
+
+    // Negating zero leaves zero and sets the zero flag
+    vm.reset();
+    vm.load_program(&Assembler::new().assemble("NEG r0\nHALT").unwrap()).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.registers()[0], 0);
+    assert_eq!(vm.flags(), 0b0001);
+}
+
+#[test]
+fn test_register_move()
+{
+    // r2 holds 42, so copying it must not load the register index 2
+    let program = "MOV r2, 42\nMOV r0, r2\nMOVR r1, r2\nHALT";
+    let bytecode = Assembler::new().assemble(program).unwrap();
+    assert_eq!(bytecode[3..9], [0x09, 0x00, 0x02, 0x09, 0x01, 0x02]);
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.registers()[..3], [42, 42, 42]);
+}
+
+#[test]
+fn test_mov_register_copies_contents()
+{
+    // Before MOV had a register form, `MOV r1, r0` loaded the index 0 into r1
+    let test_cases = vec![("MOV r0, 7\nMOV r1, r0\nHALT", vec![7, 7, 0, 0])];
+    run_test_cases(test_cases);
+
+    // The whole word is copied, not just the low byte
+    let mut vm = CPU16::from_config(VMConfig::default()).unwrap();
+    vm.load_program(&Assembler::new().assemble("MOVW r0, 300\nMOV r1, r0\nHALT").unwrap())
+        .unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.registers()[..2], [300, 300]);
+}
+
+#[test]
+fn test_pusha_popa()
+{
+    let program = r#"
+        MOV r0, 1
+        MOV r3, 4
+        MOV r7, 8
+        CALL clobber
+        HALT
+        clobber:
+        PUSHA
+        MOV r0, 0
+        MOV r3, 0
+        MOV r7, 0
+        POPA
+        RET
+    "#;
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&Assembler::new().assemble(program).unwrap()).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.registers(), [1, 0, 0, 4, 0, 0, 0, 8]);
+    assert_eq!(vm.sp(), 256);
+
+    // Not enough room for all eight registers: nothing is pushed
+    let mut vm = CPU::new(VMConfig::builder().stack_size(4).build().unwrap()).unwrap();
+    vm.load_program(&Assembler::new().assemble("PUSHA\nHALT").unwrap()).unwrap();
+    assert!(matches!(vm.run(), Err(VMError::StackOverflow)));
+    assert_eq!(vm.sp(), 256);
+}
+
+#[test]
+fn test_stack_relative_addressing()
+{
+    let program = r#"
+        MOV r0, 10
+        PUSH r0
+        MOV r0, 20
+        PUSH r0
+        MOV r0, 30
+        PUSH r0
+        LDSP r1, 0      ; top of stack
+        LDSP r2, 2      ; first value pushed
+        MOV r3, 25
+        STSP r3, 1      ; replace the middle value
+        POP r4
+        POP r5
+        POP r6
+        HALT
+    "#;
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&Assembler::new().assemble(program).unwrap()).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.registers()[1..7], [30, 10, 25, 30, 25, 10]);
+
+    // Reading past the end of memory is an error
+    vm.reset();
+    vm.load_program(&Assembler::new().assemble("LDSP r0, 0\nHALT").unwrap())
+        .unwrap();
+    assert!(matches!(vm.run(), Err(VMError::InvalidMemoryAccess(256))));
+}
+
+#[test]
+fn test_enter_leave_frames()
+{
+    let program = r#"
+        MOV r0, 7
+        CALL outer
+        HALT
+        outer:
+        ENTER 2
+        STSP r0, 0      ; local variable
+        CALL inner
+        LDSP r1, 0
+        LEAVE
+        RET
+        inner:
+        ENTER 4
+        MOV r0, 0
+        STSP r0, 0      ; doesn't touch the caller's local
+        LEAVE
+        RET
+    "#;
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&Assembler::new().assemble(program).unwrap()).unwrap();
+
+    // Stop inside `inner`, after its ENTER
+    vm.add_breakpoint(0x14);
+    assert_eq!(vm.run().unwrap(), RunOutcome::Breakpoint(0x14));
+    assert_eq!((vm.sp(), vm.bp()), (0xF6, 0xFA));
+    assert_eq!(vm.memory()[0xFA..0xFE], [0xFE, 0x00, 7, 0]);
+
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.registers()[1], 7);
+    assert_eq!((vm.sp(), vm.bp()), (256, 256));
+}
+
+#[test]
+fn test_entry_directive()
+{
+    let program = r#"
+        .entry main
+        table:
+        .byte 10, 20, 30
+        main:
+        LOAD r0, 0x01
+        HALT
+    "#;
+    let (bytecode, entry) = Assembler::new().assemble_with_entry(program).unwrap();
+    assert_eq!(entry, 3);
+
+    let config = VMConfig::builder().pc_start(entry).build().unwrap();
+    let mut vm = CPU::new(config).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.registers()[0], 20);
+
+    // Without a directive the program starts at 0
+    assert_eq!(Assembler::new().assemble_with_entry("HALT").unwrap(), (vec![0xFF], 0));
+
+    let error = Assembler::new().assemble_with_entry("HALT\n.start missing").unwrap_err();
+    assert_eq!(error.line(), Some(2));
+    assert!(matches!(error, vm::assembler::AssemblerError::AtLine { ref error, .. }
+        if matches!(**error, vm::assembler::AssemblerError::UndefinedLabel(_))));
+}
+
+#[test]
+fn test_analyze_reports_unreachable_code()
+{
+    let program = r#"
+        MOV r0, 1
+        JMP skip
+        MOV r0, 2       ; never runs
+        MOV r0, 3
+        skip:
+        CALL sub
+        HALT
+        sub:
+        RET
+        .byte 1, 2      ; data after RET is fine
+        NOP
+    "#;
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.assemble(program).unwrap();
+    let diagnostics = assembler.analyze();
+
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].line, 4);
+    assert_eq!(diagnostics[0].to_string(), "line 4: warning: unreachable code after JMP");
+    assert_eq!(diagnostics[1].line, 12);
+    assert_eq!(diagnostics[1].message, "unreachable code after RET");
+
+    // Analysis doesn't change the bytecode
+    assert_eq!(assembler.assemble(program).unwrap(), bytecode);
+
+    // The same code behind a label is reachable
+    assembler.assemble("HALT\nloop:\nJMP loop").unwrap();
+    assert!(assembler.analyze().is_empty());
+}
+
+#[test]
+fn test_optimizer_removes_redundant_instructions()
+{
+    let program = r#"
+        MOV r0, 0       ; overwritten straight away
+        MOV r0, 5
+        MOV r1, 3
+        loop:
+        MOV r2, 9       ; dead, but a jump target
+        MOV r2, 1
+        ADD r0, 0       ; flags overwritten by the SUB
+        SUB r1, r2
+        OUT r1
+        CMP r1, 0
+        JNE loop
+        OUT r0
+        HALT
+    "#;
+
+    let run = |bytecode: &[u8]| {
+        let buffer = SharedBuffer::default();
+        let mut vm = CPU::new(VMConfig::default()).unwrap();
+        vm.set_output(Box::new(buffer.clone()));
+        vm.load_program(bytecode).unwrap();
+        assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+        let output = buffer.0.borrow().clone();
+        (vm.registers().to_vec(), vm.flags(), output)
+    };
+
+    // Optimization is off by default
+    let mut assembler = Assembler::new();
+    let plain = assembler.assemble(program).unwrap();
+    assert_eq!(assembler.labels()["loop"], 9);
+
+    assembler.optimize(true);
+    let optimized = assembler.assemble(program).unwrap();
+    assert_eq!(plain.len() - optimized.len(), 6);
+    assert_eq!(assembler.labels()["loop"], 6);
+    assert_eq!(run(&optimized), run(&plain));
+
+    // A move that reads the register it overwrites keeps the earlier value alive
+    let kept = "MOV r0, 1\nMOV r0, r0\nMOV r1, 2\nMOV r1, r1\nHALT";
+    assert_eq!(assembler.assemble(kept).unwrap(), Assembler::new().assemble(kept).unwrap());
+
+    // A numeric jump target can't move with the code, so the program is left as written
+    let relative = "MOV r0, 1\nMOV r0, 2\nJR -5\nHALT";
+    assert_eq!(
+        assembler.assemble(relative).unwrap(),
+        Assembler::new().assemble(relative).unwrap()
+    );
+    let jumps_back = "MOV r0, 1\nMOV r0, 2\nJMP 3\nHALT";
+    assert_eq!(
+        assembler.assemble(jumps_back).unwrap(),
+        vec![0x04, 0x00, 0x01, 0x04, 0x00, 0x02, 0x40, 0x03, 0xFF]
+    );
+}
+
+#[test]
+fn test_intel_hex_export()
+{
+    let bytecode = Assembler::new().assemble("MOV r0, 5\nOUT r0\nHALT").unwrap();
+    assert_eq!(to_intel_hex(&bytecode).unwrap(), ":060000000400050300FFEF\n:00000001FF\n");
+
+    // A well-known record: 16 bytes at 0x0100
+    let data = [
+        0x21, 0x46, 0x01, 0x36, 0x01, 0x21, 0x47, 0x01, 0x36, 0x00, 0x7E, 0xFE, 0x09, 0xD2, 0x19, 0x01, 0x55,
+    ];
+    assert_eq!(
+        to_intel_hex_at(&data, 0x0100).unwrap(),
+        ":10010000214601360121470136007EFE09D2190140\n:010110005599\n:00000001FF\n"
+    );
+
+    // Crossing 64KiB switches to an extended linear address record
+    assert_eq!(
+        to_intel_hex_at(&[0xAA, 0xBB], 0xFFFF).unwrap(),
+        ":01FFFF00AA57\n:020000040001F9\n:01000000BB44\n:00000001FF\n"
+    );
+    assert_eq!(to_intel_hex(&[]).unwrap(), ":00000001FF\n");
+
+    // The last byte must fit in the 32-bit address space
+    assert!(to_intel_hex_at(&[1, 2, 3], 0xFFFF_FFFD).is_ok());
+    assert!(matches!(to_intel_hex_at(&[1, 2, 3], u32::MAX), Err(VMError::InvalidHex(_))));
+    assert_eq!(to_intel_hex_at(&[], u32::MAX).unwrap(), ":00000001FF\n");
+}
+
+#[test]
+fn test_load_hex()
+{
+    let bytecode = Assembler::new().assemble("MOV r0, 5\nOUT r0\nHALT").unwrap();
+    let hex = to_intel_hex_at(&bytecode, 0x20).unwrap();
+
+    let config = VMConfig::builder().pc_start(0x20).build().unwrap();
+    let mut vm = CPU::new(config).unwrap();
+    vm.load_hex(&hex).unwrap();
+    assert_eq!(&vm.memory()[0x20..0x26], bytecode.as_slice());
+    vm.set_output(Box::new(SharedBuffer::default()));
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.registers()[0], 5);
+
+    // Records from other tools may use lowercase digits and CRLF line endings
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_hex(":0300300002337a1e\r\n:00000001FF\r\n").unwrap();
+    assert_eq!(&vm.memory()[0x30..0x33], &[0x02, 0x33, 0x7A]);
+}
+
+#[test]
+fn test_load_hex_rejects_bad_records()
+{
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    let rejected = |vm: &mut CPU, hex: &str| match vm.load_hex(hex) {
+        Err(VMError::InvalidHex(reason)) => reason,
+        other => panic!("expected InvalidHex, got {:?}", other),
+    };
+
+    // Corrupted checksum
+    assert_eq!(
+        rejected(&mut vm, ":04000000040005FFF5\n:00000001FF\n"),
+        "line 1: bad checksum"
+    );
+    assert!(vm.memory()[..4].iter().all(|&byte| byte == 0));
+
+    assert_eq!(rejected(&mut vm, ":04000000040005FFF4\n"), "missing end-of-file record");
+    assert_eq!(
+        rejected(&mut vm, "04000000040005FFF4"),
+        "line 1: record doesn't start with ':'"
+    );
+    assert_eq!(
+        rejected(&mut vm, ":00000006FA\n:00000001FF"),
+        "line 1: unknown record type 06"
+    );
+
+    // Data past the end of a 256-byte memory
+    assert!(matches!(
+        vm.load_hex(&to_intel_hex_at(&[1, 2, 3, 4], 0xFE).unwrap()),
+        Err(VMError::InvalidMemoryAccess(256))
+    ));
+}
+
+#[test]
+fn test_load_program_at_offset()
+{
+    let bytecode = Assembler::new().assemble("MOV r0, 7\nADD r0, 1\nHALT").unwrap();
+
+    let config = VMConfig::builder().pc_start(0x20).build().unwrap();
+    let mut vm = CPU::new(config).unwrap();
+    vm.load_program_at(&bytecode, 0x20).unwrap();
+    assert!(vm.memory()[..0x20].iter().all(|&byte| byte == 0));
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.registers()[0], 8);
+
+    // The program must fit between the offset and the end of memory
+    assert!(matches!(
+        vm.load_program_at(&bytecode, 250),
+        Err(VMError::InvalidMemoryAccess(256))
+    ));
+    assert!(matches!(
+        vm.load_program_at(&bytecode, 300),
+        Err(VMError::InvalidMemoryAccess(300))
+    ));
+    assert!(vm.load_program_at(&bytecode, 256 - bytecode.len()).is_ok());
+}
+
+#[test]
+fn test_load_program_too_large()
+{
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    let program = vec![0u8; 300];
+    assert!(matches!(
+        vm.load_program(&program),
+        Err(VMError::ProgramTooLarge {
+            size: 300,
+            capacity: 256
+        })
+    ));
+    assert_eq!(
+        vm.load_program(&program).unwrap_err().to_string(),
+        "Program is 300 bytes but memory only holds 256"
+    );
+
+    // A program that exactly fills memory still loads
+    vm.load_program(&[0xFF; 256]).unwrap();
+}
+
+#[test]
+fn test_initial_registers()
+{
+    let config = VMConfig::builder().initial_registers(vec![10, 5]).build().unwrap();
+    let mut vm = CPU::new(config).unwrap();
+    vm.load_program(&Assembler::new().assemble("ADD r0, r1\nHALT").unwrap())
+        .unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.registers()[..3], [15, 5, 0]);
+
+    // Resetting goes back to the configured values
+    vm.reset_registers();
+    assert_eq!(vm.registers()[..3], [10, 5, 0]);
+
+    assert_eq!(
+        VMConfig::builder()
+            .num_registers(2)
+            .initial_registers(vec![1, 2, 3])
+            .build()
+            .unwrap_err(),
+        VMConfigError::TooManyInitialRegisters {
+            given: 3,
+            num_registers: 2
+        }
+    );
+}
+
+#[test]
+fn test_cycle_count()
+{
+    let program = r#"
+        MOV r0, 5
+        MOV r1, 3
+        ADD r0, r1
+        OUT r0
+        HALT
+    "#;
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.set_output(Box::new(SharedBuffer::default()));
+    vm.load_program(&Assembler::new().assemble(program).unwrap()).unwrap();
+    assert_eq!(vm.cycle_count(), 0);
+
+    vm.step().unwrap();
+    assert_eq!(vm.cycle_count(), 1);
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.cycle_count(), 5);
+
+    vm.reset_registers();
+    assert_eq!(vm.cycle_count(), 0);
+}
+
+#[test]
+fn test_weighted_cost()
+{
+    let program = r#"
+        MOV r0, 6
+        MOV r1, 7
+        MUL r0, r1
+        STORE r0, 0x80
+        HALT
+    "#;
+    let bytecode = Assembler::new().assemble(program).unwrap();
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.cycle_count(), 5);
+    // MOV + MOV + MUL + STORE + HALT
+    assert_eq!(vm.cost(), 1 + 1 + 4 + 2 + 1);
+
+    // Costs can be overridden per opcode
+    let config = VMConfig::builder().cost(0x32, 10).build().unwrap();
+    let mut vm = CPU::new(config).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.cost(), 15);
+
+    vm.reset_registers();
+    assert_eq!(vm.cost(), 0);
+
+    // Block and multi-register stack operations count as memory accesses
+    let stack_ops = Assembler::new().assemble("PUSHA\nPOPA\nENTER 2\nLEAVE\nHALT").unwrap();
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&stack_ops).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.cost(), 2 + 2 + 2 + 2 + 1);
+
+    // A compiled program is charged for the instructions it runs, even if memory changed since
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    let instructions = vm.compile();
+    vm.poke(6, 0x30).unwrap(); // MUL becomes ADD in memory only
+    vm.run_compiled(&instructions).unwrap();
+    assert_eq!(vm.get_register(0).unwrap(), 42);
+    assert_eq!(vm.cost(), 1 + 1 + 4 + 2 + 1);
+}
+
+#[test]
+fn test_run_to_string()
+{
+    let program = r#"
+        MOV r0, 5
+        MOV r1, 3
+        ADD r0, r1
+        OUT r0
+        HALT
+    "#;
+    let bytecode = Assembler::new().assemble(program).unwrap();
+    assert_eq!(run_to_string(&bytecode, VMConfig::default()).unwrap(), "8");
+
+    // Every OUT is captured, one per line
+    let bytecode = Assembler::new()
+        .assemble("MOV r0, 1\nOUT r0\nADD r0, 1\nOUT r0\nHALT")
+        .unwrap();
+    assert_eq!(run_to_string(&bytecode, VMConfig::default()).unwrap(), "1\n2");
+
+    // A program that never halts reports the cycle limit
+    let bytecode = Assembler::new().assemble("loop:\nJMP loop").unwrap();
+    let config = VMConfig::builder().max_cycles(10).build().unwrap();
+    assert!(matches!(
+        run_to_string(&bytecode, config),
+        Err(VMError::CycleLimitExceeded(10))
+    ));
+}
+
+#[test]
+fn test_json_trace()
+{
+    let program = r#"
+        MOV r0, 5
+        MOV r1, 3
+        ADD r0, r1
+        OUT r0
+        HALT
+    "#;
+    let records = Rc::new(RefCell::new(Vec::new()));
+    let sink = records.clone();
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.set_output(Box::new(SharedBuffer::default()));
+    vm.load_program(&Assembler::new().assemble(program).unwrap()).unwrap();
+    vm.set_json_trace(move |record| sink.borrow_mut().push(record));
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+
+    let records = records.borrow();
+    assert_eq!(records.len(), 5);
+    assert_eq!(
+        records[0],
+        r#"{"pc":0,"opcode":"MOV","operands":["r0","5"],"registers":{"r0":5},"flags":null}"#
+    );
+    assert_eq!(
+        records[2],
+        r#"{"pc":6,"opcode":"ADD","operands":["r0","r1"],"registers":{"r0":8},"flags":null}"#
+    );
+    assert_eq!(
+        records[4],
+        r#"{"pc":11,"opcode":"HALT","operands":[],"registers":{},"flags":null}"#
+    );
+
+    // Every record is valid JSON
+    for record in records.iter() {
+        let value: serde_json::Value = serde_json::from_str(record).unwrap();
+        assert!(value["pc"].is_u64());
+    }
+}
+
+#[test]
+fn test_assemble_padded()
+{
+    let program = "MOV r0, 5\nOUT r0\nHALT";
+    let image = Assembler::new().assemble_padded(program, 64).unwrap();
+    assert_eq!(image.len(), 64);
+    assert_eq!(image[..6], [0x04, 0x00, 0x05, 0x03, 0x00, 0xFF]);
+    assert!(image[6..].iter().all(|&byte| byte == 0));
+
+    // Exactly the program's size needs no padding
+    assert_eq!(Assembler::new().assemble_padded(program, 6).unwrap().len(), 6);
+
+    let error = Assembler::new().assemble_padded(program, 4).unwrap_err();
+    assert!(matches!(
+        error,
+        vm::assembler::AssemblerError::ProgramTooLarge { size: 6, limit: 4 }
+    ));
+}
+
+#[test]
+fn test_operand_expressions()
+{
+    let mut assembler = Assembler::new();
+    assert_eq!(assembler.assemble("MOV r0, 2 + 3").unwrap(), vec![0x04, 0x00, 0x05]);
+    assert_eq!(assembler.assemble("MOV r0, 10+5-1").unwrap(), vec![0x04, 0x00, 0x0E]);
+    assert_eq!(assembler.assemble("SUB r0, 1 - 2").unwrap(), vec![0x3B, 0x00, 0xFF]);
+
+    // Labels and constants can be offset
+    let program = r#"
+        SIZE EQU 3
+        LOAD r0, buffer + 1
+        LOAD r1, buffer + SIZE - 1
+        JMP end - 1
+        NOP
+        end:
+        HALT
+        buffer:
+        .byte 10, 20, 30
+    "#;
+    let bytecode = assembler.assemble(program).unwrap();
+    assert_eq!(bytecode[..9], [0x20, 0x00, 0x0B, 0x20, 0x01, 0x0C, 0x40, 0x08, 0x00]);
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.registers()[..2], [20, 30]);
+
+    // Results must fit in a byte, and every label must be defined
+    let error = assembler.assemble("MOV r0, 200 + 100").unwrap_err();
+    assert_eq!(error.to_string(), "line 1: Invalid value: 200+100 is 300, out of range");
+    let error = assembler.assemble("JMP missing + 1").unwrap_err();
+    assert_eq!(error.to_string(), "line 1: Undefined label: missing");
+
+    // Plain negative numbers are still literals
+    assert_eq!(assembler.assemble("MOV r0, -1").unwrap(), vec![0x04, 0x00, 0xFF]);
+}
+
+#[test]
+fn test_string_directives()
+{
+    let program = r#"
+            MOV r1, 0
+        loop:
+            LDIDX r0, msg   ; r0 = msg[r1]
+            CMP r0, 0
+            JEQ done
+            OUT r0
+            ADD r1, 1
+            JMP loop
+        done:
+            HALT
+        msg: .strz "Hi; \"x\"!"
+    "#;
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.assemble(program).unwrap();
+    let msg = assembler.labels()["msg"];
+    assert_eq!(&bytecode[msg..], b"Hi; \"x\"!\0");
+
+    let buffer = SharedBuffer::default();
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.set_output(Box::new(buffer.clone()));
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    let expected: String = "Hi; \"x\"!".bytes().map(|byte| format!("{}\n", byte)).collect();
+    assert_eq!(buffer.0.borrow().as_slice(), expected.as_bytes());
+
+    // .str has no terminator, and escapes are decoded
+    assert_eq!(assembler.assemble(r#".str "a\tb\n""#).unwrap(), b"a\tb\n");
+    assert!(assembler.assemble(r#".str "unterminated"#).is_err());
+    assert!(assembler.assemble(r#".str """#).is_err());
+}
+
+#[test]
+fn test_outstr()
+{
+    let program = r#"
+            MOV r2, greeting
+            OUTSTR r2
+            HALT
+        greeting:
+            .strz "Hello, world!\n"
+    "#;
+    let buffer = SharedBuffer::default();
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.set_output(Box::new(buffer.clone()));
+    vm.load_program(&Assembler::new().assemble(program).unwrap()).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.cycle_count(), 2 + 1);
+    assert_eq!(buffer.0.borrow().as_slice(), b"Hello, world!\n");
+
+    // A string that runs off the end of memory without a zero byte is an error
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.set_output(Box::new(SharedBuffer::default()));
+    vm.load_program(&[0x04, 0x00, 0xFE, 0x0A, 0x00]).unwrap();
+    vm.poke(0xFE, b'x').unwrap();
+    vm.poke(0xFF, b'y').unwrap();
+    assert!(matches!(vm.run(), Err(VMError::InvalidMemoryAccess(256))));
+}
+
+#[test]
+fn test_memcpy()
+{
+    let program = r#"
+        MOV r0, dest
+        MOV r1, src
+        MOV r2, 4
+        MEMCPY
+        HALT
+        src: .byte 1, 2, 3, 4
+        dest: .byte 0, 0, 0, 0, 0
+    "#;
+    let bytecode = Assembler::new().assemble(program).unwrap();
+    let src = bytecode.len() - 9;
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.memory()[src + 4..src + 9], [1, 2, 3, 4, 0]);
+
+    // Overlapping blocks copy as if through a temporary buffer
+    let program = r#"
+        MOV r0, data + 1
+        MOV r1, data
+        MOV r2, 4
+        MEMCPY
+        HALT
+        data: .byte 1, 2, 3, 4, 5
+    "#;
+    let bytecode = Assembler::new().assemble(program).unwrap();
+    let data = bytecode.len() - 5;
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.memory()[data..data + 5], [1, 1, 2, 3, 4]);
+
+    // A block running past the end of memory is an invalid access, and nothing is copied
+    let program = "MOV r0, 0xFE\nMOV r1, 0\nMOV r2, 4\nMEMCPY\nHALT";
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&Assembler::new().assemble(program).unwrap()).unwrap();
+    assert!(matches!(vm.run(), Err(VMError::InvalidMemoryAccess(256))));
+    assert_eq!(vm.memory()[0xFE..], [0, 0]);
+}
+
+#[test]
+fn test_fill()
+{
+    let program = r#"
+        MOV r0, buffer
+        MOV r1, 0
+        MOV r2, 10
+        FILL
+        HALT
+        buffer: .byte 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11
+    "#;
+    let bytecode = Assembler::new().assemble(program).unwrap();
+    let buffer = bytecode.len() - 11;
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.memory()[buffer..buffer + 11], [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 11]);
+
+    // A count running past the end of memory is an invalid access, and nothing is written
+    let program = "MOV r0, 0xF8\nMOV r1, 0xAA\nMOV r2, 9\nFILL\nHALT";
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&Assembler::new().assemble(program).unwrap()).unwrap();
+    assert!(matches!(vm.run(), Err(VMError::InvalidMemoryAccess(256))));
+    assert!(vm.memory()[0xF8..].iter().all(|&byte| byte == 0));
+}
+
+#[test]
+fn test_assemble_file_with_include()
+{
+    let dir = std::env::temp_dir().join(format!("rustbucket_include_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(
+        dir.join("main.asm"),
+        "MOV r0, 5\nCALL double\nOUT r0\nHALT\n%include \"lib/math.asm\" ; helpers\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("lib/math.asm"), "double:\n    ADD r0, r0\n    RET\n").unwrap();
+
+    let bytecode = Assembler::new().assemble_file(dir.join("main.asm"));
+
+    // Errors point at the file and line the code came from
+    std::fs::write(dir.join("lib/math.asm"), "double:\n    ADD r0, r0\n    BOGUS r0\n    RET\n").unwrap();
+    let misspelled = Assembler::new().assemble_file(dir.join("main.asm"));
+
+    // A file that ends up including itself is reported rather than expanded forever
+    std::fs::write(dir.join("lib/math.asm"), "%include \"../main.asm\"\n").unwrap();
+    let cycle = Assembler::new().assemble_file(dir.join("main.asm"));
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let combined = Assembler::new()
+        .assemble("MOV r0, 5\nCALL double\nOUT r0\nHALT\ndouble:\nADD r0, r0\nRET")
+        .unwrap();
+    assert_eq!(bytecode.unwrap(), combined);
+
+    let error = misspelled.unwrap_err();
+    assert!(error.file().unwrap().ends_with("lib/math.asm"));
+    assert_eq!(error.line(), Some(3));
+    let span = error.span().unwrap();
+    assert_eq!((span.start, span.end, span.line), (27, 35, 3));
+    assert!(
+        error.to_string().ends_with("math.asm:3: Invalid instruction: BOGUS"),
+        "{}",
+        error
+    );
+
+    assert!(matches!(cycle, Err(AssemblerError::InvalidInclude(_))));
+}
+
+#[test]
+fn test_local_labels()
+{
+    let program = r#"
+        MOV r0, 0
+        CALL count_up
+        CALL count_down
+        OUT r0
+        HALT
+
+        count_up:
+            MOV r1, 3
+        .loop:
+            ADD r0, 2
+            SUB r1, 1
+            CMP r1, 0
+            JNE .loop
+            RET
+
+        count_down:
+            MOV r1, 2
+        .loop:
+            SUB r0, 1
+            SUB r1, 1
+            CMP r1, 0
+            JNE .loop
+            RET
+    "#;
+    let mut assembler = Assembler::new();
+    let bytecode = assembler.assemble(program).unwrap();
+    let labels = assembler.labels();
+    assert_eq!(labels["count_up.loop"], labels["count_up"] + 3);
+    assert_eq!(labels["count_down.loop"], labels["count_down"] + 3);
+    assert!(!labels.contains_key(".loop"));
+
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    let buffer = SharedBuffer::default();
+    vm.set_output(Box::new(buffer.clone()));
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(String::from_utf8(buffer.0.borrow().clone()).unwrap(), "4\n");
+
+    // A local label needs a global label to belong to
+    assert!(Assembler::new().assemble(".loop:\nJMP .loop").is_err());
+}
+
+#[test]
+fn test_jump_target_past_one_byte()
+{
+    let program = "JMP far\nHALT\n.org 0x100\nfar: HALT";
+    let error = Assembler::new().assemble_padded(program, 512).unwrap_err();
+    assert_eq!(error.line(), Some(1));
+    assert!(matches!(error, AssemblerError::AtLine { ref error, .. }
+        if matches!(**error, AssemblerError::InvalidAddress(_))));
+
+    // Other one-byte operands can't refer to it either
+    let error = Assembler::new().assemble_padded("MOV r0, far\nHALT\n.org 0x100\nfar: HALT", 512);
+    assert!(error.is_err());
+
+    // Nor can a literal target
+    let error = Assembler::new().assemble_padded("CALL 0x100", 256).unwrap_err();
+    assert!(matches!(error, AssemblerError::AtLine { ref error, .. }
+        if matches!(**error, AssemblerError::InvalidAddress(_))));
+
+    // Labels that fit still assemble
+    let bytecode = Assembler::new()
+        .assemble_padded("JMP near\n.org 0xFF\nnear: HALT", 256)
+        .unwrap();
+    assert_eq!(bytecode[..2], [0x40, 0xFF]);
+
+    // Negative targets are rejected rather than wrapped, however they're written
+    for program in [
+        "start: JMP start - 1",
+        "JMP -1",
+        ".addr -1",
+        "LOAD r0, 0 - 1",
+        "STORE r0, -1",
+        "LOADW r0, -1",
+    ] {
+        let error = Assembler::new().assemble_padded(program, 256).unwrap_err();
+        assert!(
+            matches!(error, AssemblerError::AtLine { ref error, .. }
+                if matches!(**error, AssemblerError::InvalidAddress(_))),
+            "{}: {:?}",
+            program,
+            error
+        );
+    }
+
+    // Values aren't addresses, so MOVW still takes a negative one
+    assert_eq!(Assembler::new().assemble("MOVW r0, 0 - 1").unwrap(), [0x06, 0x00, 0xFF, 0xFF]);
+}
+
+#[test]
+fn test_assemble_and_run_unified_error()
+{
+    fn run(code: &str) -> Result<(), RustBucketError>
+    {
+        assemble_and_run(code, VMConfig::default())?;
+        Ok(())
+    }
+
+    assert!(run("MOV r0, 1\nHALT").is_ok());
+    assert!(matches!(run("MOV r0, nowhere\nHALT"), Err(RustBucketError::Assembler(_))));
+    assert!(matches!(
+        run("MOV r0, 1\nMOV r1, 0\nDIV r0, r1\nHALT"),
+        Err(RustBucketError::VM(VMError::DivisionByZero))
+    ));
+
+    let error: RustBucketError = VMError::StackUnderflow.into();
+    assert!(error.to_string().starts_with("Execution failed"));
+
+    // The assembler accepts the registers the configured machine has
+    let config = VMConfig::builder().num_registers(16).build().unwrap();
+    assert!(assemble_and_run("MOV r10, 1\nHALT", config).is_ok());
+    assert!(matches!(run("MOV r10, 1\nHALT"), Err(RustBucketError::Assembler(_))));
+}
+
+#[test]
+fn test_spans()
+{
+    // An error points at the instruction, without its label, indentation or comment
+    let code = "MOV r0, 1\n    start: JMP nowhere ; oops\nHALT";
+    let error = Assembler::new().assemble_padded(code, 256).unwrap_err();
+    let span = error.span().unwrap();
+    assert_eq!(&code[span.start..span.end], "JMP nowhere");
+    assert_eq!(span.line, 2);
+
+    // First-pass errors cover the whole line's code
+    let code = "HALT\n  FOO r0  ; unknown";
+    let error = Assembler::new().assemble_padded(code, 256).unwrap_err();
+    let span = error.span().unwrap();
+    assert_eq!(&code[span.start..span.end], "FOO r0");
+
+    // Diagnostics carry the span of the instruction they warn about
+    let code = "HALT\nMOV r0, 1";
+    let mut assembler = Assembler::new();
+    assembler.assemble(code).unwrap();
+    let span = assembler.analyze()[0].span;
+    assert_eq!(&code[span.start..span.end], "MOV r0, 1");
+
+    // Disassembled instructions span the bytes they were decoded from
+    let lines = disassemble_with_spans(&[0x04, 0x00, 0x05, 0x03, 0x00, 0xFF]).unwrap();
+    let spans: Vec<_> = lines.iter().map(|(span, _)| (span.start, span.end, span.line)).collect();
+    assert_eq!(spans, vec![(0, 3, 1), (3, 5, 2), (5, 6, 3)]);
+    assert_eq!(lines[1].1, "OUT r0");
+}
+
+#[test]
+fn test_more_than_eight_registers()
+{
+    let program = "MOV r10, 7\nMOV r15, 5\nADD r10, r15\nOUT r10\nHALT";
+
+    // The default assembler only knows about r0-r7
+    assert!(Assembler::new().assemble(program).is_err());
+    assert!(Assembler::with_registers(12).assemble(program).is_err());
+
+    let bytecode = Assembler::with_registers(16).assemble(program).unwrap();
+    let config = VMConfig::builder().num_registers(16).build().unwrap();
+    assert_eq!(run_to_string(&bytecode, config).unwrap(), "12");
+}
+
+#[test]
+fn test_jmpr()
+{
+    let program = r#"
+        MOV r0, target
+        JMPR r0
+        MOV r1, 1
+        HALT
+        target:
+        MOV r1, 2
+        HALT
+    "#;
+    let bytecode = Assembler::new().assemble(program).unwrap();
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.get_register(1).unwrap(), 2);
+
+    // The target must be inside memory
+    let bytecode = Assembler::new().assemble("MOVW r0, 300\nJMPR r0").unwrap();
+    let mut vm = CPU16::from_config(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert!(matches!(vm.run(), Err(VMError::InvalidMemoryAccess(300))));
+}
+
+#[test]
+fn test_jump_table_dispatch()
+{
+    for (index, expected) in [(0, 10), (1, 20), (2, 30)] {
+        let program = format!(
+            r#"
+            MOV r1, {}
+            LDIDX r0, table
+            JMPR r0
+            zero:
+                MOV r2, 10
+                HALT
+            one:
+                MOV r2, 20
+                HALT
+            two:
+                MOV r2, 30
+                HALT
+            table:
+                .addr zero, one, two
+            "#,
+            index
+        );
+        let mut assembler = Assembler::new();
+        let bytecode = assembler.assemble(&program).unwrap();
+        let labels = assembler.labels();
+        assert_eq!(
+            bytecode[labels["table"]..],
+            [labels["zero"] as u8, labels["one"] as u8, labels["two"] as u8]
+        );
+        assert!(assembler
+            .symbol_table()
+            .iter()
+            .any(|symbol| symbol.name == "table" && symbol.kind == SymbolKind::Data));
+
+        let mut vm = CPU::new(VMConfig::default()).unwrap();
+        vm.load_program(&bytecode).unwrap();
+        assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+        assert_eq!(vm.get_register(2).unwrap(), expected);
+    }
+
+    // Table entries must be one-byte addresses
+    assert!(Assembler::new()
+        .assemble_padded(".addr far\n.org 0x100\nfar: HALT", 512)
+        .is_err());
+}
+
+#[test]
+fn test_stack_and_call_depth()
+{
+    let program = r#"
+        PUSH r0
+        PUSH r1
+        CALL sub
+        HALT
+        sub:
+        PUSH r2
+        NOP
+        RET
+    "#;
+    let bytecode = Assembler::new().assemble(program).unwrap();
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert_eq!((vm.stack_depth(), vm.call_depth()), (0, 0));
+
+    // Stop inside the subroutine, after the third push
+    vm.add_breakpoint(bytecode.len() - 2);
+    assert!(matches!(vm.run().unwrap(), RunOutcome::Breakpoint(_)));
+    assert_eq!(vm.stack_depth(), 3);
+    assert_eq!(vm.call_depth(), 1);
+}
+
+#[test]
+fn test_stack_direction()
+{
+    let program = r#"
+        MOV r0, 10
+        MOV r1, 20
+        MOV r2, 30
+        PUSH r0
+        PUSH r1
+        PUSH r2
+        LDSP r3, 0      ; always the most recent push
+        LDSP r4, 2
+        POP r5
+        POP r6
+        POP r7
+        HALT
+    "#;
+    let bytecode = Assembler::new().assemble(program).unwrap();
+
+    for grows_down in [true, false] {
+        let config = VMConfig::builder()
+            .memory_size(64)
+            .stack_size(4)
+            .stack_grows_down(grows_down)
+            .build()
+            .unwrap();
+        let mut vm = CPU::new(config).unwrap();
+        let start = if grows_down { 64 } else { 60 };
+        assert_eq!(vm.sp(), start);
+
+        vm.load_program(&bytecode).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.registers()[3..8], [30, 10, 30, 20, 10]);
+        assert_eq!(vm.sp(), start);
+
+        // The values land at the end of memory, or at the start of the stack region
+        let expected = if grows_down { [30, 20, 10] } else { [10, 20, 30] };
+        let low = if grows_down { 61 } else { 60 };
+        assert_eq!(vm.memory()[low..low + 3], expected);
+
+        // Four bytes fit, the fifth push overflows
+        vm.reset();
+        let mut pushes = [0x10, 0x00].repeat(5);
+        pushes.push(0xFF);
+        vm.load_program(&pushes).unwrap();
+        assert!(matches!(vm.run(), Err(VMError::StackOverflow)));
+        assert_eq!(vm.stack_depth(), 4);
+        assert_eq!(vm.sp(), if grows_down { 60 } else { 64 });
+
+        // Popping an empty stack underflows without moving the stack pointer
+        vm.reset();
+        vm.load_program(&[0x11, 0x00, 0xFF]).unwrap();
+        assert!(matches!(vm.run(), Err(VMError::StackUnderflow)));
+        assert_eq!(vm.sp(), start);
+
+        // Reading an empty stack stays inside the stack region in both directions
+        for (op, bytes) in [("LDSP", [0x26, 0x00, 0x00, 0xFF]), ("STSP", [0x27, 0x00, 0x00, 0xFF])] {
+            vm.reset();
+            vm.load_program(&bytes).unwrap();
+            let expected = if grows_down { 64 } else { 59 };
+            match vm.run() {
+                Err(VMError::InvalidMemoryAccess(addr)) => assert_eq!(addr, expected, "{}", op),
+                other => panic!("{} on an empty stack: {:?}", op, other),
+            }
+        }
+
+        // With one value pushed, offset 0 is valid and offset 1 is past it
+        vm.reset();
+        vm.load_program(&[0x04, 0x00, 0x07, 0x10, 0x00, 0x26, 0x01, 0x00, 0x26, 0x02, 0x01, 0xFF])
+            .unwrap();
+        assert!(matches!(vm.run(), Err(VMError::InvalidMemoryAccess(_))));
+        assert_eq!(vm.registers()[1], 7);
+    }
+}
+
+#[test]
+fn test_verify()
+{
+    let config = VMConfig::builder().memory_size(64).num_registers(4).build().unwrap();
+
+    // A clean program passes
+    let program = r#"
+        MOV r0, 5
+        MOV r1, 3
+        ADD r0, r1
+        STORE r0, 0x30
+        loop:
+        SUBI r1, 1
+        CMP r1, 0
+        JNE loop
+        HALT
+    "#;
+    let bytecode = Assembler::new().assemble(program).unwrap();
+    assert!(verify(&bytecode, &config).is_ok());
+
+    // Registers the machine doesn't have, including the implicit ones of MEMCPY
+    let errors = verify(&[0x04, 0x04, 0x01, 0x30, 0x00, 0x05, 0xFF], &config).unwrap_err();
+    assert!(matches!(
+        errors[..],
+        [VMError::InvalidRegister(4), VMError::InvalidRegister(5)]
+    ));
+    let errors = verify(&[0x28, 0xFF], &VMConfig::builder().num_registers(2).build().unwrap()).unwrap_err();
+    assert!(matches!(errors[..], [VMError::InvalidRegister(2)]));
+
+    // Data addresses and jump targets outside memory
+    let errors = verify(&[0x20, 0x00, 0x40, 0x44, 0x50, 0x4A, 0x7F, 0xFF], &config).unwrap_err();
+    assert!(matches!(
+        errors[..],
+        [
+            VMError::InvalidMemoryAccess(0x40),
+            VMError::InvalidMemoryAccess(0x50),
+            VMError::InvalidMemoryAccess(134)
+        ]
+    ));
+
+    // Unknown opcodes
+    let errors = verify(&[0x04, 0x00, 0x01, 0xEE, 0xFF], &config).unwrap_err();
+    assert!(matches!(errors[..], [VMError::InvalidOpcode(0xEE)]));
+
+    // A truncated last instruction, reported after the faults before it
+    let errors = verify(&[0x04, 0x09, 0x01, 0x04, 0x00], &config).unwrap_err();
+    assert!(matches!(
+        errors[..],
+        [VMError::InvalidRegister(9), VMError::InvalidMemoryAccess(5)]
+    ));
+
+    // A program larger than memory
+    let errors = verify(&[0x00; 65], &config).unwrap_err();
+    assert!(matches!(errors[..], [VMError::ProgramTooLarge { size: 65, capacity: 64 }]));
+
+    // Faults that depend on run-time values, like dividing by zero, aren't reported
+    assert!(verify(&[0x33, 0x00, 0x01, 0xFF], &config).is_ok());
+
+    // Data after the last HALT isn't decoded as code
+    let program = r#"
+        MOV r2, msg
+        OUTSTR r2
+        HALT
+        msg: .strz "Hi; there"
+    "#;
+    let bytecode = Assembler::new().assemble(program).unwrap();
+    assert!(verify(&bytecode, &config).is_ok());
+    assert_eq!(run_to_string(&bytecode, VMConfig::default()).unwrap(), "Hi; there");
+
+    // Code behind a JMP is checked only if something jumps to it
+    assert!(verify(&[0x40, 0x04, 0xEE, 0xEE, 0xFF], &config).is_ok());
+    let errors = verify(&[0x41, 0x03, 0xFF, 0xEE, 0xFF], &config).unwrap_err();
+    assert!(matches!(errors[..], [VMError::InvalidOpcode(0xEE)]));
+
+    // Word accesses need the whole word in memory
+    let program = [0x24, 0x00, 0x3F, 0x00, 0x25, 0x00, 0x3E, 0x00, 0xFF];
+    assert!(verify(&program, &config).is_ok());
+    let errors = verify_for::<u16>(&program, &config).unwrap_err();
+    assert!(matches!(errors[..], [VMError::InvalidMemoryAccess(0x3F)]));
+    let mut vm = CPU16::from_config(VMConfig::builder().memory_size(64).build().unwrap()).unwrap();
+    vm.load_program(&program).unwrap();
+    assert!(matches!(vm.run(), Err(VMError::InvalidMemoryAccess(0x3F))));
+}
+
+#[test]
+fn test_is_halted()
+{
+    let bytecode = Assembler::new().assemble("MOV r0, 5\nMOV r1, 3\nADD r0, r1\nHALT").unwrap();
+    let mut vm = CPU::new(VMConfig::default()).unwrap();
+    vm.load_program(&bytecode).unwrap();
+    assert!(!vm.is_halted());
+
+    // Part way through, the program hasn't halted yet
+    vm.step().unwrap();
+    vm.step().unwrap();
+    assert!(!vm.is_halted());
+
+    assert_eq!(vm.run().unwrap(), RunOutcome::Halted);
+    assert!(vm.is_halted());
+    assert_eq!(vm.get_register(0).unwrap(), 8);
+
+    // Running off the end of memory stops the program without halting it
+    vm.reset();
+    vm.load_program(&[0x00]).unwrap();
+    assert_eq!(vm.run().unwrap(), RunOutcome::MemoryExhausted);
+    assert!(!vm.is_halted());
+}
+
+#[test]
+fn test_unknown_opcode_policy()
+{
+    // MOV r0, 1 / unknown 0xEE / MOV r0, 2 / HALT 3
+    let program = [0x04, 0x00, 0x01, 0xEE, 0x04, 0x00, 0x02, 0xFE, 0x03];
+    let run = |policy: UnknownPolicy| {
+        let config = VMConfig::builder().on_unknown_opcode(policy).build().unwrap();
+        let mut vm = CPU::new(config).unwrap();
+        vm.load_program(&program).unwrap();
+        let result = vm.run();
+        (result, vm)
+    };
+
+    let (result, vm) = run(UnknownPolicy::default());
+    assert!(matches!(result, Err(VMError::InvalidOpcode(0xEE))));
+    assert_eq!(vm.get_register(0).unwrap(), 1);
+
+    let (result, vm) = run(UnknownPolicy::Skip);
+    assert_eq!(result.unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.get_register(0).unwrap(), 2);
+    assert_eq!(vm.exit_code(), Some(3));
+
+    let (result, vm) = run(UnknownPolicy::Halt);
+    assert_eq!(result.unwrap(), RunOutcome::Halted);
+    assert_eq!(vm.get_register(0).unwrap(), 1);
+    assert_eq!(vm.exit_code(), Some(0));
+
+    // Only the error policy makes the unknown byte a fault for the verifier
+    let config = VMConfig::builder().on_unknown_opcode(UnknownPolicy::Skip).build().unwrap();
+    assert!(verify(&program, &config).is_ok());
+    assert!(verify(&program, &VMConfig::default()).is_err());
+}
+
 fn run_test_cases(test_cases: Vec<(&str, Vec<u8>)>)
 {
     let mut assembler = Assembler::new();
 
     for (program, expected_registers) in test_cases {
-        let mut vm = CPU::new(VMConfig::default());
+        let mut vm = CPU::new(VMConfig::default()).unwrap();
 
         let bytecode = assembler
             .assemble(program)
             .unwrap_or_else(|_| panic!("Assembly failed for program:\n{}", program));
-        vm.load_program(&bytecode);
+        vm.load_program(&bytecode).unwrap();
         vm.run().expect("Program execution failed");
 
-        // Check the first 4 registers against expected values
-        for i in 0..4 {
+        // Check the leading registers against expected values
+        for (i, &expected) in expected_registers.iter().enumerate() {
             assert_eq!(
                 vm.get_register(i).expect("Failed to get register"),
-                expected_registers[i],
+                expected,
                 "Register r{} has incorrect value",
                 i
             );